@@ -0,0 +1,32 @@
+use critters_rs::html::{parse_html, traits::*};
+use critters_rs::{Critters, CrittersOptions};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let critters = Critters::new(CrittersOptions::default());
+
+    // Parse the document once, for example to apply other transforms to it
+    let dom = parse_html().one(
+        r#"
+        <html>
+            <head>
+                <style>
+                    .critical { color: red; }
+                    .unused { color: blue; }
+                </style>
+            </head>
+            <body>
+                <div class="critical">Hello World</div>
+            </body>
+        </html>
+    "#,
+    );
+
+    // Reduce the stylesheets of the document in place
+    let report = critters.process_document(&dom)?;
+    println!("Reduced {} stylesheets.", report.stylesheets_reduced());
+
+    // Serialize the document once all transforms are applied
+    println!("Processed HTML:\n{}", dom);
+
+    Ok(())
+}
@@ -41,9 +41,33 @@ export default (options?: AstroCrittersOptions): AstroIntegration => {
 				const stats = critters.processDir();
 
 				logger.info(c.green(` ✓ Completed in ${stats.timeSec.toFixed(2)}s.`));
-				// TODO: more stats
-				logger.info(c.green(`🚀 Processed ${stats.pages} pages.`));
+				logger.info(
+					c.green(
+						`🚀 Processed ${stats.pages} pages, inlining ${formatBytes(stats.criticalBytes)} of critical CSS.`,
+					),
+				);
+				if (stats.pagesSkipped > 0) {
+					logger.info(
+						`${stats.pagesSkipped} pages without stylesheets were skipped.`,
+					);
+				}
+				for (const page of stats.pageReports) {
+					if (page.status === "failed") {
+						logger.warn(`Failed to process ${page.path}: ${page.error}`);
+					}
+				}
+				for (const page of stats.biggestPages.slice(0, 3)) {
+					logger.debug(
+						`${page.path}: ${formatBytes(page.criticalBytes)} of critical CSS`,
+					);
+				}
 			},
 		},
 	};
 };
+
+function formatBytes(bytes: number): string {
+	if (bytes < 1024) return `${bytes} B`;
+	if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KiB`;
+	return `${(bytes / (1024 * 1024)).toFixed(1)} MiB`;
+}
@@ -84,7 +84,11 @@ async function fixTypes() {
 		.readFile(declarationFile)
 		.then((b) => b.toString("utf-8"));
 	const updatedDeclaration = `import type { CrittersOptions as FullCrittersOptions } from "./CrittersOptions.ts";
+import type { CrittersDirectoryStats } from "./CrittersDirectoryStats.ts";
+import type { DirectoryOptions } from "./DirectoryOptions.ts";
+import type { ProcessReport } from "./ProcessReport.ts";
 export type CrittersOptions = Partial<FullCrittersOptions>;
+export type { CrittersDirectoryStats, DirectoryOptions, ProcessReport };
 
 ${declaration.replace(/constructor\(.*?\)/, "constructor(options?: CrittersOptions)")}
 `;
@@ -23,3 +23,30 @@ test("basic functionality", () => {
 	expect(inlined).toContain(".blue");
 	expect(inlined).not.toContain(".red");
 });
+
+test("process with report", () => {
+	const critters = new Critters();
+
+	const { html, report } = critters.processWithReport(`
+  <html>
+  <head>
+    <style>
+      .red { color: red }
+      .blue { color: blue }
+    </style>
+  </head>
+  <body>
+    <div class="blue">I'm Blue</div>
+  </body>
+  </html>
+  `);
+
+	expect(html).not.toContain(".red");
+	expect(report.stylesheets).toHaveLength(1);
+	expect(report.stylesheets[0]).toMatchObject({
+		source: "inline",
+		action: "reduced",
+		rulesKept: 1,
+		rulesRemoved: 1,
+	});
+});
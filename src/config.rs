@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "directory")]
+use crate::{utils, CrittersError, CrittersOptions};
+
+/// Options applied to the pages of a directory matching a glob.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct OptionsOverride {
+    /// Glob matched against the path of each page relative to `path`, such as `blog/**`.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// The options to apply to matching pages, over the base options.
+    #[cfg_attr(feature = "typegen", ts(type = "Record<string, unknown>"))]
+    pub options: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The overrides of a set of options, compiled once for all the pages of a directory.
+#[cfg(feature = "directory")]
+pub(crate) struct Overrides<'a> {
+    base: serde_json::Map<String, serde_json::Value>,
+    overrides: Vec<(globset::GlobMatcher, &'a OptionsOverride)>,
+    /// The options of the base which take precedence over the overrides.
+    locked: &'a [String],
+}
+
+#[cfg(feature = "directory")]
+impl<'a> Overrides<'a> {
+    pub fn new(
+        options: &CrittersOptions,
+        overrides: &'a [OptionsOverride],
+        locked: &'a [String],
+    ) -> Result<Self, CrittersError> {
+        let base = match serde_json::to_value(options) {
+            Ok(serde_json::Value::Object(base)) => base,
+            _ => unreachable!("options serialize to an object"),
+        };
+        let overrides = overrides
+            .iter()
+            .map(|o| Ok((utils::compile_glob(&o.pattern, "overrides")?, o)))
+            .collect::<Result<Vec<_>, CrittersError>>()?;
+        let overrides = Self {
+            base,
+            overrides,
+            locked,
+        };
+
+        // Report invalid options upfront, rather than for each page they apply to
+        for (_, o) in &overrides.overrides {
+            // Options which do not apply to single pages, such as those of the directory, would
+            // otherwise be silently ignored
+            if let Some(key) = o
+                .options
+                .keys()
+                .find(|key| !overrides.base.contains_key(*key))
+            {
+                return Err(CrittersError::InvalidOptions(format!(
+                    "Invalid override of options for \"{}\". Unknown option \"{key}\".",
+                    o.pattern
+                )));
+            }
+            overrides.merge([*o])?;
+        }
+
+        Ok(overrides)
+    }
+
+    /// The options of the page at the given path, relative to the directory, if any override
+    /// applies to it. Later overrides take precedence over earlier ones.
+    pub fn options_for(
+        &self,
+        path: &std::path::Path,
+    ) -> Result<Option<CrittersOptions>, CrittersError> {
+        let matching = self
+            .overrides
+            .iter()
+            .filter(|(glob, _)| glob.is_match(path))
+            .map(|(_, o)| *o)
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            return Ok(None);
+        }
+        self.merge(matching).map(Some)
+    }
+
+    fn merge(
+        &self,
+        overrides: impl IntoIterator<Item = &'a OptionsOverride>,
+    ) -> Result<CrittersOptions, CrittersError> {
+        let mut options = self.base.clone();
+        for o in overrides {
+            options.extend(o.options.clone());
+        }
+        for key in self.locked {
+            if let Some(value) = self.base.get(key) {
+                options.insert(key.clone(), value.clone());
+            }
+        }
+
+        serde_json::from_value(options.into())
+            .map_err(|e| CrittersError::InvalidOptions(format!("Invalid override of options. {e}")))
+    }
+}
@@ -0,0 +1,967 @@
+use indexmap::IndexSet;
+use lightningcss::declaration::DeclarationBlock;
+use lightningcss::properties::animation::AnimationName;
+use lightningcss::properties::custom::{CustomPropertyName, TokenList, TokenOrValue};
+use lightningcss::properties::{Property, PropertyId};
+use lightningcss::rules::layer::LayerName;
+use lightningcss::rules::style::StyleRule;
+use lightningcss::rules::{CssRule, CssRuleList};
+use lightningcss::values::image::Image;
+use lightningcss::vendor_prefix::VendorPrefix;
+use std::collections::{HashMap, HashSet};
+
+use crate::utils::{regex, StyleRuleExt};
+
+/// Visits every style rule in the given list, including rules nested within conditional group
+/// rules (`@media`, `@supports`, `@layer`, ...) and CSS nesting.
+pub fn walk_style_rules<'a, 'i>(rules: &'a CssRuleList<'i>, f: &mut impl FnMut(&'a StyleRule<'i>)) {
+    for rule in &rules.0 {
+        match rule {
+            CssRule::Style(style) => {
+                f(style);
+                walk_style_rules(&style.rules, f);
+            }
+            _ => {
+                if let Some(nested) = nested_rules(rule) {
+                    walk_style_rules(nested, f);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the rules nested within a grouping at-rule, if any.
+pub fn nested_rules<'a, 'i>(rule: &'a CssRule<'i>) -> Option<&'a CssRuleList<'i>> {
+    match rule {
+        CssRule::Media(r) => Some(&r.rules),
+        CssRule::Supports(r) => Some(&r.rules),
+        CssRule::LayerBlock(r) => Some(&r.rules),
+        CssRule::Container(r) => Some(&r.rules),
+        CssRule::Scope(r) => Some(&r.rules),
+        CssRule::StartingStyle(r) => Some(&r.rules),
+        CssRule::MozDocument(r) => Some(&r.rules),
+        CssRule::Style(r) => Some(&r.rules),
+        CssRule::Nesting(r) => Some(&r.style.rules),
+        _ => None,
+    }
+}
+
+/// Returns the style rules which are reduced against the document: top-level rules and rules
+/// within (possibly nested) `@layer` blocks.
+pub fn reducible_style_rules<'a, 'i>(rules: &'a CssRuleList<'i>) -> Vec<&'a StyleRule<'i>> {
+    let mut style_rules = Vec::new();
+    for rule in &rules.0 {
+        match rule {
+            CssRule::Style(style) => style_rules.push(style),
+            CssRule::LayerBlock(layer) => style_rules.extend(reducible_style_rules(&layer.rules)),
+            _ => (),
+        }
+    }
+    style_rules
+}
+
+/// Removes the given style rules from within `@layer` blocks, preserving the layer order of the
+/// stylesheet. Layer statements are always kept, and layer blocks left empty are only kept if
+/// they are the first declaration of their layer, since that determines the layer's position in
+/// the cascade.
+pub fn reduce_layers(rules: &mut CssRuleList, rules_to_remove: &HashSet<u128>) {
+    reduce_layers_inner(rules, rules_to_remove, "", &mut HashSet::new(), &mut 0);
+}
+
+fn reduce_layers_inner(
+    rules: &mut CssRuleList,
+    rules_to_remove: &HashSet<u128>,
+    parent: &str,
+    declared: &mut HashSet<String>,
+    anonymous: &mut usize,
+) {
+    let qualify = |name: &LayerName| {
+        let name = name
+            .0
+            .iter()
+            .map(|n| n.as_ref())
+            .collect::<Vec<_>>()
+            .join(".");
+        if parent.is_empty() {
+            name
+        } else {
+            format!("{parent}.{name}")
+        }
+    };
+
+    rules.0.retain_mut(|rule| match rule {
+        CssRule::LayerStatement(statement) => {
+            declared.extend(statement.names.iter().map(qualify));
+            true
+        }
+        CssRule::LayerBlock(layer) => {
+            let (name, first_declaration) = match &layer.name {
+                Some(name) => {
+                    let name = qualify(name);
+                    (name.clone(), declared.insert(name))
+                }
+                None => {
+                    // anonymous layers are unique, and an empty one has no effect
+                    *anonymous += 1;
+                    (format!("{parent}.<anonymous-{anonymous}>"), false)
+                }
+            };
+
+            layer.rules.0.retain(|rule| match rule {
+                CssRule::Style(style) => !rules_to_remove.contains(&style.id()),
+                _ => true,
+            });
+            reduce_layers_inner(
+                &mut layer.rules,
+                rules_to_remove,
+                &name,
+                declared,
+                anonymous,
+            );
+
+            !layer.rules.0.is_empty() || first_declaration
+        }
+        _ => true,
+    });
+}
+
+/// Keyframes names referenced by a set of `animation` and `animation-name` declarations.
+#[derive(Debug, Default)]
+pub struct AnimationNames {
+    /// Names referenced directly by the declarations.
+    pub names: HashSet<String>,
+    /// Custom properties referenced through `var()`, which may expand to further names.
+    pub variables: HashSet<String>,
+}
+
+impl AnimationNames {
+    /// Records the keyframes names referenced by the given declaration, if it is an
+    /// `animation` or `animation-name` declaration (with any vendor prefix).
+    pub fn collect(&mut self, property: &Property) {
+        match property {
+            Property::Animation(animations, _) => {
+                for animation in animations {
+                    self.insert_name(&animation.name);
+                }
+            }
+            Property::AnimationName(names, _) => {
+                for name in names {
+                    self.insert_name(name);
+                }
+            }
+            Property::Unparsed(unparsed)
+                if matches!(
+                    unparsed.property_id,
+                    PropertyId::Animation(_) | PropertyId::AnimationName(_)
+                ) =>
+            {
+                self.insert_tokens(&unparsed.value);
+            }
+            _ => (),
+        }
+    }
+
+    /// Expands the collected `var()` references using the custom properties declared in the
+    /// given stylesheet, following references between custom properties transitively.
+    pub fn resolve_variables(&mut self, rules: &CssRuleList) {
+        let mut custom_properties: HashMap<&str, Vec<&Property>> = HashMap::new();
+        walk_style_rules(rules, &mut |style| {
+            for (property, _) in style.declarations.iter() {
+                if let Property::Custom(custom) = property {
+                    if let CustomPropertyName::Custom(name) = &custom.name {
+                        custom_properties
+                            .entry(name.as_ref())
+                            .or_default()
+                            .push(property);
+                    }
+                }
+            }
+        });
+
+        let mut resolved = HashSet::new();
+        let mut pending = self.variables.iter().cloned().collect::<Vec<_>>();
+        while let Some(variable) = pending.pop() {
+            if !resolved.insert(variable.clone()) {
+                continue;
+            }
+
+            for value in custom_properties
+                .get(variable.as_str())
+                .into_iter()
+                .flatten()
+            {
+                let mut nested = AnimationNames::default();
+                nested.insert_custom_property_value(value);
+                self.names.extend(nested.names);
+                pending.extend(nested.variables);
+            }
+        }
+        self.variables = resolved;
+    }
+
+    /// Returns whether the given keyframes name is referenced.
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    fn insert_name(&mut self, name: &AnimationName) {
+        match name {
+            AnimationName::Ident(ident) => {
+                self.names.insert(ident.0.to_string());
+            }
+            AnimationName::String(string) => {
+                self.names.insert(string.to_string());
+            }
+            AnimationName::None => (),
+        }
+    }
+
+    /// Custom properties are untyped, so attempt to interpret the value as an animation list
+    /// before falling back to treating every identifier as a potential name.
+    fn insert_custom_property_value(&mut self, property: &Property) {
+        let Property::Custom(custom) = property else {
+            return;
+        };
+        let css = match property.value_to_css_string(Default::default()) {
+            Ok(css) => css,
+            Err(_) => return,
+        };
+        match Property::parse_string(
+            PropertyId::Animation(VendorPrefix::None),
+            &css,
+            Default::default(),
+        ) {
+            Ok(animation @ Property::Animation(..)) => self.collect(&animation),
+            _ => self.insert_tokens(&custom.value),
+        };
+    }
+
+    /// Collects identifiers, strings and `var()` references from a raw token list. This is used
+    /// when the value could not be parsed, typically because it contains a `var()`.
+    fn insert_tokens(&mut self, tokens: &TokenList) {
+        use lightningcss::properties::custom::Token;
+
+        for token in &tokens.0 {
+            match token {
+                TokenOrValue::Token(Token::Ident(ident)) if !is_animation_keyword(ident) => {
+                    self.names.insert(ident.to_string());
+                }
+                TokenOrValue::Token(Token::String(string)) => {
+                    self.names.insert(string.to_string());
+                }
+                TokenOrValue::AnimationName(name) => self.insert_name(name),
+                TokenOrValue::Var(var) => {
+                    self.variables.insert(var.name.ident.as_ref().to_string());
+                    if let Some(fallback) = &var.fallback {
+                        self.insert_tokens(fallback);
+                    }
+                }
+                TokenOrValue::Function(function) => self.insert_tokens(&function.arguments),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Whether the identifier is a CSS-wide keyword or a keyword of one of the `animation`
+/// longhands, rather than an animation name. Like lightningcss when parsing `animation`, these
+/// are never treated as names, even if a `@keyframes` rule uses them.
+fn is_animation_keyword(ident: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        // CSS-wide keywords
+        "initial",
+        "inherit",
+        "unset",
+        "revert",
+        "revert-layer",
+        "default",
+        "none",
+        // animation-duration
+        "auto",
+        // animation-timing-function, including the arguments of steps()
+        "ease",
+        "ease-in",
+        "ease-out",
+        "ease-in-out",
+        "linear",
+        "step-start",
+        "step-end",
+        "jump-start",
+        "jump-end",
+        "jump-none",
+        "jump-both",
+        "start",
+        "end",
+        // animation-iteration-count
+        "infinite",
+        // animation-direction
+        "normal",
+        "reverse",
+        "alternate",
+        "alternate-reverse",
+        // animation-fill-mode
+        "forwards",
+        "backwards",
+        "both",
+        // animation-play-state
+        "running",
+        "paused",
+    ];
+    KEYWORDS
+        .iter()
+        .any(|keyword| ident.eq_ignore_ascii_case(keyword))
+}
+
+/// Names of `@counter-style` and `@font-palette-values` rules referenced by a set of
+/// declarations.
+#[derive(Debug, Default)]
+pub struct AtRuleUsage {
+    /// Counter styles referenced by `list-style` or the `counter()` and `counters()` functions.
+    pub counter_styles: HashSet<String>,
+    /// Font palettes referenced by `font-palette`.
+    pub font_palettes: HashSet<String>,
+}
+
+impl AtRuleUsage {
+    /// Records the at-rule names referenced by the given declaration.
+    pub fn collect(&mut self, property: &Property) {
+        use lightningcss::properties::list::{CounterStyle, ListStyleType};
+
+        let list_style_type = match property {
+            Property::ListStyle(list_style) => Some(&list_style.list_style_type),
+            Property::ListStyleType(list_style_type) => Some(list_style_type),
+            Property::FontPalette(palette) => {
+                self.font_palettes
+                    .insert(palette.ident.as_ref().to_string());
+                None
+            }
+            Property::Unparsed(unparsed) => {
+                self.insert_counter_functions(&unparsed.value);
+                None
+            }
+            Property::Custom(custom) => {
+                self.insert_counter_functions(&custom.value);
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(ListStyleType::CounterStyle(CounterStyle::Name(name))) = list_style_type {
+            self.counter_styles.insert(name.0.to_string());
+        }
+    }
+
+    /// Follows the `system: extends` and `fallback` references of the referenced counter styles.
+    pub fn resolve_counter_styles(&mut self, rules: &CssRuleList) {
+        use lightningcss::properties::custom::Token;
+
+        let mut references: HashMap<&str, HashSet<String>> = HashMap::new();
+        for rule in &rules.0 {
+            if let CssRule::CounterStyle(counter_style) = rule {
+                let entry = references.entry(counter_style.name.0.as_ref()).or_default();
+                for (property, _) in counter_style.declarations.iter() {
+                    if let Property::Custom(custom) = property {
+                        for token in &custom.value.0 {
+                            if let TokenOrValue::Token(Token::Ident(ident)) = token {
+                                entry.insert(ident.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut pending = self.counter_styles.iter().cloned().collect::<Vec<_>>();
+        while let Some(name) = pending.pop() {
+            for reference in references.get(name.as_str()).into_iter().flatten() {
+                if self.counter_styles.insert(reference.clone()) {
+                    pending.push(reference.clone());
+                }
+            }
+        }
+    }
+
+    /// Collects counter style names from `counter()` and `counters()` functions, e.g. within
+    /// `content` declarations.
+    fn insert_counter_functions(&mut self, tokens: &TokenList) {
+        use lightningcss::properties::custom::Token;
+
+        for token in &tokens.0 {
+            if let TokenOrValue::Function(function) = token {
+                if function.name.eq_ignore_ascii_case("counter")
+                    || function.name.eq_ignore_ascii_case("counters")
+                {
+                    // the counter style is always the last argument
+                    if let Some(TokenOrValue::Token(Token::Ident(name))) = function
+                        .arguments
+                        .0
+                        .iter()
+                        .rev()
+                        .find(|t| !t.is_whitespace())
+                    {
+                        let has_style = function
+                            .arguments
+                            .0
+                            .iter()
+                            .any(|t| matches!(t, TokenOrValue::Token(Token::Comma)));
+                        if has_style {
+                            self.counter_styles.insert(name.to_string());
+                        }
+                    }
+                }
+                self.insert_counter_functions(&function.arguments);
+            }
+        }
+    }
+}
+
+/// Returns the font families listed in the prelude of a `@font-feature-values` rule.
+pub fn font_feature_values_families(prelude: &TokenList) -> Vec<String> {
+    family_names(prelude)
+}
+
+/// Records the font families used by the given declaration, from `font-family` and the `font`
+/// shorthand, including values which are not parsed such as those using `var()`.
+pub fn collect_font_families(property: &Property, families: &mut HashSet<String>) {
+    use lightningcss::properties::font::FontFamily;
+
+    let list = match property {
+        Property::FontFamily(list) => list,
+        Property::Font(font) => &font.family,
+        Property::Unparsed(unparsed)
+            if matches!(
+                unparsed.property_id,
+                PropertyId::Font | PropertyId::FontFamily
+            ) =>
+        {
+            families.extend(family_names(&unparsed.value));
+            return;
+        }
+        _ => return,
+    };
+    for family in list {
+        if let FontFamily::FamilyName(name) = family {
+            families.insert(name.to_string());
+        }
+    }
+}
+
+/// Returns the font family names of a comma-separated list. Each name is made of the identifiers
+/// and strings at the end of its item, so that the size and style preceding the first family in
+/// the `font` shorthand are left out.
+fn family_names(tokens: &TokenList) -> Vec<String> {
+    use lightningcss::properties::custom::Token;
+
+    let mut families = vec![String::new()];
+    for token in &tokens.0 {
+        let family = families.last_mut().unwrap();
+        match token {
+            TokenOrValue::Token(Token::Ident(ident)) => family.push_str(ident),
+            TokenOrValue::Token(Token::String(string)) => family.push_str(string),
+            TokenOrValue::Token(Token::WhiteSpace(_)) => family.push(' '),
+            TokenOrValue::Token(Token::Comma) => families.push(String::new()),
+            // a family name follows any other token
+            _ => family.clear(),
+        }
+    }
+
+    families
+        .into_iter()
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+/// Dependency graph between custom properties and the declarations that reference them through
+/// `var()`.
+#[derive(Debug, Default)]
+pub struct CustomPropertyUsage {
+    /// Custom properties referenced by regular declarations.
+    roots: HashSet<String>,
+    /// Custom properties referenced by the declarations of each custom property.
+    dependencies: HashMap<String, HashSet<String>>,
+}
+
+impl CustomPropertyUsage {
+    /// Records the references made by every declaration in the given stylesheet.
+    pub fn add_stylesheet(&mut self, rules: &CssRuleList) {
+        walk_style_rules(rules, &mut |style| {
+            for (property, _) in style.declarations.iter() {
+                self.add_declaration(property);
+            }
+        });
+
+        self.add_keyframes(rules);
+    }
+
+    /// Records the references made by an inline `style` attribute. Declarations in style
+    /// attributes cannot be removed, so all of their references are treated as used.
+    pub fn add_inline_style(&mut self, declarations: &DeclarationBlock) {
+        for (property, _) in declarations.iter() {
+            match property {
+                Property::Custom(custom) => {
+                    variable_references(&custom.value, &mut |name| {
+                        self.roots.insert(name.to_string());
+                    });
+                }
+                _ => self.add_declaration(property),
+            }
+        }
+    }
+
+    /// Records a stylesheet which is not parsed, such as one left untouched after an error, by
+    /// scanning its text. Every reference is treated as used, and every declaration as declared.
+    pub fn add_unparsed_stylesheet(&mut self, css: &str) {
+        for c in regex!(r"var\(\s*(--[\w-]+)").captures_iter(css) {
+            self.add_root(&c[1]);
+        }
+        for c in regex!(r"(--[\w-]+)\s*:").captures_iter(css) {
+            self.dependencies.entry(c[1].to_string()).or_default();
+        }
+    }
+
+    /// Marks the given custom property as referenced.
+    pub fn add_root(&mut self, name: &str) {
+        self.roots.insert(name.to_string());
+    }
+
+    /// Records the references made by a single declaration.
+    pub fn add_declaration(&mut self, property: &Property) {
+        match property {
+            Property::Custom(custom) => match &custom.name {
+                CustomPropertyName::Custom(name) => {
+                    let dependencies = self.dependencies.entry(name.to_string()).or_default();
+                    variable_references(&custom.value, &mut |name| {
+                        dependencies.insert(name.to_string());
+                    });
+                }
+                CustomPropertyName::Unknown(_) => {
+                    variable_references(&custom.value, &mut |name| {
+                        self.roots.insert(name.to_string());
+                    });
+                }
+            },
+            Property::Unparsed(unparsed) => {
+                variable_references(&unparsed.value, &mut |name| {
+                    self.roots.insert(name.to_string());
+                });
+            }
+            // Registered custom properties may be transitioned without being referenced by `var()`
+            Property::Transition(transitions, _) => {
+                for transition in transitions {
+                    self.add_property_id(&transition.property);
+                }
+            }
+            Property::TransitionProperty(properties, _) => {
+                for property in properties {
+                    self.add_property_id(property);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the custom properties declared by the recorded stylesheets.
+    pub fn declared(&self) -> impl Iterator<Item = &String> {
+        self.dependencies.keys()
+    }
+
+    /// Returns the set of custom properties reachable from regular declarations.
+    pub fn used(&self) -> HashSet<String> {
+        let mut used = HashSet::new();
+        let mut pending = self.roots.iter().collect::<Vec<_>>();
+        while let Some(name) = pending.pop() {
+            if used.insert(name.clone()) {
+                pending.extend(self.dependencies.get(name).into_iter().flatten());
+            }
+        }
+        used
+    }
+
+    fn add_keyframes(&mut self, rules: &CssRuleList) {
+        for rule in &rules.0 {
+            if let CssRule::Keyframes(keyframes) = rule {
+                for keyframe in &keyframes.keyframes {
+                    for (property, _) in keyframe.declarations.iter() {
+                        self.add_declaration(property);
+                    }
+                }
+            } else if let Some(nested) = nested_rules(rule) {
+                self.add_keyframes(nested);
+            }
+        }
+    }
+
+    fn add_property_id(&mut self, property: &PropertyId) {
+        if let PropertyId::Custom(CustomPropertyName::Custom(name)) = property {
+            self.roots.insert(name.to_string());
+        }
+    }
+}
+
+/// Removes declarations of custom properties that are not in the `used` set, and `@property`
+/// registrations that are not in the `registered` set. Passing `None` for either set leaves the
+/// corresponding rules untouched. Style rules left without any declarations are removed as well.
+///
+/// Returns the number of declarations and registrations removed.
+pub fn remove_unused_custom_properties(
+    rules: &mut CssRuleList,
+    used: Option<&HashSet<String>>,
+    registered: Option<&HashSet<String>>,
+) -> usize {
+    let is_unused = |property: &Property| match (property, used) {
+        (Property::Custom(custom), Some(used)) => match &custom.name {
+            CustomPropertyName::Custom(name) => !used.contains(name.as_ref()),
+            CustomPropertyName::Unknown(_) => false,
+        },
+        _ => false,
+    };
+
+    let mut removed = 0;
+    rules.0.retain_mut(|rule| {
+        match rule {
+            CssRule::Property(registration) => {
+                if registered.is_some_and(|r| !r.contains(registration.name.as_ref())) {
+                    removed += 1;
+                    return false;
+                }
+            }
+            CssRule::Style(style) => {
+                let declarations = &mut style.declarations;
+                let original =
+                    declarations.declarations.len() + declarations.important_declarations.len();
+                declarations.declarations.retain(|p| !is_unused(p));
+                declarations
+                    .important_declarations
+                    .retain(|p| !is_unused(p));
+                let remaining =
+                    declarations.declarations.len() + declarations.important_declarations.len();
+                removed += original - remaining;
+                removed += remove_unused_custom_properties(&mut style.rules, used, registered);
+
+                // only drop rules which were emptied by this pass
+                if remaining == 0 && original != 0 && style.rules.0.is_empty() {
+                    return false;
+                }
+            }
+            _ => {
+                if let Some(nested) = nested_rules_mut(rule) {
+                    removed += remove_unused_custom_properties(nested, used, registered);
+                }
+            }
+        }
+        true
+    });
+
+    removed
+}
+
+/// Mutable variant of [`nested_rules`].
+pub fn nested_rules_mut<'a, 'i>(rule: &'a mut CssRule<'i>) -> Option<&'a mut CssRuleList<'i>> {
+    match rule {
+        CssRule::Media(r) => Some(&mut r.rules),
+        CssRule::Supports(r) => Some(&mut r.rules),
+        CssRule::LayerBlock(r) => Some(&mut r.rules),
+        CssRule::Container(r) => Some(&mut r.rules),
+        CssRule::Scope(r) => Some(&mut r.rules),
+        CssRule::StartingStyle(r) => Some(&mut r.rules),
+        CssRule::MozDocument(r) => Some(&mut r.rules),
+        CssRule::Style(r) => Some(&mut r.rules),
+        CssRule::Nesting(r) => Some(&mut r.style.rules),
+        _ => None,
+    }
+}
+
+/// Calls `f` with the name of every custom property referenced through `var()` in the given
+/// token list, including references within fallbacks and functions.
+fn variable_references(tokens: &TokenList, f: &mut impl FnMut(&str)) {
+    for token in &tokens.0 {
+        match token {
+            TokenOrValue::Var(var) => {
+                f(var.name.ident.as_ref());
+                if let Some(fallback) = &var.fallback {
+                    variable_references(fallback, f);
+                }
+            }
+            TokenOrValue::Env(env) => {
+                if let Some(fallback) = &env.fallback {
+                    variable_references(fallback, f);
+                }
+            }
+            TokenOrValue::Function(function) => variable_references(&function.arguments, f),
+            _ => (),
+        }
+    }
+}
+
+/// An image referenced by a critical declaration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CriticalImage {
+    /// A plain `url()` reference.
+    Url(String),
+    /// The candidate URLs of an `image-set()`, along with their density descriptors (e.g. `2x`).
+    ImageSet(Vec<(String, String)>),
+}
+
+/// Collects the images referenced by `background`, `mask`, `list-style` and `content`
+/// declarations, in order of appearance.
+pub fn collect_images(property: &Property, images: &mut IndexSet<CriticalImage>) {
+    use lightningcss::properties::custom::Token;
+
+    let mut insert = |image: &Image| {
+        if let Some(image) = critical_image(image) {
+            images.insert(image);
+        }
+    };
+
+    match property {
+        Property::BackgroundImage(list) | Property::MaskImage(list, _) => {
+            list.iter().for_each(insert)
+        }
+        Property::Background(list) => list.iter().for_each(|b| insert(&b.image)),
+        Property::Mask(list, _) => list.iter().for_each(|m| insert(&m.image)),
+        Property::ListStyleImage(image) => insert(image),
+        Property::ListStyle(list_style) => insert(&list_style.image),
+        Property::Unparsed(unparsed)
+            if matches!(
+                unparsed.property_id,
+                PropertyId::BackgroundImage
+                    | PropertyId::Background
+                    | PropertyId::MaskImage(_)
+                    | PropertyId::Mask(_)
+                    | PropertyId::ListStyleImage
+                    | PropertyId::ListStyle
+            ) =>
+        {
+            for token in &unparsed.value.0 {
+                if let TokenOrValue::Url(url) = token {
+                    images.insert(CriticalImage::Url(url.url.to_string()));
+                }
+            }
+        }
+        Property::Custom(custom) if matches!(&custom.name, CustomPropertyName::Unknown(name) if name.eq_ignore_ascii_case("content")) => {
+            for token in &custom.value.0 {
+                match token {
+                    TokenOrValue::Url(url) => {
+                        images.insert(CriticalImage::Url(url.url.to_string()));
+                    }
+                    TokenOrValue::Token(Token::UnquotedUrl(url)) => {
+                        images.insert(CriticalImage::Url(url.to_string()));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn critical_image(image: &Image) -> Option<CriticalImage> {
+    use lightningcss::values::resolution::Resolution;
+
+    match image {
+        Image::Url(url) => Some(CriticalImage::Url(url.url.to_string())),
+        Image::ImageSet(set) => {
+            let candidates = set
+                .options
+                .iter()
+                // skip candidates which the browser may not support
+                .filter(|option| option.file_type.is_none())
+                .filter_map(|option| match &option.image {
+                    Image::Url(url) => {
+                        let density = match option.resolution {
+                            Resolution::Dppx(x) => x,
+                            Resolution::Dpi(dpi) => dpi / 96.0,
+                            Resolution::Dpcm(dpcm) => dpcm * 2.54 / 96.0,
+                        };
+                        Some((url.url.to_string(), format!("{density}x")))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            (!candidates.is_empty()).then_some(CriticalImage::ImageSet(candidates))
+        }
+        Image::None | Image::Gradient(_) => None,
+    }
+}
+
+/// A source position as reported by lightningcss: a 0-based line and a 1-based column, counted
+/// in UTF-16 code units.
+type SourcePosition = (u32, u32);
+
+/// A `critters:` control comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlComment {
+    Include,
+    Exclude,
+    IncludeStart,
+    IncludeEnd,
+    ExcludeStart,
+    ExcludeEnd,
+}
+
+/// Style rules forced in or out of the critical CSS by `critters:` control comments.
+///
+/// `/* critters:include */` and `/* critters:exclude */` apply to the rule they are written in,
+/// or to the next rule if they are written between rules. `/* critters:include start */` and
+/// `/* critters:exclude start */` apply to every rule up to the matching `end` comment (or the
+/// end of the stylesheet). Markers on a single rule take precedence over regions.
+#[derive(Debug, Default)]
+pub struct ControlComments {
+    rules: HashMap<SourcePosition, bool>,
+}
+
+impl ControlComments {
+    /// Scans the source of a stylesheet for control comments, since these are dropped by the
+    /// parser, and resolves them against the parsed rules.
+    pub fn parse(sheet: &str, rules: &CssRuleList) -> Self {
+        let markers = scan_control_comments(sheet);
+        if markers.is_empty() {
+            return Default::default();
+        }
+
+        let mut style_rules = Vec::new();
+        walk_style_rules(rules, &mut |style| {
+            style_rules.push((style.loc.line, style.loc.column))
+        });
+        style_rules.sort();
+        style_rules.dedup();
+
+        let mut forced = HashMap::new();
+
+        // regions
+        let mut region = None;
+        let mut markers_iter = markers.iter().peekable();
+        for &position in &style_rules {
+            while let Some((marker, _, _)) = markers_iter.next_if(|(_, pos, _)| *pos < position) {
+                match marker {
+                    ControlComment::IncludeStart => region = Some(true),
+                    ControlComment::ExcludeStart => region = Some(false),
+                    ControlComment::IncludeEnd | ControlComment::ExcludeEnd => region = None,
+                    ControlComment::Include | ControlComment::Exclude => (),
+                }
+            }
+            if let Some(include) = region {
+                forced.insert(position, include);
+            }
+        }
+
+        // single rules
+        for (marker, position, enclosing) in &markers {
+            let include = match marker {
+                ControlComment::Include => true,
+                ControlComment::Exclude => false,
+                _ => continue,
+            };
+            let target = enclosing
+                .filter(|enclosing| style_rules.binary_search(enclosing).is_ok())
+                .or_else(|| {
+                    let next = style_rules.partition_point(|rule| rule < position);
+                    style_rules.get(next).copied()
+                });
+            if let Some(target) = target {
+                forced.insert(target, include);
+            }
+        }
+
+        ControlComments { rules: forced }
+    }
+
+    /// Returns whether the given rule is forced in (`true`) or out (`false`) of the critical CSS.
+    pub fn get(&self, rule: &StyleRule) -> Option<bool> {
+        self.rules.get(&(rule.loc.line, rule.loc.column)).copied()
+    }
+}
+
+/// Finds the control comments in a stylesheet, along with their position and the position of the
+/// prelude of the block they are written in, if any.
+fn scan_control_comments(
+    sheet: &str,
+) -> Vec<(ControlComment, SourcePosition, Option<SourcePosition>)> {
+    let mut markers = Vec::new();
+    let mut blocks: Vec<Option<SourcePosition>> = Vec::new();
+    let mut prelude = None;
+    let (mut line, mut column) = (0, 1);
+
+    let mut chars = sheet.chars().peekable();
+    fn advance(c: char, line: &mut u32, column: &mut u32) {
+        match c {
+            '\n' | '\x0C' => {
+                *line += 1;
+                *column = 1;
+            }
+            // only counted as part of a `\r\n` line break
+            '\r' => (),
+            _ => *column += c.len_utf16() as u32,
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        let position = (line, column);
+        advance(c, &mut line, &mut column);
+        match c {
+            '/' if chars.peek() == Some(&'*') => {
+                let mut text = String::new();
+                let mut prev = '/';
+                for c in chars.by_ref() {
+                    advance(c, &mut line, &mut column);
+                    if prev == '*' && c == '/' && text.len() > 1 {
+                        break;
+                    }
+                    text.push(c);
+                    prev = c;
+                }
+                // strip the opening and closing asterisks
+                let text = text
+                    .strip_prefix('*')
+                    .unwrap_or(&text)
+                    .trim_end_matches('*')
+                    .trim();
+                let marker = match text.strip_prefix("critters:").map(str::trim) {
+                    Some("include") => ControlComment::Include,
+                    Some("exclude") => ControlComment::Exclude,
+                    Some("include start") => ControlComment::IncludeStart,
+                    Some("include end") => ControlComment::IncludeEnd,
+                    Some("exclude start") => ControlComment::ExcludeStart,
+                    Some("exclude end") => ControlComment::ExcludeEnd,
+                    _ => continue,
+                };
+                markers.push((marker, position, blocks.last().copied().flatten()));
+            }
+            '{' => {
+                blocks.push(prelude.take());
+            }
+            '}' => {
+                blocks.pop();
+                prelude = None;
+            }
+            ';' => prelude = None,
+            c if c.is_whitespace() => (),
+            _ => {
+                prelude.get_or_insert(position);
+                match c {
+                    '"' | '\'' => {
+                        while let Some(next) = chars.next() {
+                            advance(next, &mut line, &mut column);
+                            if next == '\\' {
+                                if let Some(escaped) = chars.next() {
+                                    advance(escaped, &mut line, &mut column);
+                                }
+                            } else if next == c || next == '\n' {
+                                break;
+                            }
+                        }
+                    }
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            advance(escaped, &mut line, &mut column);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    markers
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// An error preventing a document or stylesheet from being processed.
+#[derive(Debug, thiserror::Error)]
+pub enum CrittersError {
+    /// A stylesheet could not be parsed.
+    #[error(
+        "Failed to parse stylesheet{}: {message}",
+        fmt_location(href, location)
+    )]
+    Parse {
+        /// The `href` of the stylesheet, unless it is inline.
+        href: Option<String>,
+        /// The reason the stylesheet could not be parsed.
+        message: String,
+        /// The location of the error within the stylesheet. For inline stylesheets, it is relative
+        /// to the start of the `<style>` element's contents.
+        location: Option<SourceLocation>,
+    },
+    /// A reduced stylesheet could not be printed.
+    #[error("Failed to print stylesheet: {0}")]
+    Print(String),
+    /// A stylesheet referenced by the document could not be resolved or loaded.
+    #[error("Failed to load stylesheet \"{href}\": {reason}")]
+    Asset {
+        /// The `href` of the stylesheet.
+        href: String,
+        /// The path the `href` resolved to, if any.
+        path: Option<PathBuf>,
+        /// The reason the stylesheet could not be loaded.
+        reason: String,
+    },
+    /// An element required for processing is missing from the document.
+    #[error("Failed to locate <{0}> element in document.")]
+    MissingElement(&'static str),
+    /// A `<style>` element contains something other than text.
+    #[error("Invalid style tag")]
+    InvalidStyle,
+    /// A file could not be read or written.
+    #[error("Failed to access \"{}\": {error}", path.display())]
+    Io {
+        /// The path of the file.
+        path: PathBuf,
+        /// The underlying error.
+        error: std::io::Error,
+    },
+    /// The provided options are invalid.
+    #[error("Failed to parse options: {0}")]
+    InvalidOptions(String),
+    /// Problems were encountered in strict mode, which would otherwise only be warnings.
+    #[error(
+        "Processing failed in strict mode with {} problems:{}",
+        .0.len(),
+        .0.iter().map(|p| format!("\n  - {p}")).collect::<String>()
+    )]
+    Strict(Vec<String>),
+}
+
+impl CrittersError {
+    /// A stable identifier of the kind of error, used as the `code` of errors thrown to
+    /// JavaScript.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CrittersError::Parse { .. } => "PARSE_ERROR",
+            CrittersError::Print(_) => "PRINT_ERROR",
+            CrittersError::Asset { .. } => "ASSET_ERROR",
+            CrittersError::MissingElement(_) => "MISSING_ELEMENT",
+            CrittersError::InvalidStyle => "INVALID_STYLE",
+            CrittersError::Io { .. } => "IO_ERROR",
+            CrittersError::InvalidOptions(_) => "INVALID_OPTIONS",
+            CrittersError::Strict(_) => "STRICT",
+        }
+    }
+
+    pub(crate) fn parse<T: fmt::Display>(
+        href: Option<&str>,
+        err: lightningcss::error::Error<T>,
+    ) -> Self {
+        CrittersError::Parse {
+            href: href.map(str::to_string),
+            message: err.kind.to_string(),
+            location: err.loc.map(|loc| SourceLocation {
+                line: loc.line + 1,
+                column: loc.column,
+            }),
+        }
+    }
+
+    #[cfg(feature = "directory")]
+    pub(crate) fn io(path: impl Into<PathBuf>, error: std::io::Error) -> Self {
+        CrittersError::Io {
+            path: path.into(),
+            error,
+        }
+    }
+}
+
+#[cfg(feature = "use-napi")]
+impl From<CrittersError> for napi::JsError<String> {
+    fn from(err: CrittersError) -> Self {
+        napi::Error::new(err.code().to_string(), err.to_string()).into()
+    }
+}
+
+/// A line and column within a stylesheet, both starting from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+fn fmt_location(href: &Option<String>, location: &Option<SourceLocation>) -> String {
+    match (href, location) {
+        (Some(href), Some(location)) => format!(" {href}:{location}"),
+        (Some(href), None) => format!(" {href}"),
+        (None, Some(location)) => format!(" at {location}"),
+        (None, None) => String::new(),
+    }
+}
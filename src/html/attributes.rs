@@ -1,36 +1,43 @@
 use html5ever::{local_name, namespace_url, ns, LocalName, Namespace, Prefix};
-use indexmap::{map::Entry, IndexMap};
+use indexmap::IndexMap;
+use itertools::Itertools;
 use selectors::attr::{CaseSensitivity, SELECTOR_WHITESPACE};
+use std::ops::{Deref, DerefMut};
 
 /// Convenience wrapper around a indexmap that adds method for attributes in the null namespace.
 #[derive(Debug, Clone)]
 pub struct Attributes {
-    /// The list of CSS classes for the element
-    pub class_list: Vec<LocalName>,
+    /// The list of CSS classes for the element, kept in sync with the `class` attribute. Read it
+    /// with [`Attributes::class_list`].
+    pub(crate) class_list: ClassList,
     /// A map of attributes whose name can have namespaces.
     pub(crate) map: IndexMap<ExpandedName, Attribute>,
 }
 
+fn class_name() -> ExpandedName {
+    ExpandedName::new(ns!(), local_name!("class"))
+}
+
 impl Attributes {
     pub(crate) fn new<I>(attributes: I) -> Attributes
     where
         I: IntoIterator<Item = (ExpandedName, Attribute)>,
     {
-        let map: IndexMap<ExpandedName, Attribute> = attributes.into_iter().collect();
-        let class_list = map
-            .get(&ExpandedName {
-                ns: ns!(),
-                local: local_name!("class"),
-            })
-            .map(|a| {
-                a.value
-                    .split(SELECTOR_WHITESPACE)
-                    .map(LocalName::from)
-                    .collect()
-            })
-            .unwrap_or_else(|| Vec::new());
+        let mut attributes = Attributes {
+            map: attributes.into_iter().collect(),
+            class_list: ClassList::default(),
+        };
+        attributes.sync_class_list();
+        attributes
+    }
 
-        Attributes { map, class_list }
+    /// Parses the class list again from the `class` attribute, after it was changed.
+    pub(crate) fn sync_class_list(&mut self) {
+        self.class_list = self
+            .map
+            .get(&class_name())
+            .map(|attr| ClassList::parse(&attr.value))
+            .unwrap_or_default();
     }
 
     #[inline]
@@ -90,19 +97,21 @@ impl Attributes {
             .map(|attr| &*attr.value)
     }
 
-    /// Like IndexMap::get_mut
-    pub fn get_mut<A: Into<LocalName>>(&mut self, local_name: A) -> Option<&mut String> {
-        self.map
-            .get_mut(&ExpandedName::new(ns!(), local_name))
-            .map(|attr| &mut attr.value)
+    /// Like IndexMap::get_mut. The class list is updated once the returned value is dropped.
+    pub fn get_mut<A: Into<LocalName>>(&mut self, local_name: A) -> Option<ValueMut<'_>> {
+        let name = ExpandedName::new(ns!(), local_name);
+        self.map.contains_key(&name).then(|| ValueMut {
+            attributes: self,
+            name,
+        })
     }
 
     /// Like IndexMap::entry
-    pub fn entry<A: Into<LocalName>>(
-        &mut self,
-        local_name: A,
-    ) -> Entry<'_, ExpandedName, Attribute> {
-        self.map.entry(ExpandedName::new(ns!(), local_name))
+    pub fn entry<A: Into<LocalName>>(&mut self, local_name: A) -> Entry<'_> {
+        Entry {
+            attributes: self,
+            name: ExpandedName::new(ns!(), local_name),
+        }
     }
 
     /// Like IndexMap::insert
@@ -111,22 +120,227 @@ impl Attributes {
         local_name: A,
         value: String,
     ) -> Option<Attribute> {
-        self.map.insert(
-            ExpandedName::new(ns!(), local_name),
+        let name = ExpandedName::new(ns!(), local_name);
+        let is_class = name == class_name();
+        let previous = self.map.insert(
+            name,
             Attribute {
                 prefix: None,
                 value,
             },
-        )
+        );
+        if is_class {
+            self.sync_class_list();
+        }
+        previous
     }
 
     /// Like IndexMap::remove
     pub fn remove<A: Into<LocalName>>(&mut self, local_name: A) -> Option<Attribute> {
-        self.map.swap_remove(&ExpandedName::new(ns!(), local_name))
+        let name = ExpandedName::new(ns!(), local_name);
+        let removed = self.map.swap_remove(&name);
+        if name == class_name() {
+            self.class_list = ClassList::default();
+        }
+        removed
     }
 
     /// Like IndexMap::keys
     pub fn keys(&self) -> impl Iterator<Item = &LocalName> {
         self.map.keys().map(|expanded_name| &expanded_name.local)
     }
+
+    /// The classes of the element, from its `class` attribute. This replaces the former public
+    /// `class_list` field, which could get out of sync with the attribute; use
+    /// [`ClassList::as_slice`] where a slice of classes is needed.
+    pub fn class_list(&self) -> &ClassList {
+        &self.class_list
+    }
+
+    /// The classes of the element, with methods to change them along with the `class`
+    /// attribute.
+    pub fn class_list_mut(&mut self) -> ClassListMut<'_> {
+        ClassListMut { attributes: self }
+    }
+}
+
+/// A mutable reference to the value of an attribute. Changes to the `class` attribute are
+/// applied to the class list once it is dropped.
+pub struct ValueMut<'a> {
+    attributes: &'a mut Attributes,
+    name: ExpandedName,
+}
+
+impl Deref for ValueMut<'_> {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.attributes.map[&self.name].value
+    }
+}
+
+impl DerefMut for ValueMut<'_> {
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.attributes.map[&self.name].value
+    }
+}
+
+impl Drop for ValueMut<'_> {
+    fn drop(&mut self) {
+        if self.name == class_name() {
+            self.attributes.sync_class_list();
+        }
+    }
+}
+
+/// An attribute in the null namespace, which may or may not be present.
+pub struct Entry<'a> {
+    attributes: &'a mut Attributes,
+    name: ExpandedName,
+}
+
+impl<'a> Entry<'a> {
+    /// The local name of the attribute.
+    pub fn key(&self) -> &LocalName {
+        &self.name.local
+    }
+
+    /// Inserts the attribute with the given value if it is missing, and returns its value.
+    pub fn or_insert(self, default: String) -> ValueMut<'a> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Inserts the attribute with the value returned by the given function if it is missing, and
+    /// returns its value.
+    pub fn or_insert_with<F: FnOnce() -> String>(self, default: F) -> ValueMut<'a> {
+        self.attributes
+            .map
+            .entry(self.name.clone())
+            .or_insert_with(|| Attribute {
+                prefix: None,
+                value: default(),
+            });
+        ValueMut {
+            attributes: self.attributes,
+            name: self.name,
+        }
+    }
+}
+
+/// The classes of an element, parsed from its `class` attribute as an ordered set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClassList(Vec<LocalName>);
+
+impl ClassList {
+    fn parse(value: &str) -> Self {
+        let mut classes: Vec<LocalName> = Vec::new();
+        for class in value.split(SELECTOR_WHITESPACE).filter(|c| !c.is_empty()) {
+            if !classes.iter().any(|existing| &**existing == class) {
+                classes.push(LocalName::from(class));
+            }
+        }
+        ClassList(classes)
+    }
+
+    /// Returns the classes, in order.
+    pub fn as_slice(&self) -> &[LocalName] {
+        &self.0
+    }
+
+    /// Returns an iterator over the classes, in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, LocalName> {
+        self.0.iter()
+    }
+
+    /// Returns whether the list contains the given class.
+    pub fn contains(&self, class: &str) -> bool {
+        self.0.iter().any(|existing| &**existing == class)
+    }
+
+    /// Returns the number of classes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether there are no classes.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a ClassList {
+    type Item = &'a LocalName;
+    type IntoIter = std::slice::Iter<'a, LocalName>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Returns whether the class can be stored in a class list: it must be neither empty nor
+/// contain whitespace.
+fn is_valid_class(class: &str) -> bool {
+    !class.is_empty() && !class.contains(SELECTOR_WHITESPACE)
+}
+
+/// The classes of an element, which can be changed like the DOM's `classList`. Every change
+/// rewrites the `class` attribute from the list, separating classes with single spaces.
+///
+/// Like `DOMTokenList`, classes which are empty or contain whitespace are rejected, since they
+/// would be parsed differently from the rewritten attribute.
+pub struct ClassListMut<'a> {
+    attributes: &'a mut Attributes,
+}
+
+impl ClassListMut<'_> {
+    /// Returns whether the list contains the given class.
+    pub fn contains(&self, class: &str) -> bool {
+        self.attributes.class_list.contains(class)
+    }
+
+    /// Adds the class if missing. Returns whether it was added, which it is not if the class is
+    /// invalid.
+    pub fn add(&mut self, class: &str) -> bool {
+        if !is_valid_class(class) || self.contains(class) {
+            return false;
+        }
+        self.attributes.class_list.0.push(LocalName::from(class));
+        self.write();
+        true
+    }
+
+    /// Removes the class if present. Returns whether it was removed.
+    pub fn remove(&mut self, class: &str) -> bool {
+        if !self.contains(class) {
+            return false;
+        }
+        self.attributes
+            .class_list
+            .0
+            .retain(|existing| &**existing != class);
+        self.write();
+        true
+    }
+
+    /// Removes the class if present, or adds it otherwise. Returns whether it is now present,
+    /// which it is not if the class is invalid.
+    pub fn toggle(&mut self, class: &str) -> bool {
+        if self.remove(class) {
+            false
+        } else {
+            self.add(class)
+        }
+    }
+
+    fn write(&mut self) {
+        let value = self.attributes.class_list.iter().join(" ");
+        self.attributes
+            .map
+            .entry(class_name())
+            .or_insert_with(|| Attribute {
+                prefix: None,
+                value: String::new(),
+            })
+            .value = value;
+    }
 }
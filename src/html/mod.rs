@@ -1,6 +1,10 @@
 /*!
 
-Kuchikiki (口利き) is an HTML tree manipulation library for Rust.
+The HTML tree used by critters, derived from Kuchikiki (口利き).
+
+Documents parsed with [`parse_html`] can be transformed and then processed in place with
+[`Critters::process_document`](crate::Critters::process_document), which reduces their stylesheets
+without serializing and parsing them again.
 
 */
 
@@ -14,12 +18,15 @@ mod node_data_ref;
 mod parser;
 mod select;
 mod serializer;
+mod state;
 pub mod style_calculation;
 #[cfg(test)]
 mod tests;
 mod tree;
 
-pub use attributes::{Attribute, Attributes, ExpandedName};
+pub use attributes::{
+    Attribute, Attributes, ClassList, ClassListMut, Entry, ExpandedName, ValueMut,
+};
 pub use node_data_ref::NodeDataRef;
 pub use parser::{parse_fragment, parse_html, parse_html_with_options, ParseOpts, Sink};
 pub use select::{Selector, Selectors, Specificity};
@@ -199,6 +199,7 @@ impl TreeSink for Sink {
                     attributes::Attribute { prefix, value }
                 });
         }
+        attributes.sync_class_list();
     }
 
     #[inline]
@@ -1,6 +1,7 @@
 use crate::html::attributes::ExpandedName;
 use crate::html::iter::{NodeIterator, Select};
 use crate::html::node_data_ref::NodeDataRef;
+use crate::html::state::{self, StateCache};
 use crate::html::tree::{ElementData, Node, NodeData, NodeRef};
 use cssparser::{self, CowRcStr, ParseError, SourceLocation, ToCss};
 use html5ever::{local_name, namespace_url, ns, LocalName, Namespace};
@@ -13,6 +14,7 @@ use selectors::parser::{
 use selectors::parser::{SelectorIter, SelectorParseErrorKind};
 use selectors::{self, matching, OpaqueElement};
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KuchikiSelectors;
@@ -31,7 +33,17 @@ impl SelectorImpl for KuchikiSelectors {
     type NonTSPseudoClass = PseudoClass;
     type PseudoElement = PseudoElement;
 
-    type ExtraMatchingData = ();
+    type ExtraMatchingData = DocumentState;
+}
+
+/// State of the document which cannot be derived from its tree, available while matching.
+#[derive(Debug, Clone, Default)]
+pub struct DocumentState {
+    /// The fragment of the document URL, without the leading `#`, whose element matches
+    /// `:target`.
+    pub target: Option<LocalName>,
+    /// State derived from the documents being matched, shared by the clones of this state.
+    pub cache: Rc<StateCache>,
 }
 
 struct KuchikiParser;
@@ -40,6 +52,18 @@ impl<'i> Parser<'i> for KuchikiParser {
     type Impl = KuchikiSelectors;
     type Error = SelectorParseErrorKind<'i>;
 
+    fn parse_slotted(&self) -> bool {
+        true
+    }
+
+    fn parse_part(&self) -> bool {
+        true
+    }
+
+    fn parse_host(&self) -> bool {
+        true
+    }
+
     fn parse_non_ts_pseudo_class(
         &self,
         location: SourceLocation,
@@ -66,6 +90,20 @@ impl<'i> Parser<'i> for KuchikiParser {
             Ok(Checked)
         } else if name.eq_ignore_ascii_case("indeterminate") {
             Ok(Indeterminate)
+        } else if name.eq_ignore_ascii_case("default") {
+            Ok(Default)
+        } else if name.eq_ignore_ascii_case("required") {
+            Ok(Required)
+        } else if name.eq_ignore_ascii_case("optional") {
+            Ok(Optional)
+        } else if name.eq_ignore_ascii_case("read-only") {
+            Ok(ReadOnly)
+        } else if name.eq_ignore_ascii_case("read-write") {
+            Ok(ReadWrite)
+        } else if name.eq_ignore_ascii_case("placeholder-shown") {
+            Ok(PlaceholderShown)
+        } else if name.eq_ignore_ascii_case("target") {
+            Ok(Target)
         } else {
             Err(
                 location.new_custom_error(SelectorParseErrorKind::UnsupportedPseudoClassOrElement(
@@ -74,6 +112,37 @@ impl<'i> Parser<'i> for KuchikiParser {
             )
         }
     }
+
+    fn parse_non_ts_functional_pseudo_class<'t>(
+        &self,
+        name: CowRcStr<'i>,
+        arguments: &mut cssparser::Parser<'i, 't>,
+    ) -> Result<PseudoClass, ParseError<'i, SelectorParseErrorKind<'i>>> {
+        if name.eq_ignore_ascii_case("lang") {
+            let languages = arguments.parse_comma_separated(|parser| {
+                parser
+                    .expect_ident_or_string()
+                    .map(|lang| lang.to_string())
+                    .map_err(Into::into)
+            })?;
+            Ok(PseudoClass::Lang(languages.into_boxed_slice()))
+        } else if name.eq_ignore_ascii_case("dir") {
+            let location = arguments.current_source_location();
+            let direction = arguments.expect_ident()?;
+            if direction.eq_ignore_ascii_case("ltr") {
+                Ok(PseudoClass::Dir(Direction::Ltr))
+            } else if direction.eq_ignore_ascii_case("rtl") {
+                Ok(PseudoClass::Dir(Direction::Rtl))
+            } else {
+                let token = cssparser::Token::Ident(direction.clone());
+                Err(location.new_unexpected_token_error(token))
+            }
+        } else {
+            Err(arguments.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            ))
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug, Hash)]
@@ -88,6 +157,22 @@ pub enum PseudoClass {
     Disabled,
     Checked,
     Indeterminate,
+    Default,
+    Required,
+    Optional,
+    ReadOnly,
+    ReadWrite,
+    PlaceholderShown,
+    Target,
+    Lang(Box<[String]>),
+    Dir(Direction),
+}
+
+/// The directionality matched by `:dir()`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub enum Direction {
+    Ltr,
+    Rtl,
 }
 
 impl NonTSPseudoClass for PseudoClass {
@@ -115,6 +200,18 @@ impl ToCss for PseudoClass {
         W: fmt::Write,
     {
         dest.write_str(match *self {
+            PseudoClass::Lang(ref languages) => {
+                dest.write_str(":lang(")?;
+                for (i, language) in languages.iter().enumerate() {
+                    if i > 0 {
+                        dest.write_str(", ")?;
+                    }
+                    cssparser::serialize_identifier(language, dest)?;
+                }
+                return dest.write_str(")");
+            }
+            PseudoClass::Dir(Direction::Ltr) => ":dir(ltr)",
+            PseudoClass::Dir(Direction::Rtl) => ":dir(rtl)",
             PseudoClass::AnyLink => ":any-link",
             PseudoClass::Link => ":link",
             PseudoClass::Visited => ":visited",
@@ -125,6 +222,13 @@ impl ToCss for PseudoClass {
             PseudoClass::Disabled => ":disabled",
             PseudoClass::Checked => ":checked",
             PseudoClass::Indeterminate => ":indeterminate",
+            PseudoClass::Default => ":default",
+            PseudoClass::Required => ":required",
+            PseudoClass::Optional => ":optional",
+            PseudoClass::ReadOnly => ":read-only",
+            PseudoClass::ReadWrite => ":read-write",
+            PseudoClass::PlaceholderShown => ":placeholder-shown",
+            PseudoClass::Target => ":target",
         })
     }
 }
@@ -156,15 +260,46 @@ impl selectors::Element for NodeDataRef<ElementData> {
 
     #[inline]
     fn is_html_slot_element(&self) -> bool {
-        false
+        self.name.ns == ns!(html) && self.name.local == local_name!("slot")
     }
     #[inline]
     fn parent_node_is_shadow_root(&self) -> bool {
-        false
+        self.as_node()
+            .parent()
+            .is_some_and(|parent| parent.shadow_host().is_some())
     }
     #[inline]
     fn containing_shadow_host(&self) -> Option<Self> {
-        None
+        let mut root = self.as_node().clone();
+        while let Some(parent) = root.parent() {
+            root = parent;
+        }
+        root.shadow_host().and_then(NodeRef::into_element_ref)
+    }
+
+    fn assigned_slot(&self) -> Option<Self> {
+        // only children of a shadow host are slotted, other than the shadow root declaration
+        let host = self.parent_element()?;
+        let shadow_root = host.as_node().shadow_root()?;
+        if self.name.local == local_name!("template")
+            && self.attributes.borrow().contains("shadowrootmode")
+        {
+            return None;
+        }
+
+        let attributes = self.attributes.borrow();
+        let slot_name = attributes.get(local_name!("slot")).unwrap_or_default();
+        shadow_root
+            .descendants()
+            .elements()
+            .filter(|el| el.is_html_slot_element())
+            .find(|slot| {
+                slot.attributes
+                    .borrow()
+                    .get(local_name!("name"))
+                    .unwrap_or_default()
+                    == slot_name
+            })
     }
 
     #[inline]
@@ -211,18 +346,23 @@ impl selectors::Element for NodeDataRef<ElementData> {
     }
 
     #[inline]
-    fn is_part(&self, _name: &LocalName) -> bool {
-        false
+    fn is_part(&self, name: &LocalName) -> bool {
+        self.attributes
+            .borrow()
+            .get("part")
+            .is_some_and(|parts| parts.split_ascii_whitespace().any(|part| part == &**name))
     }
 
-    #[inline]
-    fn exported_part(&self, _: &LocalName) -> Option<LocalName> {
-        None
+    fn exported_part(&self, name: &LocalName) -> Option<LocalName> {
+        exported_parts(self)
+            .find(|(inner, _)| inner == name)
+            .map(|(_, outer)| outer)
     }
 
-    #[inline]
-    fn imported_part(&self, _: &LocalName) -> Option<LocalName> {
-        None
+    fn imported_part(&self, name: &LocalName) -> Option<LocalName> {
+        exported_parts(self)
+            .find(|(_, outer)| outer == name)
+            .map(|(inner, _)| inner)
     }
 
     #[inline]
@@ -294,7 +434,7 @@ impl selectors::Element for NodeDataRef<ElementData> {
     fn match_non_ts_pseudo_class<F>(
         &self,
         pseudo: &PseudoClass,
-        _context: &mut matching::MatchingContext<KuchikiSelectors>,
+        context: &mut matching::MatchingContext<KuchikiSelectors>,
         _flags_setter: &mut F,
     ) -> bool
     where
@@ -302,9 +442,30 @@ impl selectors::Element for NodeDataRef<ElementData> {
     {
         use self::PseudoClass::*;
         match *pseudo {
-            Active | Focus | Hover | Enabled | Disabled | Checked | Indeterminate | Visited => {
-                false
-            }
+            // user action states never apply on load
+            Active | Focus | Hover | Visited => false,
+            Enabled => state::is_disableable(self) && !state::is_disabled(self),
+            Disabled => state::is_disableable(self) && state::is_disabled(self),
+            Checked => state::is_checked(self, &context.extra_data.cache),
+            Indeterminate => state::is_indeterminate(self, &context.extra_data.cache),
+            Default => state::is_default(self, &context.extra_data.cache),
+            Required => state::is_required(self) == Some(true),
+            Optional => state::is_required(self) == Some(false),
+            ReadOnly => !state::is_read_write(self),
+            ReadWrite => state::is_read_write(self),
+            PlaceholderShown => state::is_placeholder_shown(self),
+            Target => context
+                .extra_data
+                .target
+                .as_ref()
+                .is_some_and(|target| self.has_id(target, CaseSensitivity::CaseSensitive)),
+            Lang(ref ranges) => state::element_language(self, &context.extra_data.cache)
+                .is_some_and(|lang| {
+                    ranges
+                        .iter()
+                        .any(|range| state::language_matches(&lang, range))
+                }),
+            Dir(direction) => state::element_direction(self) == direction,
             AnyLink | Link => {
                 self.name.ns == ns!(html)
                     && matches!(
@@ -317,6 +478,47 @@ impl selectors::Element for NodeDataRef<ElementData> {
     }
 }
 
+/// Returns the parts of a shadow tree exported by its host through the `exportparts`
+/// attribute, as pairs of inner and outer names.
+fn exported_parts(host: &NodeDataRef<ElementData>) -> impl Iterator<Item = (LocalName, LocalName)> {
+    let exportparts = host
+        .attributes
+        .borrow()
+        .get("exportparts")
+        .unwrap_or_default()
+        .to_string();
+    exportparts
+        .split(',')
+        .filter_map(|mapping| {
+            let (inner, outer) = mapping.split_once(':').unwrap_or((mapping, mapping));
+            let (inner, outer) = (inner.trim(), outer.trim());
+            (!inner.is_empty() && !outer.is_empty()).then(|| (inner.into(), outer.into()))
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// Returns the quirks mode of the document containing the node, in which class and id selectors
+/// match case-insensitively. Shadow trees share the quirks mode of their host's document.
+pub(crate) fn quirks_mode(node: &NodeRef) -> QuirksMode {
+    let mut root = node.clone();
+    loop {
+        while let Some(parent) = root.parent() {
+            root = parent;
+        }
+        match root.shadow_host() {
+            Some(host) => root = host,
+            None => break,
+        }
+    }
+
+    match root.as_document().map(|document| document.quirks_mode()) {
+        Some(html5ever::tree_builder::QuirksMode::Quirks) => QuirksMode::Quirks,
+        Some(html5ever::tree_builder::QuirksMode::LimitedQuirks) => QuirksMode::LimitedQuirks,
+        _ => QuirksMode::NoQuirks,
+    }
+}
+
 /// A pre-compiled list of CSS Selectors.
 pub struct Selectors(pub Vec<Selector>);
 
@@ -332,14 +534,15 @@ impl Selector {
         self.0.iter()
     }
 
-    /// Returns whether the given element matches this selector.
+    /// Returns whether the given element matches this selector, in the quirks mode of its
+    /// document.
     #[inline]
     pub fn matches(&self, element: &NodeDataRef<ElementData>) -> bool {
         let mut context = matching::MatchingContext::new(
             matching::MatchingMode::Normal,
             None,
             None,
-            QuirksMode::NoQuirks,
+            quirks_mode(element.as_node()),
         );
         matching::matches_selector(&self.0, 0, None, element, &mut context, &mut |_, _| {})
     }
@@ -360,8 +563,8 @@ impl Selector {
         Specificity(self.0.specificity())
     }
 
-    pub(crate) fn ancestor_hashes(&self) -> AncestorHashes {
-        AncestorHashes::new(&self.0, QuirksMode::NoQuirks)
+    pub(crate) fn ancestor_hashes(&self, quirks_mode: QuirksMode) -> AncestorHashes {
+        AncestorHashes::new(&self.0, quirks_mode)
     }
 }
 
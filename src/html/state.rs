@@ -0,0 +1,559 @@
+//! Element states which can be evaluated from the markup alone, such as the state of form
+//! controls on load and the language and directionality of elements.
+
+use crate::html::iter::NodeIterator;
+use crate::html::select::Direction;
+use crate::html::{ElementData, NodeDataRef, NodeRef};
+use html5ever::{local_name, namespace_url, ns, LocalName};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+type Element = NodeDataRef<ElementData>;
+
+/// State derived from whole documents, computed once for all the elements matched against
+/// rather than for each of them.
+#[derive(Debug, Default)]
+pub struct StateCache {
+    /// The language from the `Content-Language` pragma of each document, by root.
+    pragma_languages: RefCell<HashMap<NodeRef, Option<String>>>,
+    /// The named radio buttons of each form, or of each document for those without a form
+    /// owner, grouped by name in tree order.
+    radio_groups: RefCell<HashMap<NodeRef, HashMap<String, Vec<Element>>>>,
+    /// The selected option of each single-selection `<select>`, if any.
+    selected_options: RefCell<HashMap<NodeRef, Option<Element>>>,
+    /// The first submit button of each form, if any.
+    default_buttons: RefCell<HashMap<NodeRef, Option<Element>>>,
+}
+
+/// Input types accepting free-form text, to which `readonly` and `placeholder` apply.
+const TEXT_INPUT_TYPES: &[&str] = &[
+    "text",
+    "search",
+    "url",
+    "tel",
+    "email",
+    "password",
+    "date",
+    "month",
+    "week",
+    "time",
+    "datetime-local",
+    "number",
+];
+
+/// Other input types, which are all known to the parser.
+const OTHER_INPUT_TYPES: &[&str] = &[
+    "hidden", "range", "color", "checkbox", "radio", "file", "submit", "image", "reset", "button",
+];
+
+fn is_html(element: &Element, name: LocalName) -> bool {
+    element.name.ns == ns!(html) && element.name.local == name
+}
+
+fn has_attribute(element: &Element, name: &str) -> bool {
+    element.attributes.borrow().contains(name)
+}
+
+/// Returns the state of an `<input>` from its `type` attribute, defaulting to text.
+fn input_type(element: &Element) -> Option<String> {
+    if !is_html(element, local_name!("input")) {
+        return None;
+    }
+    let input_type = element
+        .attributes
+        .borrow()
+        .get(local_name!("type"))
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    if TEXT_INPUT_TYPES.contains(&&*input_type) || OTHER_INPUT_TYPES.contains(&&*input_type) {
+        Some(input_type)
+    } else {
+        Some("text".to_string())
+    }
+}
+
+fn is_input_of_type(element: &Element, types: &[&str]) -> bool {
+    input_type(element).is_some_and(|input_type| types.contains(&&*input_type))
+}
+
+/// Returns the parent element, or the shadow host for the children of a shadow root.
+fn parent_element(element: &Element) -> Option<Element> {
+    let parent = element.as_node().parent()?;
+    parent.shadow_host().unwrap_or(parent).into_element_ref()
+}
+
+/// Returns whether the element can be disabled, and thus matches either `:enabled` or
+/// `:disabled`.
+pub fn is_disableable(element: &Element) -> bool {
+    element.name.ns == ns!(html)
+        && matches!(
+            element.name.local,
+            local_name!("button")
+                | local_name!("input")
+                | local_name!("select")
+                | local_name!("textarea")
+                | local_name!("optgroup")
+                | local_name!("option")
+                | local_name!("fieldset")
+        )
+}
+
+/// Returns whether the element is disabled, either by its own `disabled` attribute, by a disabled
+/// `<optgroup>` or by a disabled ancestor `<fieldset>`, outside of the fieldset's first
+/// `<legend>`.
+pub fn is_disabled(element: &Element) -> bool {
+    if has_attribute(element, "disabled") {
+        return true;
+    }
+
+    if is_html(element, local_name!("option")) {
+        return parent_element(element).is_some_and(|parent| {
+            is_html(&parent, local_name!("optgroup")) && has_attribute(&parent, "disabled")
+        });
+    }
+    if is_html(element, local_name!("optgroup")) {
+        return false;
+    }
+
+    let mut child = element.as_node().clone();
+    for ancestor in element.as_node().ancestors() {
+        if let Some(fieldset) = ancestor.clone().into_element_ref() {
+            if is_html(&fieldset, local_name!("fieldset")) && has_attribute(&fieldset, "disabled") {
+                let legend = ancestor
+                    .children()
+                    .elements()
+                    .find(|child| is_html(child, local_name!("legend")));
+                if legend.is_none_or(|legend| *legend.as_node() != child) {
+                    return true;
+                }
+            }
+        }
+        child = ancestor;
+    }
+    false
+}
+
+/// Returns the form owner of the element, its nearest ancestor `<form>`. The `form` attribute
+/// is not considered.
+fn form_owner(element: &Element) -> Option<NodeRef> {
+    element.as_node().ancestors().find(|ancestor| {
+        ancestor
+            .as_element()
+            .is_some_and(|el| el.name.ns == ns!(html) && el.name.local == local_name!("form"))
+    })
+}
+
+/// Returns the root of the tree containing the node.
+fn tree_root(node: &NodeRef) -> NodeRef {
+    let mut root = node.clone();
+    while let Some(parent) = root.parent() {
+        root = parent;
+    }
+    root
+}
+
+/// Returns the radio buttons in the group of the given radio button, in tree order: those with
+/// the same name and form owner. A radio button without a name forms a group by itself.
+fn radio_group(radio: &Element, cache: &StateCache) -> Vec<Element> {
+    let name = radio
+        .attributes
+        .borrow()
+        .get(local_name!("name"))
+        .unwrap_or_default()
+        .to_string();
+    if name.is_empty() {
+        return vec![radio.clone()];
+    }
+
+    let owner = form_owner(radio);
+    let scope = owner.clone().unwrap_or_else(|| tree_root(radio.as_node()));
+    let mut groups = cache.radio_groups.borrow_mut();
+    let groups = groups
+        .entry(scope.clone())
+        .or_insert_with(|| radio_groups(&scope, owner.as_ref()));
+    groups.get(&name).cloned().unwrap_or_default()
+}
+
+/// Returns the named radio buttons within the scope whose form owner is the given form, grouped
+/// by name.
+fn radio_groups(scope: &NodeRef, owner: Option<&NodeRef>) -> HashMap<String, Vec<Element>> {
+    let mut groups = HashMap::<String, Vec<Element>>::new();
+    for radio in scope.descendants().elements() {
+        if !is_input_of_type(&radio, &["radio"]) || form_owner(&radio).as_ref() != owner {
+            continue;
+        }
+        let name = radio
+            .attributes
+            .borrow()
+            .get(local_name!("name"))
+            .unwrap_or_default()
+            .to_string();
+        if !name.is_empty() {
+            groups.entry(name).or_default().push(radio);
+        }
+    }
+    groups
+}
+
+/// Returns whether the radio button is checked: the last radio button of its group with the
+/// `checked` attribute is.
+fn is_checked_radio(radio: &Element, cache: &StateCache) -> bool {
+    radio_group(radio, cache)
+        .into_iter()
+        .rev()
+        .find(|other| has_attribute(other, "checked"))
+        .is_some_and(|checked| checked == *radio)
+}
+
+/// Returns the `<select>` the option belongs to, if any.
+fn option_select(option: &Element) -> Option<Element> {
+    let parent = parent_element(option)?;
+    if is_html(&parent, local_name!("select")) {
+        return Some(parent);
+    }
+    parent_element(&parent).filter(|select| {
+        is_html(&parent, local_name!("optgroup")) && is_html(select, local_name!("select"))
+    })
+}
+
+/// Returns whether the option is selected. In a single-selection `<select>`, the last option with
+/// the `selected` attribute is selected, or else the first option which is not disabled when the
+/// select is displayed as a drop-down box.
+fn is_selected_option(option: &Element, cache: &StateCache) -> bool {
+    let selected = has_attribute(option, "selected");
+    let Some(select) = option_select(option) else {
+        return selected;
+    };
+    if select.attributes.borrow().contains("multiple") {
+        return selected;
+    }
+
+    cache
+        .selected_options
+        .borrow_mut()
+        .entry(select.as_node().clone())
+        .or_insert_with(|| selected_option(&select))
+        .as_ref()
+        .is_some_and(|selected| selected == option)
+}
+
+/// Returns the selected option of a single-selection `<select>`, if any.
+fn selected_option(select: &Element) -> Option<Element> {
+    let options = select
+        .as_node()
+        .descendants()
+        .elements()
+        .filter(|el| {
+            is_html(el, local_name!("option")) && option_select(el).as_ref() == Some(select)
+        })
+        .collect::<Vec<_>>();
+    if let Some(last) = options
+        .iter()
+        .rev()
+        .find(|el| has_attribute(el, "selected"))
+    {
+        return Some(last.clone());
+    }
+
+    let size = select
+        .attributes
+        .borrow()
+        .get(local_name!("size"))
+        .and_then(|size| size.trim().parse::<u32>().ok())
+        .unwrap_or(1);
+    if size > 1 {
+        return None;
+    }
+    options.into_iter().find(|el| !is_disabled(el))
+}
+
+/// Returns whether the element matches `:checked`: checked checkboxes and radio buttons, and
+/// selected options.
+pub fn is_checked(element: &Element, cache: &StateCache) -> bool {
+    match input_type(element).as_deref() {
+        Some("checkbox") => has_attribute(element, "checked"),
+        Some("radio") => is_checked_radio(element, cache),
+        Some(_) => false,
+        None => is_html(element, local_name!("option")) && is_selected_option(element, cache),
+    }
+}
+
+/// Returns whether the element matches `:indeterminate`: radio buttons in a group without any
+/// checked radio button, and progress bars without a value. The indeterminate state of
+/// checkboxes can only be set from scripts.
+pub fn is_indeterminate(element: &Element, cache: &StateCache) -> bool {
+    if is_input_of_type(element, &["radio"]) {
+        return radio_group(element, cache)
+            .iter()
+            .all(|radio| !has_attribute(radio, "checked"));
+    }
+    is_html(element, local_name!("progress")) && !has_attribute(element, "value")
+}
+
+fn is_submit_button(element: &Element) -> bool {
+    if is_html(element, local_name!("button")) {
+        let button_type = element
+            .attributes
+            .borrow()
+            .get(local_name!("type"))
+            .map(str::to_ascii_lowercase);
+        return !matches!(button_type.as_deref(), Some("reset" | "button"));
+    }
+    is_input_of_type(element, &["submit", "image"])
+}
+
+/// Returns whether the element matches `:default`: checkboxes and radio buttons checked by
+/// default, options selected by default, and the first submit button of each form.
+pub fn is_default(element: &Element, cache: &StateCache) -> bool {
+    if is_input_of_type(element, &["checkbox", "radio"]) {
+        return has_attribute(element, "checked");
+    }
+    if is_html(element, local_name!("option")) {
+        return has_attribute(element, "selected");
+    }
+    if !is_submit_button(element) {
+        return false;
+    }
+    let Some(form) = form_owner(element) else {
+        return false;
+    };
+    cache
+        .default_buttons
+        .borrow_mut()
+        .entry(form.clone())
+        .or_insert_with(|| {
+            form.descendants()
+                .elements()
+                .find(|el| is_submit_button(el) && form_owner(el).as_ref() == Some(&form))
+        })
+        .as_ref()
+        .is_some_and(|first| first == element)
+}
+
+/// Returns whether the element is required, or `None` if `required` does not apply to it.
+pub fn is_required(element: &Element) -> Option<bool> {
+    let applies = is_html(element, local_name!("select"))
+        || is_html(element, local_name!("textarea"))
+        || is_input_of_type(element, TEXT_INPUT_TYPES)
+        || is_input_of_type(element, &["checkbox", "radio", "file"]);
+    applies.then(|| has_attribute(element, "required"))
+}
+
+/// Returns whether the element matches `:read-write`: text fields which are neither read-only nor
+/// disabled, and editable content.
+pub fn is_read_write(element: &Element) -> bool {
+    if input_type(element).is_some() || is_html(element, local_name!("textarea")) {
+        return (is_html(element, local_name!("textarea"))
+            || is_input_of_type(element, TEXT_INPUT_TYPES))
+            && !has_attribute(element, "readonly")
+            && !is_disabled(element);
+    }
+
+    // the nearest valid `contenteditable` attribute determines whether content is editable
+    element
+        .as_node()
+        .inclusive_ancestors()
+        .filter_map(|ancestor| {
+            let ancestor = ancestor.into_element_ref()?;
+            let attributes = ancestor.attributes.borrow();
+            match attributes
+                .get("contenteditable")?
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "" | "true" | "plaintext-only" => Some(true),
+                "false" => Some(false),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(false)
+}
+
+/// Returns whether the element matches `:placeholder-shown`: text fields with a placeholder and
+/// an empty value.
+pub fn is_placeholder_shown(element: &Element) -> bool {
+    if !has_attribute(element, "placeholder") {
+        return false;
+    }
+    if is_html(element, local_name!("textarea")) {
+        return element.text_contents().is_empty();
+    }
+    is_input_of_type(
+        element,
+        &[
+            "text", "search", "url", "tel", "email", "password", "number",
+        ],
+    ) && element
+        .attributes
+        .borrow()
+        .get(local_name!("value"))
+        .unwrap_or_default()
+        .is_empty()
+}
+
+/// Returns the language of the element, from the nearest `xml:lang` or `lang` attribute or else
+/// from a `Content-Language` pragma. An empty language is unknown.
+pub fn element_language(element: &Element, cache: &StateCache) -> Option<String> {
+    let mut current = Some(element.clone());
+    while let Some(element) = current {
+        let attributes = element.attributes.borrow();
+        let lang = attributes
+            .map
+            .iter()
+            .find(|(name, _)| name.ns == ns!(xml) && name.local == local_name!("lang"))
+            .map(|(_, attr)| &*attr.value)
+            .or_else(|| attributes.get(local_name!("lang")));
+        if let Some(lang) = lang {
+            return Some(lang.trim().to_string());
+        }
+        drop(attributes);
+        current = parent_element(&element);
+    }
+
+    let root = tree_root(element.as_node());
+    cache
+        .pragma_languages
+        .borrow_mut()
+        .entry(root.clone())
+        .or_insert_with(|| pragma_language(&root))
+        .clone()
+}
+
+/// Returns the language from the `Content-Language` pragma of the document, if it holds a single
+/// language.
+fn pragma_language(root: &NodeRef) -> Option<String> {
+    root.descendants()
+        .elements()
+        .filter(|meta| is_html(meta, local_name!("meta")))
+        .find_map(|meta| {
+            let attributes = meta.attributes.borrow();
+            let http_equiv = attributes.get(local_name!("http-equiv"))?;
+            let content = attributes.get(local_name!("content"))?.trim();
+            (http_equiv.eq_ignore_ascii_case("content-language") && !content.contains(','))
+                .then(|| content.to_string())
+        })
+}
+
+/// Returns whether the language tag matches the language range, using extended filtering as
+/// defined by RFC 4647, so that `en` matches `en-US` and `*-CH` matches `de-CH`.
+pub fn language_matches(tag: &str, range: &str) -> bool {
+    if tag.is_empty() || range.is_empty() {
+        return tag.is_empty() && range.is_empty();
+    }
+
+    let mut tag = tag.split('-');
+    let mut range = range.split('-');
+    let (first_tag, first_range) = (
+        tag.next().unwrap_or_default(),
+        range.next().unwrap_or_default(),
+    );
+    if first_range != "*" && !first_range.eq_ignore_ascii_case(first_tag) {
+        return false;
+    }
+
+    let mut subtag = tag.next();
+    for range_subtag in range {
+        if range_subtag == "*" {
+            continue;
+        }
+        loop {
+            match subtag {
+                None => return false,
+                Some(s) if s.eq_ignore_ascii_case(range_subtag) => {
+                    subtag = tag.next();
+                    break;
+                }
+                // singletons such as `x` for private use cannot be skipped
+                Some(s) if s.len() == 1 => return false,
+                Some(_) => subtag = tag.next(),
+            }
+        }
+    }
+    true
+}
+
+/// Returns the direction of the element, from the nearest `dir` attribute or else left-to-right.
+/// `dir="auto"` and `<bdi>` elements take the direction of the first strongly directional
+/// character of their text.
+pub fn element_direction(element: &Element) -> Direction {
+    let dir = element
+        .attributes
+        .borrow()
+        .get(local_name!("dir"))
+        .map(str::to_ascii_lowercase);
+    match dir.as_deref() {
+        Some("ltr") => return Direction::Ltr,
+        Some("rtl") => return Direction::Rtl,
+        Some("auto") => return auto_direction(element),
+        _ => (),
+    }
+    if is_html(element, local_name!("bdi")) {
+        return auto_direction(element);
+    }
+    if is_input_of_type(element, &["tel"]) {
+        return Direction::Ltr;
+    }
+
+    parent_element(element)
+        .map(|parent| element_direction(&parent))
+        .unwrap_or(Direction::Ltr)
+}
+
+fn auto_direction(element: &Element) -> Direction {
+    let direction = if input_type(element).is_some() {
+        let attributes = element.attributes.borrow();
+        text_direction(attributes.get(local_name!("value")).unwrap_or_default())
+    } else if is_html(element, local_name!("textarea")) {
+        text_direction(&element.text_contents())
+    } else {
+        descendant_text_direction(element.as_node())
+    };
+    direction.unwrap_or(Direction::Ltr)
+}
+
+/// Returns the direction of the first strongly directional character of the descendant text,
+/// skipping elements with their own directionality.
+fn descendant_text_direction(node: &NodeRef) -> Option<Direction> {
+    node.children().find_map(|child| {
+        if let Some(text) = child.as_text() {
+            return text_direction(&text.borrow());
+        }
+        let element = child.clone().into_element_ref()?;
+        let skipped = ["bdi", "script", "style", "textarea"]
+            .iter()
+            .any(|name| element.name.local.as_ref() == *name)
+            || element
+                .attributes
+                .borrow()
+                .get(local_name!("dir"))
+                .is_some_and(|dir| {
+                    ["ltr", "rtl", "auto"]
+                        .iter()
+                        .any(|valid| dir.eq_ignore_ascii_case(valid))
+                });
+        if skipped {
+            None
+        } else {
+            descendant_text_direction(&child)
+        }
+    })
+}
+
+/// Returns the direction of the first strongly directional character of the text. Characters of
+/// right-to-left scripts such as Hebrew and Arabic are right-to-left, other letters are
+/// left-to-right.
+fn text_direction(text: &str) -> Option<Direction> {
+    text.chars().find_map(|c| {
+        if matches!(
+            c as u32,
+            0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF
+        ) {
+            Some(Direction::Rtl)
+        } else if c.is_alphabetic() {
+            Some(Direction::Ltr)
+        } else {
+            None
+        }
+    })
+}
@@ -4,12 +4,17 @@
 //! using techniques from modern browser engines like Blink and WebKit.
 
 use crate::html::filter::StyleBloom;
-use crate::html::{ElementData, NodeDataRef, Selector};
+use crate::html::iter::NodeIterator;
+use crate::html::select::quirks_mode;
+use crate::html::select::DocumentState;
+use crate::html::{ElementData, NodeDataRef, NodeRef, Selector, Selectors};
+use crate::utils::regex;
 use html5ever::{local_name, LocalName};
-use selectors::context::{MatchingContext, MatchingMode};
+use selectors::context::{MatchingContext, MatchingMode, QuirksMode};
 use selectors::parser::{AncestorHashes, Component};
 use smallvec::SmallVec;
 use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
 
 /// A CSS rule with selector, specificity, and declaration block.
 #[derive(Debug, Clone, Eq)]
@@ -18,13 +23,51 @@ struct Rule {
     pub selector: Selector,
     /// The ancestor hashes for this rule
     pub hashes: AncestorHashes,
+    /// The kind of element targeted by this rule
+    pub target: Target,
 }
 
 impl Rule {
     /// Creates a new CSS rule with the given parameters.
-    pub fn new(selector: Selector) -> Self {
-        let hashes = selector.ancestor_hashes();
-        Self { selector, hashes }
+    pub fn new(selector: Selector, quirks_mode: QuirksMode) -> Self {
+        let hashes = selector.ancestor_hashes(quirks_mode);
+        let target = Target::of(&selector);
+        Self {
+            selector,
+            hashes,
+            target,
+        }
+    }
+}
+
+/// The kind of element a selector targets, relative to the tree of the stylesheet it belongs
+/// to. Elements are only matched against rules targeting their kind, since shadow trees are
+/// encapsulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// Elements in the tree of the stylesheet.
+    Tree,
+    /// The shadow host, through `:host`.
+    Host,
+    /// Elements assigned to a slot of the shadow tree, through `::slotted()`.
+    Slotted,
+    /// Elements in nested shadow trees, through `::part()`.
+    Part,
+}
+
+impl Target {
+    /// Determines the target of a selector from its rightmost compound selector.
+    fn of(selector: &Selector) -> Self {
+        let mut target = Target::Tree;
+        for component in selector.iter() {
+            match component {
+                Component::Host(_) => target = Target::Host,
+                Component::Slotted(_) => return Target::Slotted,
+                Component::Part(_) => return Target::Part,
+                _ => (),
+            }
+        }
+        target
     }
 }
 
@@ -44,7 +87,7 @@ impl PartialEq for Rule {
 ///
 /// Rules are partitioned into buckets by their key selector component,
 /// enabling O(1) lookup time for most selector types.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct RuleSet {
     /// Rules indexed by ID selectors (highest priority)
     pub id_rules: HashMap<String, Vec<Rule>>,
@@ -56,12 +99,36 @@ struct RuleSet {
     pub universal_rules: Vec<Rule>,
     /// Total rule count for performance tracking
     pub rule_count: usize,
+    /// The quirks mode of the document, in which ids and classes are indexed in lowercase
+    pub quirks_mode: QuirksMode,
 }
 
 impl RuleSet {
-    /// Creates a new empty rule set.
-    pub fn new() -> Self {
-        Self::default()
+    /// Creates a rule set from the given selectors, for a document in the given quirks mode.
+    pub fn new(selectors: impl IntoIterator<Item = Selector>, quirks_mode: QuirksMode) -> Self {
+        let mut set = Self {
+            id_rules: HashMap::new(),
+            class_rules: HashMap::new(),
+            tag_rules: HashMap::new(),
+            universal_rules: Vec::new(),
+            rule_count: 0,
+            quirks_mode,
+        };
+
+        for selector in selectors {
+            set.add_rule(Rule::new(selector, quirks_mode));
+        }
+
+        set
+    }
+
+    /// Returns the key under which the given id or class is indexed.
+    fn key(&self, name: &str) -> String {
+        if self.quirks_mode == QuirksMode::Quirks {
+            name.to_ascii_lowercase()
+        } else {
+            name.to_string()
+        }
     }
 
     /// Adds a rule to the appropriate hash bucket based on its key selector.
@@ -70,13 +137,14 @@ impl RuleSet {
 
         match key_component {
             KeySelector::Id(id) => {
-                self.id_rules.entry(id).or_default().push(rule);
+                self.id_rules.entry(self.key(&id)).or_default().push(rule);
             }
             KeySelector::Class(class) => {
+                let class = LocalName::from(self.key(&class));
                 self.class_rules.entry(class).or_default().push(rule);
             }
             KeySelector::Tag(tag) => {
-                self.tag_rules.entry(tag.into()).or_default().push(rule);
+                self.tag_rules.entry(tag).or_default().push(rule);
             }
             KeySelector::Universal => {
                 self.universal_rules.push(rule);
@@ -124,14 +192,18 @@ impl RuleSet {
 
         // Check ID rules
         if let Some(id) = attributes.get(local_name!("id")) {
-            if let Some(id_rules) = self.id_rules.get(id) {
+            if let Some(id_rules) = self.id_rules.get(&self.key(id)) {
                 rules.extend(id_rules.iter());
             }
         }
 
         // Check class rules
         for class in &attributes.class_list {
-            if let Some(class_rules) = self.class_rules.get(class) {
+            let class = match self.quirks_mode {
+                QuirksMode::Quirks => LocalName::from(class.to_ascii_lowercase()),
+                _ => class.clone(),
+            };
+            if let Some(class_rules) = self.class_rules.get(&class) {
                 rules.extend(class_rules.iter());
             }
         }
@@ -145,17 +217,6 @@ impl RuleSet {
         rules
     }
 }
-impl FromIterator<Rule> for RuleSet {
-    fn from_iter<I: IntoIterator<Item = Rule>>(iter: I) -> Self {
-        let mut set = RuleSet::new();
-
-        for rule in iter {
-            set.add_rule(rule);
-        }
-
-        set
-    }
-}
 
 #[derive(Debug, Clone)]
 enum KeySelector {
@@ -166,7 +227,14 @@ enum KeySelector {
 }
 
 #[inline]
-fn matches_rule(element: &NodeDataRef<ElementData>, rule: &Rule, bloom: &mut StyleBloom) -> bool {
+fn matches_rule(
+    element: &NodeDataRef<ElementData>,
+    rule: &Rule,
+    bloom: &mut StyleBloom,
+    host: Option<&NodeDataRef<ElementData>>,
+    state: &DocumentState,
+    quirks_mode: QuirksMode,
+) -> bool {
     if cfg!(debug_assertions) {
         bloom.assert_complete(element.clone());
     }
@@ -175,8 +243,10 @@ fn matches_rule(element: &NodeDataRef<ElementData>, rule: &Rule, bloom: &mut Sty
         MatchingMode::Normal,
         Some(bloom.filter()),
         None,
-        selectors::context::QuirksMode::NoQuirks,
+        quirks_mode,
     );
+    context.current_host = host.map(selectors::Element::opaque);
+    context.extra_data = state.clone();
 
     rule.selector
         .matches_with_context(element, Some(&rule.hashes), &mut context)
@@ -192,18 +262,73 @@ fn calculate_matching_rules<'a>(
     rule_set: &'a RuleSet,
     bloom: &mut StyleBloom,
     rules: &mut HashSet<&'a Rule>,
+    host: Option<&NodeDataRef<ElementData>>,
+    state: &DocumentState,
+    target: Target,
 ) {
     // Get potential matching rules from indexed buckets
     let potential_rules = rule_set.get_potential_rules(element);
 
     // Filter rules by actually matching selectors and insert into the set
     for rule in potential_rules {
-        if matches_rule(element, rule, bloom) {
+        if rule.target == target
+            && matches_rule(element, rule, bloom, host, state, rule_set.quirks_mode)
+        {
             rules.insert(rule);
         }
     }
 }
 
+/// Controls which elements are visited when calculating styles for a tree.
+#[derive(Debug, Default)]
+pub struct TraversalOptions {
+    /// Elements matching these selectors are skipped, along with their descendants.
+    pub ignore: Option<Selectors>,
+    /// Skip elements which are hidden on load, along with their descendants: elements with the
+    /// `hidden` attribute or an inline `display: none` style, closed `<dialog>`s and `<template>`s.
+    pub skip_hidden: bool,
+    /// The first element past the fold, see [`find_fold`]. This element and every element after
+    /// it in document order are skipped.
+    pub fold: Option<NodeDataRef<ElementData>>,
+    /// The fragment of the document URL, without the leading `#`. The element with this id
+    /// matches `:target`.
+    pub target: Option<String>,
+}
+
+impl TraversalOptions {
+    /// Returns whether the given element and its descendants should be skipped.
+    fn should_skip(&self, element: &NodeDataRef<ElementData>) -> bool {
+        let attributes = element.attributes.borrow();
+        if attributes.contains("data-critters-ignore") {
+            return true;
+        }
+
+        if self.skip_hidden {
+            let name = &element.name.local;
+            if attributes.contains("hidden")
+                || *name == local_name!("template")
+                || (*name == local_name!("dialog") && !attributes.contains("open"))
+            {
+                return true;
+            }
+
+            let display_none =
+                regex!(r"(?i)(?:^|;)\s*display\s*:\s*none\s*(?:!\s*important\s*)?(?:;|$)");
+            if attributes
+                .get("style")
+                .is_some_and(|style| display_none.is_match(style))
+            {
+                return true;
+            }
+        }
+        drop(attributes);
+
+        self.ignore
+            .as_ref()
+            .is_some_and(|ignore| ignore.matches(element))
+    }
+}
+
 /// Calculates matching styles for all elements in a DOM tree.
 ///
 /// Returns a mapping from elements to their matching CSS rules,
@@ -212,39 +337,370 @@ pub fn calculate_styles_for_tree(
     root: &NodeDataRef<ElementData>,
     selectors: impl IntoIterator<Item = Selector>,
 ) -> HashSet<Selector> {
-    let rule_set: RuleSet = selectors.into_iter().map(Rule::new).collect();
-    let mut bloom = StyleBloom::new();
-    bloom.rebuild(root.clone());
+    calculate_styles_for_trees(std::slice::from_ref(root), selectors, &Default::default())
+}
+
+/// Calculates matching styles for all elements in several DOM trees, combining the results.
+///
+/// Roots nested within another root are skipped, as their elements are already visited.
+/// Elements of the shadow trees within the roots are only matched against `::part()` rules.
+pub fn calculate_styles_for_trees(
+    roots: &[NodeDataRef<ElementData>],
+    selectors: impl IntoIterator<Item = Selector>,
+    options: &TraversalOptions,
+) -> HashSet<Selector> {
+    let quirks_mode = roots
+        .first()
+        .map_or(QuirksMode::NoQuirks, |root| quirks_mode(root.as_node()));
+    let rule_set = RuleSet::new(selectors, quirks_mode);
+    let mut walker = TreeWalker::new(&rule_set, options, None);
+
+    for root in top_level_roots(roots) {
+        if walker.walk(root, Target::Tree).is_break() {
+            break;
+        }
+    }
+    walker.walk_shadow_trees();
 
-    let mut rules = HashSet::new();
+    walker.into_selectors()
+}
 
-    let mut stack: Vec<(NodeDataRef<ElementData>, usize)> =
-        vec![(root.clone(), bloom.traversal_depth())];
+/// Calculates matching styles for the declarative shadow tree of the given host, as used to
+/// reduce the stylesheets within the shadow tree. `:host` rules are matched against the host,
+/// `::slotted()` rules against its children, and other rules against the shadow tree.
+pub fn calculate_styles_for_shadow_tree(
+    host: &NodeDataRef<ElementData>,
+    selectors: impl IntoIterator<Item = Selector>,
+    options: &TraversalOptions,
+) -> HashSet<Selector> {
+    let rule_set = RuleSet::new(selectors, quirks_mode(host.as_node()));
+    let mut walker = TreeWalker::new(&rule_set, options, Some(host.clone()));
 
-    while let Some((el, depth)) = stack.pop() {
-        // If we have ascended, update the bloom filter
-        while bloom.traversal_depth() > depth {
-            bloom.pop();
+    walker.visit(host, Target::Host);
+    for child in host.as_node().children().elements() {
+        walker.visit(&child, Target::Slotted);
+    }
+    if let Some(shadow_root) = host.as_node().shadow_root() {
+        for child in shadow_root.children().elements() {
+            if walker.walk(&child, Target::Tree).is_break() {
+                break;
+            }
         }
+    }
+    walker.walk_shadow_trees();
+
+    walker.into_selectors()
+}
 
-        calculate_matching_rules(&el, &rule_set, &mut bloom, &mut rules);
+/// Walks DOM trees, collecting the rules matching their elements.
+struct TreeWalker<'a> {
+    rule_set: &'a RuleSet,
+    options: &'a TraversalOptions,
+    /// The shadow host of the tree being walked, if any.
+    host: Option<NodeDataRef<ElementData>>,
+    state: DocumentState,
+    bloom: StyleBloom,
+    rules: HashSet<&'a Rule>,
+    /// Shadow roots found while walking, which have yet to be walked.
+    shadow_roots: Vec<NodeRef>,
+}
 
-        // Update bloom filter
-        bloom.push(el.clone());
-        let depth = bloom.traversal_depth();
+impl<'a> TreeWalker<'a> {
+    fn new(
+        rule_set: &'a RuleSet,
+        options: &'a TraversalOptions,
+        host: Option<NodeDataRef<ElementData>>,
+    ) -> Self {
+        Self {
+            rule_set,
+            options,
+            host,
+            state: DocumentState {
+                target: options.target.as_deref().map(LocalName::from),
+                ..Default::default()
+            },
+            bloom: StyleBloom::new(),
+            rules: HashSet::new(),
+            shadow_roots: Vec::new(),
+        }
+    }
 
-        stack.extend(
-            el.as_node()
-                .children()
-                .filter_map(|c| c.into_element_ref())
-                .map(|c| (c, depth)),
+    /// Matches a single element against the rules with the given target.
+    fn visit(&mut self, element: &NodeDataRef<ElementData>, target: Target) {
+        if self.options.should_skip(element) {
+            return;
+        }
+        self.bloom.rebuild(element.clone());
+        calculate_matching_rules(
+            element,
+            self.rule_set,
+            &mut self.bloom,
+            &mut self.rules,
+            self.host.as_ref(),
+            &self.state,
+            target,
         );
     }
 
-    rules
-        .into_iter()
-        .map(|rule| rule.selector.clone())
-        .collect()
+    /// Matches the given element and its descendants against the rules with the given target,
+    /// in document order. Breaks once the fold is reached.
+    fn walk(&mut self, root: &NodeDataRef<ElementData>, target: Target) -> ControlFlow<()> {
+        self.bloom.rebuild(root.clone());
+
+        let mut stack: Vec<(NodeDataRef<ElementData>, usize)> =
+            vec![(root.clone(), self.bloom.traversal_depth())];
+
+        while let Some((el, depth)) = stack.pop() {
+            if self.options.fold.as_ref() == Some(&el) {
+                return ControlFlow::Break(());
+            }
+            if self.options.should_skip(&el) {
+                continue;
+            }
+
+            // If we have ascended, update the bloom filter
+            while self.bloom.traversal_depth() > depth {
+                self.bloom.pop();
+            }
+
+            calculate_matching_rules(
+                &el,
+                self.rule_set,
+                &mut self.bloom,
+                &mut self.rules,
+                self.host.as_ref(),
+                &self.state,
+                target,
+            );
+            if let Some(shadow_root) = el.as_node().shadow_root() {
+                self.shadow_roots.push(shadow_root);
+            }
+
+            // Update bloom filter
+            self.bloom.push(el.clone());
+            let depth = self.bloom.traversal_depth();
+
+            // Children are pushed in reverse, so that elements are visited in document order
+            stack.extend(
+                el.as_node()
+                    .children()
+                    .rev()
+                    .filter_map(|c| c.into_element_ref())
+                    .map(|c| (c, depth)),
+            );
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Walks the shadow trees found so far, including nested ones, matching their elements
+    /// against `::part()` rules.
+    fn walk_shadow_trees(&mut self) {
+        while let Some(shadow_root) = self.shadow_roots.pop() {
+            for child in shadow_root.children().elements() {
+                // the fold is never within a shadow tree
+                let _ = self.walk(&child, Target::Part);
+            }
+        }
+    }
+
+    fn into_selectors(self) -> HashSet<Selector> {
+        self.rules
+            .into_iter()
+            .map(|rule| rule.selector.clone())
+            .collect()
+    }
+}
+
+/// Returns the elements of the given trees which are analyzed with the given options, in document
+/// order: skipped elements, their descendants and the elements past the fold are left out.
+pub fn analyzed_elements(
+    roots: &[NodeDataRef<ElementData>],
+    options: &TraversalOptions,
+) -> Vec<NodeDataRef<ElementData>> {
+    let mut elements = Vec::new();
+    for root in top_level_roots(roots) {
+        let mut stack = vec![root.clone()];
+        while let Some(el) = stack.pop() {
+            if options.fold.as_ref() == Some(&el) {
+                return elements;
+            }
+            if options.should_skip(&el) {
+                continue;
+            }
+            stack.extend(
+                el.as_node()
+                    .children()
+                    .rev()
+                    .filter_map(|c| c.into_element_ref()),
+            );
+            elements.push(el);
+        }
+    }
+    elements
+}
+
+/// Returns the given roots, without those nested within another root.
+fn top_level_roots(
+    roots: &[NodeDataRef<ElementData>],
+) -> impl Iterator<Item = &NodeDataRef<ElementData>> {
+    roots.iter().filter(|root| {
+        !root
+            .as_node()
+            .ancestors()
+            .any(|ancestor| roots.iter().any(|other| other.as_node() == &ancestor))
+    })
+}
+
+/// Limits on the content analyzed before the fold, beyond which elements do not contribute to the
+/// critical CSS. An element with the `data-critters-fold` attribute always marks the fold.
+#[derive(Debug, Default, Clone)]
+pub struct FoldOptions {
+    /// The maximum number of elements analyzed, in document order.
+    pub max_elements: Option<usize>,
+    /// The maximum number of top-level sections (children of the roots) analyzed.
+    pub max_sections: Option<usize>,
+    /// The maximum estimated height of the content analyzed, in pixels. Heights are estimated
+    /// from element types and explicit `height` attributes.
+    pub max_height: Option<u32>,
+}
+
+/// Why the analysis of the document was cut off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub enum FoldReason {
+    /// An element with the `data-critters-fold` attribute was reached.
+    Marker,
+    /// The maximum number of elements was reached.
+    MaxElements,
+    /// The maximum number of top-level sections was reached.
+    MaxSections,
+    /// The maximum estimated height was reached.
+    MaxHeight,
+}
+
+/// The point at which the analysis of the document is cut off.
+#[derive(Debug, Clone)]
+pub struct FoldCutoff {
+    /// The first element past the fold.
+    pub element: NodeDataRef<ElementData>,
+    /// Why the analysis was cut off at this element.
+    pub reason: FoldReason,
+    /// The number of elements before the fold.
+    pub elements: usize,
+    /// The estimated height of the content before the fold, in pixels.
+    pub height: u32,
+}
+
+/// Finds the first element past the fold, visiting the roots in document order and skipping
+/// the elements excluded by the traversal options. Returns `None` if the fold is not reached.
+pub fn find_fold(
+    roots: &[NodeDataRef<ElementData>],
+    fold: &FoldOptions,
+    options: &TraversalOptions,
+) -> Option<FoldCutoff> {
+    let mut elements = 0;
+    let mut sections = 0;
+    let mut height = 0;
+
+    for root in top_level_roots(roots) {
+        let mut stack = vec![root.clone()];
+        while let Some(el) = stack.pop() {
+            if options.should_skip(&el) {
+                continue;
+            }
+
+            let is_section = el.as_node() != root.as_node()
+                && el.as_node().parent().as_ref() == Some(root.as_node());
+            let reason = if el.attributes.borrow().contains("data-critters-fold") {
+                Some(FoldReason::Marker)
+            } else if fold.max_elements.is_some_and(|max| elements >= max) {
+                Some(FoldReason::MaxElements)
+            } else if is_section && fold.max_sections.is_some_and(|max| sections >= max) {
+                Some(FoldReason::MaxSections)
+            } else if fold.max_height.is_some_and(|max| height >= max) {
+                Some(FoldReason::MaxHeight)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Some(FoldCutoff {
+                    element: el,
+                    reason,
+                    elements,
+                    height,
+                });
+            }
+
+            elements += 1;
+            if is_section {
+                sections += 1;
+            }
+            height += estimated_height(&el);
+
+            stack.extend(
+                el.as_node()
+                    .children()
+                    .rev()
+                    .filter_map(|c| c.into_element_ref()),
+            );
+        }
+    }
+
+    None
+}
+
+/// Crudely estimates the height an element adds to the page, in pixels, ignoring the height of
+/// its descendants.
+fn estimated_height(element: &NodeDataRef<ElementData>) -> u32 {
+    let name = &element.name.local;
+
+    let is_replaced = matches!(
+        *name,
+        local_name!("img")
+            | local_name!("video")
+            | local_name!("iframe")
+            | local_name!("canvas")
+            | local_name!("svg")
+            | local_name!("embed")
+            | local_name!("object")
+    );
+    if is_replaced {
+        let explicit = element
+            .attributes
+            .borrow()
+            .get("height")
+            .and_then(|height| {
+                let digits = height.trim().split(|c: char| !c.is_ascii_digit()).next()?;
+                digits.parse().ok()
+            });
+        // replaced elements default to a height of 150px
+        return explicit.unwrap_or(150);
+    }
+
+    match *name {
+        local_name!("h1") => 40,
+        local_name!("h2") => 32,
+        local_name!("h3") => 28,
+        local_name!("h4") | local_name!("h5") | local_name!("h6") => 24,
+        local_name!("p")
+        | local_name!("li")
+        | local_name!("dt")
+        | local_name!("dd")
+        | local_name!("tr")
+        | local_name!("pre")
+        | local_name!("blockquote")
+        | local_name!("figcaption")
+        | local_name!("summary")
+        | local_name!("label") => 24,
+        local_name!("input")
+        | local_name!("select")
+        | local_name!("button")
+        | local_name!("textarea") => 32,
+        local_name!("hr") => 16,
+        _ => 0,
+    }
 }
 
 #[cfg(test)]
@@ -399,7 +855,7 @@ mod tests {
         let document = parse_html().one(html);
         let root = document.select_first("body").unwrap();
 
-        let selectors = vec![".level2", ".level3", ".unused"]
+        let selectors = [".level2", ".level3", ".unused"]
             .iter()
             .flat_map(|s| Selectors::compile(s).unwrap().0)
             .collect_vec();
@@ -409,6 +865,26 @@ mod tests {
         assert_eq!(result.len(), 2);
     }
 
+    #[test]
+    fn test_calculate_styles_for_tree_quirks_mode() {
+        let body = r#"<div id="Main"><p class="Lead">Hello</p></div>"#;
+        let selectors = Selectors::compile("#main .lead, .LEAD, p.lead, .Lead")
+            .unwrap()
+            .0;
+
+        // without a doctype, ids and classes match case-insensitively
+        let document = parse_html().one(body);
+        let root = document.select_first("body").unwrap();
+        let result = calculate_styles_for_tree(&root, selectors.clone());
+        assert_eq!(result.len(), 4);
+
+        let document = parse_html().one(format!("<!DOCTYPE html>{body}"));
+        let root = document.select_first("body").unwrap();
+        let result = calculate_styles_for_tree(&root, selectors);
+        let result = result.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(result, [".Lead"]);
+    }
+
     #[test]
     fn test_calculate_styles_for_tree_single_element() {
         let html = r#"<p class="single">Only element</p>"#;
@@ -497,4 +973,144 @@ mod tests {
         // Should not match anything since .label is not inside .content
         assert_eq!(result.len(), 0);
     }
+
+    #[test]
+    fn test_calculate_styles_for_trees_multiple_roots() {
+        let html = r#"<header><p class="logo">Logo</p></header>
+            <main><section><p class="hero">Hero</p></section></main>
+            <footer><p class="copyright">Copyright</p></footer>"#;
+        let document = parse_html().one(html);
+        let roots = document
+            .select("header, main, main > section")
+            .unwrap()
+            .collect::<Vec<_>>();
+
+        let selectors = Selectors::compile(".logo, main .hero, .copyright, body > header").unwrap();
+        let result = calculate_styles_for_trees(&roots, selectors.0, &Default::default())
+            .iter()
+            .map(|selector| selector.to_string())
+            .sorted()
+            .collect::<Vec<_>>();
+
+        // Elements outside of the roots are not matched, but ancestors are considered
+        assert_eq!(result, vec![".logo", "body > header", "main .hero"]);
+    }
+
+    #[test]
+    fn test_find_fold() {
+        let html = r#"<header><h1 id="title">Title</h1></header>
+            <section id="hero"><img height="400"><p>Intro</p></section>
+            <section id="marker" data-critters-fold><p>Content</p></section>
+            <footer><p>Footer</p></footer>"#;
+        let document = parse_html().one(html);
+        let roots = vec![document.select_first("body").unwrap()];
+        let options = TraversalOptions::default();
+        let find = |fold: FoldOptions| {
+            find_fold(&roots, &fold, &options).map(|cutoff| {
+                let id = cutoff
+                    .element
+                    .attributes
+                    .borrow()
+                    .get("id")
+                    .map(String::from);
+                (id, cutoff.reason, cutoff.elements, cutoff.height)
+            })
+        };
+
+        assert_eq!(
+            find(Default::default()),
+            Some((Some("marker".into()), FoldReason::Marker, 6, 464))
+        );
+        assert_eq!(
+            find(FoldOptions {
+                max_elements: Some(2),
+                ..Default::default()
+            }),
+            Some((Some("title".into()), FoldReason::MaxElements, 2, 0))
+        );
+        assert_eq!(
+            find(FoldOptions {
+                max_sections: Some(1),
+                ..Default::default()
+            }),
+            Some((Some("hero".into()), FoldReason::MaxSections, 3, 40))
+        );
+        assert_eq!(
+            find(FoldOptions {
+                max_height: Some(100),
+                ..Default::default()
+            }),
+            Some((None, FoldReason::MaxHeight, 5, 440))
+        );
+    }
+
+    #[test]
+    fn test_calculate_styles_for_trees_fold() {
+        let html = r#"<header><h1>Title</h1></header>
+            <section><p class="intro">Intro</p></section>
+            <section><p class="content">Content</p></section>"#;
+        let document = parse_html().one(html);
+        let roots = vec![document.select_first("body").unwrap()];
+        let fold = FoldOptions {
+            max_sections: Some(2),
+            ..Default::default()
+        };
+        let options = TraversalOptions {
+            fold: find_fold(&roots, &fold, &Default::default()).map(|cutoff| cutoff.element),
+            ..Default::default()
+        };
+
+        let selectors = Selectors::compile("h1, .intro, .content").unwrap();
+        let result = calculate_styles_for_trees(&roots, selectors.0, &options)
+            .iter()
+            .map(|selector| selector.to_string())
+            .sorted()
+            .collect::<Vec<_>>();
+
+        assert_eq!(result, vec![".intro", "h1"]);
+    }
+
+    #[test]
+    fn test_calculate_styles_for_shadow_trees() {
+        let html = r#"<x-app>
+            <template shadowrootmode="open">
+                <x-button exportparts="label: button-label">
+                    <template shadowrootmode="closed">
+                        <span part="label"><slot name="icon"></slot></span>
+                    </template>
+                    <i slot="icon"></i>
+                    <b></b>
+                </x-button>
+            </template>
+        </x-app>"#;
+        let document = parse_html().one(html);
+        let roots = vec![document.select_first("body").unwrap()];
+
+        // parts are only exposed through exportparts
+        let selectors =
+            Selectors::compile("x-app::part(button-label), x-app::part(label), span").unwrap();
+        let result = calculate_styles_for_trees(&roots, selectors.0, &Default::default())
+            .iter()
+            .map(|selector| selector.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(result, vec!["x-app::part(button-label)"]);
+
+        // slotted elements are matched against the slot they are assigned to
+        let app = document.select_first("x-app").unwrap();
+        let shadow_root = app.as_node().shadow_root().unwrap();
+        let host = shadow_root.select_first("x-button").unwrap();
+        let selectors = Selectors::compile(
+            ":host, :host(x-app), slot[name=icon]::slotted(i), ::slotted(b), span, i",
+        )
+        .unwrap();
+        let result = calculate_styles_for_shadow_tree(&host, selectors.0, &Default::default())
+            .iter()
+            .map(|selector| selector.to_string())
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            result,
+            vec![":host", "slot[name=\"icon\"]::slotted(i)", "span"]
+        );
+    }
 }
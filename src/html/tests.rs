@@ -1,5 +1,5 @@
 use html5ever::tree_builder::QuirksMode;
-use html5ever::{local_name, namespace_url, ns, QualName};
+use html5ever::{local_name, namespace_url, ns, LocalName, QualName};
 use std::path::Path;
 
 use tempfile::TempDir;
@@ -7,6 +7,7 @@ use tempfile::TempDir;
 use crate::html::parser::{parse_fragment, parse_html};
 use crate::html::select::*;
 use crate::html::traits::*;
+use crate::html::{ElementData, NodeDataRef, NodeRef};
 
 #[test]
 fn text_nodes() {
@@ -262,3 +263,194 @@ fn specificity() {
     assert!(specificities[0] > specificities[2]);
     assert!(specificities[1] > specificities[2]);
 }
+
+/// Returns the ids of the elements matching the selector.
+fn select_ids(document: &NodeRef, selector: &str) -> Vec<String> {
+    document
+        .select(selector)
+        .unwrap()
+        .map(|el| {
+            el.attributes
+                .borrow()
+                .get("id")
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn select_form_states() {
+    let html = r#"
+<form>
+    <input id="a" type="checkbox" checked>
+    <input id="b" type="radio" name="r" checked>
+    <input id="c" type="radio" name="r" checked>
+    <input id="d" type="radio" name="other">
+    <select id="e"><option id="f" disabled>F<option id="g">G<option id="h">H</select>
+    <fieldset id="i" disabled>
+        <legend id="j"><input id="k"></legend>
+        <input id="l" required placeholder="Name">
+        <textarea id="m" readonly placeholder="Text">Value</textarea>
+    </fieldset>
+    <button id="n">Submit</button>
+    <button id="o">Submit too</button>
+    <progress id="p"></progress>
+    <div id="q" contenteditable><span id="r">Editable</span></div>
+</form>
+"#;
+
+    let document = parse_html().one(html);
+    assert_eq!(select_ids(&document, ":checked"), ["a", "c", "g"]);
+    assert_eq!(select_ids(&document, ":default"), ["a", "b", "c", "n"]);
+    assert_eq!(select_ids(&document, ":indeterminate"), ["d", "p"]);
+    assert_eq!(select_ids(&document, ":disabled"), ["f", "i", "l", "m"]);
+    assert_eq!(
+        select_ids(&document, "input:enabled"),
+        ["a", "b", "c", "d", "k"]
+    );
+    assert_eq!(select_ids(&document, ":required"), ["l"]);
+    assert_eq!(
+        select_ids(&document, "input:optional"),
+        ["a", "b", "c", "d", "k"]
+    );
+    assert_eq!(select_ids(&document, ":read-write"), ["k", "q", "r"]);
+    assert_eq!(select_ids(&document, ":placeholder-shown"), ["l"]);
+}
+
+#[test]
+fn select_language_and_direction() {
+    let html = r#"
+<html lang="en-US">
+<body>
+    <p id="a">Hello</p>
+    <p id="b" lang="de-CH">Grüezi</p>
+    <div id="c" dir="rtl"><p id="d" lang="he">שלום</p></div>
+    <p id="e" dir="auto">שלום</p>
+    <bdi id="f">Hello</bdi>
+</body>
+</html>
+"#;
+
+    let document = parse_html().one(html);
+    assert_eq!(select_ids(&document, "p:lang(en)"), ["a", "e"]);
+    assert_eq!(select_ids(&document, "p:lang(\"*-CH\", he)"), ["b", "d"]);
+    assert!(select_ids(&document, "p:lang(en-GB)").is_empty());
+    assert_eq!(select_ids(&document, ":dir(rtl)"), ["c", "d", "e"]);
+    assert_eq!(
+        select_ids(&document, "p:dir(ltr), bdi:dir(ltr)"),
+        ["a", "b", "f"]
+    );
+
+    let selectors = Selectors::compile(":lang(en, \"*-CH\"):dir(rtl)").unwrap();
+    assert_eq!(selectors.to_string(), ":lang(en, \\*-CH):dir(rtl)");
+}
+
+#[test]
+fn select_quirks_mode() {
+    let body = r#"<p id="Intro" class="Lead">Hello</p>"#;
+
+    let quirks = parse_html().one(body);
+    assert_eq!(
+        quirks.as_document().unwrap().quirks_mode(),
+        QuirksMode::Quirks
+    );
+    assert_eq!(select_ids(&quirks, "#intro"), ["Intro"]);
+    assert_eq!(select_ids(&quirks, ".lead"), ["Intro"]);
+
+    let standards = parse_html().one(format!("<!DOCTYPE html>{body}"));
+    assert!(select_ids(&standards, "#intro").is_empty());
+    assert!(select_ids(&standards, ".lead").is_empty());
+    assert_eq!(select_ids(&standards, ".Lead"), ["Intro"]);
+}
+
+#[test]
+fn select_case_insensitive_attribute_values() {
+    let html = r#"<!DOCTYPE html>
+<input id="a" type="text">
+<form id="b" method="POST" data-state="Open"></form>
+"#;
+
+    let document = parse_html().one(html);
+    assert_eq!(select_ids(&document, "[type=Text]"), ["a"]);
+    assert_eq!(select_ids(&document, "[method=post]"), ["b"]);
+    assert!(select_ids(&document, "[method=post s]").is_empty());
+    assert!(select_ids(&document, "[data-state=open]").is_empty());
+    assert_eq!(select_ids(&document, "[data-state=open i]"), ["b"]);
+}
+
+#[test]
+fn class_list_sync() {
+    let document = parse_html().one(r#"<!DOCTYPE html><div class=" a  b a"><p>Text</p></div>"#);
+    let div = document.select_first("div").unwrap();
+    let classes = |div: &NodeDataRef<ElementData>| {
+        let attributes = div.attributes.borrow();
+        let list = attributes.class_list().iter().map(|c| c.to_string());
+        (
+            attributes.get("class").map(String::from),
+            list.collect::<Vec<_>>(),
+        )
+    };
+    assert_eq!(
+        classes(&div),
+        (Some(" a  b a".into()), vec!["a".into(), "b".into()])
+    );
+
+    div.attributes.borrow_mut().insert("class", "c".to_string());
+    assert_eq!(classes(&div), (Some("c".into()), vec!["c".into()]));
+
+    div.attributes
+        .borrow_mut()
+        .get_mut("class")
+        .unwrap()
+        .push_str(" d");
+    assert_eq!(
+        classes(&div),
+        (Some("c d".into()), vec!["c".into(), "d".into()])
+    );
+
+    div.attributes.borrow_mut().remove("class");
+    assert_eq!(classes(&div), (None, vec![]));
+
+    div.attributes
+        .borrow_mut()
+        .entry("class")
+        .or_insert("e".to_string());
+    assert_eq!(classes(&div), (Some("e".into()), vec!["e".into()]));
+
+    {
+        let mut attributes = div.attributes.borrow_mut();
+        let mut class_list = attributes.class_list_mut();
+        assert!(class_list.add("f"));
+        assert!(!class_list.add("f"));
+        assert!(class_list.remove("e"));
+        assert!(class_list.toggle("g"));
+        assert!(!class_list.toggle("f"));
+    }
+    assert_eq!(classes(&div), (Some("g".into()), vec!["g".into()]));
+
+    // empty classes and classes with whitespace are rejected
+    {
+        let mut attributes = div.attributes.borrow_mut();
+        let mut class_list = attributes.class_list_mut();
+        assert!(!class_list.add(""));
+        assert!(!class_list.add("h i"));
+        assert!(!class_list.add("h\ti"));
+        assert!(!class_list.toggle(""));
+        assert!(!class_list.toggle("h i"));
+    }
+    assert_eq!(classes(&div), (Some("g".into()), vec!["g".into()]));
+    assert_eq!(
+        div.attributes.borrow().class_list().as_slice(),
+        [LocalName::from("g")]
+    );
+
+    // matching and bloom filter hashes follow the changes
+    assert!(document.select_first("div.g > p").is_ok());
+    assert!(document.select_first("div.e").is_err());
+    let root = document.select_first("body").unwrap();
+    let selectors = Selectors::compile(".g p, .e p").unwrap().0;
+    let result = crate::html::style_calculation::calculate_styles_for_tree(&root, selectors);
+    let result = result.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(result, [".g p"]);
+}
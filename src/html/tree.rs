@@ -2,6 +2,7 @@ use html5ever::tree_builder::QuirksMode;
 use html5ever::{expanded_name, local_name, namespace_url, ns, QualName};
 use std::cell::{Cell, RefCell};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::rc::{Rc, Weak};
 
@@ -113,6 +114,14 @@ impl PartialEq for NodeRef {
     }
 }
 
+impl Hash for NodeRef {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let ptr: *const Node = &*self.0;
+        ptr.hash(state)
+    }
+}
+
 /// A node inside a DOM-like tree.
 pub struct Node {
     parent: Cell<Option<Weak<Node>>>,
@@ -120,6 +129,8 @@ pub struct Node {
     next_sibling: Cell<Option<Rc<Node>>>,
     first_child: Cell<Option<Rc<Node>>>,
     last_child: Cell<Option<Weak<Node>>>,
+    /// The `<template>` element whose contents this document fragment holds, if any.
+    template: Weak<Node>,
     data: NodeData,
 }
 
@@ -196,14 +207,7 @@ impl NodeRef {
     /// Create a new node.
     #[inline]
     pub fn new(data: NodeData) -> NodeRef {
-        NodeRef(Rc::new(Node {
-            parent: Cell::new(None),
-            first_child: Cell::new(None),
-            last_child: Cell::new(None),
-            previous_sibling: Cell::new(None),
-            next_sibling: Cell::new(None),
-            data,
-        }))
+        NodeRef(Rc::new(Node::new(data)))
     }
 
     /// Create a new element node.
@@ -212,14 +216,20 @@ impl NodeRef {
     where
         I: IntoIterator<Item = (ExpandedName, Attribute)>,
     {
-        NodeRef::new(NodeData::Element(ElementData {
-            template_contents: if name.expanded() == expanded_name!(html "template") {
-                Some(NodeRef::new(NodeData::DocumentFragment))
-            } else {
-                None
-            },
-            name,
-            attributes: RefCell::new(Attributes::new(attributes)),
+        let is_template = name.expanded() == expanded_name!(html "template");
+        let attributes = RefCell::new(Attributes::new(attributes));
+
+        // template contents hold a weak reference back to their template
+        NodeRef(Rc::new_cyclic(|template| {
+            Node::new(NodeData::Element(ElementData {
+                template_contents: is_template.then(|| {
+                    let mut contents = Node::new(NodeData::DocumentFragment);
+                    contents.template = template.clone();
+                    NodeRef(Rc::new(contents))
+                }),
+                name,
+                attributes,
+            }))
         }))
     }
 
@@ -282,6 +292,19 @@ impl NodeRef {
 }
 
 impl Node {
+    #[inline]
+    fn new(data: NodeData) -> Node {
+        Node {
+            parent: Cell::new(None),
+            first_child: Cell::new(None),
+            last_child: Cell::new(None),
+            previous_sibling: Cell::new(None),
+            next_sibling: Cell::new(None),
+            template: Weak::new(),
+            data,
+        }
+    }
+
     /// Return a reference to this node’s node-type-specific data.
     #[inline]
     pub fn data(&self) -> &NodeData {
@@ -333,6 +356,13 @@ impl Node {
         }
     }
 
+    /// If this node is a document fragment holding the contents of a `<template>` element,
+    /// return that element.
+    #[inline]
+    pub fn template(&self) -> Option<NodeRef> {
+        self.template.upgrade().map(NodeRef)
+    }
+
     /// Return a reference to the parent node, unless this node is the root of the tree.
     #[inline]
     pub fn parent(&self) -> Option<NodeRef> {
@@ -398,6 +428,26 @@ impl Node {
 }
 
 impl NodeRef {
+    /// If this node is an element hosting a declarative shadow root, that is a
+    /// `<template shadowrootmode>` child, return the document fragment at the root of the
+    /// shadow tree.
+    pub fn shadow_root(&self) -> Option<NodeRef> {
+        self.children()
+            .elements()
+            .find(|child| is_declarative_shadow_root(child))
+            .and_then(|template| template.template_contents.clone())
+    }
+
+    /// If this node is the root of a declarative shadow tree, return its host element.
+    pub fn shadow_host(&self) -> Option<NodeRef> {
+        let template = self.template()?;
+        let element = template.as_element()?;
+        if !is_declarative_shadow_root(element) {
+            return None;
+        }
+        template.parent().filter(|host| host.as_element().is_some())
+    }
+
     /// Append a new child to this node, after existing children.
     ///
     /// The new child is detached from its previous position.
@@ -485,3 +535,15 @@ impl NodeRef {
         }
     }
 }
+
+/// Whether the given element is a `<template>` declaring a shadow root for its parent.
+fn is_declarative_shadow_root(element: &ElementData) -> bool {
+    element.name.expanded() == expanded_name!(html "template")
+        && element
+            .attributes
+            .borrow()
+            .get("shadowrootmode")
+            .is_some_and(|mode| {
+                mode.eq_ignore_ascii_case("open") || mode.eq_ignore_ascii_case("closed")
+            })
+}
@@ -28,18 +28,28 @@
 //! ```rust,no_run
 #![doc = include_str!("../examples/advanced_config.rs")]
 //! ```
+//!
+//! ## Processing Parsed Documents
+//!
+//! Documents already parsed with the [`html`] module can be processed in place with
+//! [`Critters::process_document`], without serializing and parsing them again.
+//!
+//! ```rust,no_run
+#![doc = include_str!("../examples/process_document.rs")]
+//! ```
 
 use html::traits::TendrilSink;
 use html::{NodeData, NodeRef};
+use indexmap::IndexSet;
 use itertools::Itertools;
 use lightningcss::printer::PrinterOptions;
-use lightningcss::properties::PropertyId;
+use lightningcss::properties::font::FontFamily;
 use lightningcss::rules::{font_face::FontFaceProperty, keyframes::KeyframesName, CssRule};
 use lightningcss::selector::SelectorList;
-use lightningcss::stylesheet::StyleSheet;
+use lightningcss::stylesheet::{StyleAttribute, StyleSheet};
 use lightningcss::traits::ToCss;
 use lightningcss::values::ident::CustomIdent;
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use path_clean::PathClean;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -48,34 +58,58 @@ use std::fs;
 use std::{default, path};
 use utils::{is_valid_media_query, regex, NodeRefExt, StyleRuleExt};
 
+use crate::css::{
+    AnimationNames, AtRuleUsage, ControlComments, CriticalImage, CustomPropertyUsage,
+};
+
 #[cfg(feature = "use-napi")]
 use napi_derive::napi;
 
-use crate::html::{style_calculation, Selectors};
+use crate::html::style_calculation::{self, FoldOptions, TraversalOptions};
+use crate::html::Selectors;
+use crate::safelist::Safelist;
 
-#[doc(hidden)]
+mod config;
+mod css;
+mod error;
 pub mod html;
+mod report;
+mod safelist;
 mod utils;
 
+pub use config::OptionsOverride;
+pub use error::{CrittersError, SourceLocation};
+pub use report::{
+    CrittersDirectoryStats, DiscoveryReport, ExcludedFiles, FoldReport, PageReport, PageStatus,
+    PageSummary, PreloadKind, PreloadReport, ProcessReport, StylesheetAction, StylesheetReport,
+    StylesheetSource, StylesheetUsage,
+};
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize, clap::ValueEnum)]
 #[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
 pub enum PreloadStrategy {
     /// Move stylesheet links to the end of the document and insert preload meta tags in their place.
     #[default]
+    #[serde(alias = "body-preload")]
     BodyPreload,
     /// Move all external stylesheet links to the end of the document.
+    #[serde(alias = "body")]
     Body,
     /// Load stylesheets asynchronously by adding media="not x" and removing once loaded. JS
+    #[serde(alias = "media")]
     Media,
     /// Convert stylesheet links to preloads that swap to rel="stylesheet" once loaded (details). JS
+    #[serde(alias = "swap")]
     Swap,
     /// Use <link rel="alternate stylesheet preload"> and swap to rel="stylesheet" once loaded (details). JS
+    #[serde(alias = "swap-high")]
     SwapHigh,
     // /// Inject an asynchronous CSS loader similar to LoadCSS and use it to load stylesheets. JS
     // Js,
     // /// Like "js", but the stylesheet is disabled until fully loaded.
     // JsLazy,
     /// Disables adding preload tags.
+    #[serde(alias = "none")]
     None,
 }
 
@@ -84,13 +118,43 @@ pub enum PreloadStrategy {
 pub enum KeyframesStrategy {
     /// Inline keyframes rules used by the critical CSS
     #[default]
+    #[serde(alias = "critical")]
     Critical,
     /// Inline all keyframes rules
+    #[serde(alias = "all")]
     All,
     /// Remove all keyframes rules
+    #[serde(alias = "none")]
+    None,
+}
+
+/// Controls which rules of a given at-rule type are inlined.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub enum AtRuleStrategy {
+    /// Inline rules used by the critical CSS
+    #[default]
+    #[serde(alias = "critical")]
+    Critical,
+    /// Inline all rules
+    #[serde(alias = "all")]
+    All,
+    /// Remove all rules
+    #[serde(alias = "none")]
     None,
 }
 
+impl AtRuleStrategy {
+    /// Returns whether a rule should be retained, evaluating `is_critical` only if required.
+    fn retain(&self, is_critical: impl FnOnce() -> bool) -> bool {
+        match self {
+            AtRuleStrategy::Critical => is_critical(),
+            AtRuleStrategy::All => true,
+            AtRuleStrategy::None => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Matcher {
     String(String),
@@ -113,10 +177,11 @@ impl Serialize for Matcher {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(match self {
-            Self::Regex(r) => r.as_str(),
-            Self::String(s) => s,
-        })
+        // Regular expressions keep their delimiters, so that they deserialize as such
+        match self {
+            Self::Regex(r) => serializer.serialize_str(&format!("/{}/", r.as_str())),
+            Self::String(s) => serializer.serialize_str(s),
+        }
     }
 }
 impl<'de> Deserialize<'de> for Matcher {
@@ -143,8 +208,9 @@ impl<'de> Deserialize<'de> for Matcher {
 #[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
 #[cfg_attr(feature = "typegen", ts(export))]
 pub struct CrittersOptions {
-    /// Base path location of the CSS files
-    #[clap(short, long)]
+    /// Base path location of the CSS files. On the command line, defaults to the current
+    /// directory, or to the directory of the processed file for the `process` command.
+    #[clap(short, long, default_value_t)]
     pub path: String,
     /// Public path of the CSS resources. This prefix is removed from the href.
     #[clap(long, default_value_t)]
@@ -173,6 +239,40 @@ pub struct CrittersOptions {
     /// Set it to false to skip processing inline styles.
     #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
     pub reduce_inline_styles: bool,
+    /// Selector for the elements analyzed for critical CSS. Every matching element is analyzed
+    /// and the results are combined. If no element matches, the whole body is analyzed.
+    #[clap(long, default_value = "[data-critters-container]")]
+    pub container_selector: String,
+    /// Selector for elements which are excluded from the analysis, along with their
+    /// descendants. Elements with the `data-critters-ignore` attribute are always excluded.
+    #[clap(long)]
+    pub ignore_selector: Option<String>,
+    /// Exclude elements which are hidden on load from the analysis: elements with the `hidden`
+    /// attribute or an inline `display: none` style, closed `<dialog>`s and `<template>`s.
+    #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
+    pub skip_hidden: bool,
+    /// The fragment of the URL the document is served at, such as `#pricing`. The element it
+    /// targets matches `:target`.
+    #[clap(long)]
+    pub url_fragment: Option<String>,
+    /// Stop analyzing the document after this many elements, in document order. Elements past
+    /// the fold do not contribute to the critical CSS. An element with the `data-critters-fold`
+    /// attribute always marks the fold.
+    #[clap(long)]
+    pub fold_max_elements: Option<usize>,
+    /// Stop analyzing the document after this many top-level sections of the containers.
+    #[clap(long)]
+    pub fold_max_sections: Option<usize>,
+    /// Stop analyzing the document once the content reaches this height, in pixels. Heights are
+    /// crudely estimated from element types and explicit `height` attributes.
+    #[clap(long)]
+    pub fold_max_height: Option<u32>,
+    /// Treat classes and attribute values found in inline scripts, JSON islands and framework
+    /// attributes (such as `x-bind:class` or `:class`) as present in the document, so that rules
+    /// for elements styled at hydration are kept. Only the analyzed content is harvested: scripts
+    /// and markup outside of the containers, in ignored subtrees or past the fold are not.
+    #[clap(long)]
+    pub harvest_classes: bool,
     /// Which preload strategy to use.
     #[clap(long, default_value = "body-preload")]
     pub preload: PreloadStrategy,
@@ -185,12 +285,53 @@ pub struct CrittersOptions {
     /// Preloads critical fonts
     #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
     pub preload_fonts: bool,
+    /// Preloads images referenced by the critical CSS, such as background images. Relative URLs
+    /// in external stylesheets are resolved against the `href` of the stylesheet.
+    #[clap(long)]
+    pub preload_images: bool,
+    /// Adds `fetchpriority="high"` to image preloads.
+    #[clap(long)]
+    pub preload_images_high_priority: bool,
     /// Controls which keyframes rules are inlined.
     #[clap(long, default_value = "critical")]
     pub keyframes: KeyframesStrategy,
+    /// Remove custom property declarations (and their `@property` registrations) which are not
+    /// referenced by the critical CSS or by inline styles.
+    #[clap(long)]
+    pub prune_custom_properties: bool,
+    /// Controls which `@property` rules are inlined. Registrations are critical if the custom
+    /// property is declared or referenced by the critical CSS of the page.
+    #[clap(long, default_value = "critical")]
+    pub property_rules: AtRuleStrategy,
+    /// Controls which `@counter-style` rules are inlined. Counter styles are critical if they
+    /// are referenced by `list-style` or a `counter()` function in the critical CSS.
+    #[clap(long, default_value = "critical")]
+    pub counter_style_rules: AtRuleStrategy,
+    /// Controls which `@font-feature-values` rules are inlined. These are critical if one of
+    /// their font families is used by the critical CSS.
+    #[clap(long, default_value = "critical")]
+    pub font_feature_values_rules: AtRuleStrategy,
+    /// Controls which `@font-palette-values` rules are inlined. Palettes are critical if they
+    /// are referenced by `font-palette` in the critical CSS.
+    #[clap(long, default_value = "critical")]
+    pub font_palette_values_rules: AtRuleStrategy,
+    /// Controls which `@page` rules are inlined. Page rules only apply to paged media, so they
+    /// are never critical.
+    #[clap(long, default_value = "critical")]
+    pub page_rules: AtRuleStrategy,
+    /// Controls which `@view-transition` rules are inlined. These apply to the document as a
+    /// whole, so they are always critical.
+    #[clap(long, default_value = "critical")]
+    pub view_transition_rules: AtRuleStrategy,
     /// Compress resulting critical CSS
     #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
     pub compress: bool,
+    /// Fail processing on any problem which would otherwise only be reported as a warning, such
+    /// as stylesheets which cannot be loaded or parsed, or preloads which cannot be injected.
+    /// When processing a directory, pages which fail to process also fail the whole run, while
+    /// skipped pages, such as those which are not valid UTF-8, do not.
+    #[clap(long)]
+    pub strict: bool,
     /// Provide a list of selectors that should be included in the critical CSS.
     #[clap(skip)]
     #[cfg_attr(feature = "typegen", ts(as = "Vec<String>"))]
@@ -203,18 +344,6 @@ pub struct CrittersOptions {
     pub exclude_external: Vec<Matcher>,
 }
 
-/// Statistics resulting from `Critters::process_dir`.
-#[derive(Debug)]
-#[cfg(any(feature = "cli", feature = "use-napi"))]
-#[cfg_attr(feature = "use-napi", napi)]
-pub struct CrittersDirectoryStats {
-    /// Total duration of processing, in seconds
-    pub time_sec: f64,
-    /// Number of pages processed
-    pub pages: u32,
-    // TODO: add more stats
-}
-
 impl default::Default for CrittersOptions {
     fn default() -> Self {
         Self {
@@ -227,21 +356,126 @@ impl default::Default for CrittersOptions {
             merge_stylesheets: true,
             additional_stylesheets: Default::default(),
             reduce_inline_styles: true,
+            container_selector: "[data-critters-container]".to_string(),
+            ignore_selector: None,
+            skip_hidden: true,
+            url_fragment: None,
+            fold_max_elements: None,
+            fold_max_sections: None,
+            fold_max_height: None,
+            harvest_classes: false,
             preload: Default::default(),
             noscript_fallback: true,
             inline_fonts: false,
             preload_fonts: true,
+            preload_images: false,
+            preload_images_high_priority: false,
             keyframes: Default::default(),
+            prune_custom_properties: false,
+            property_rules: Default::default(),
+            counter_style_rules: Default::default(),
+            font_feature_values_rules: Default::default(),
+            font_palette_values_rules: Default::default(),
+            page_rules: Default::default(),
+            view_transition_rules: Default::default(),
             compress: true,
+            strict: false,
             allow_rules: Default::default(),
             exclude_external: Default::default(),
         }
     }
 }
 
+/// Options of [`Critters::process_dir`], which only apply when processing the HTML files of the
+/// directory given by the `path` option.
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+#[serde(default, rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+#[cfg_attr(feature = "typegen", ts(export))]
+pub struct DirectoryOptions {
+    /// Directory to write processed HTML files to, mirroring their location within `path`,
+    /// which is left untouched. Pages which are not processed are copied as is. Stylesheets are
+    /// still read from `path`, and are not copied, as they are never modified. When it is within
+    /// `path`, the files it contains are not processed. By default, HTML files are processed in
+    /// place.
+    #[clap(long = "out-dir")]
+    pub output_dir: Option<String>,
+    /// Only process the HTML files matching one of these globs, relative to `path`. By default,
+    /// all HTML files are processed.
+    #[clap(long)]
+    pub include: Vec<String>,
+    /// Skip the HTML files matching any of these globs, relative to `path`, such as
+    /// `**/node_modules/**`.
+    #[clap(long)]
+    pub exclude: Vec<String>,
+    /// The extensions of the HTML files to process.
+    #[clap(long, default_values_t = [String::from("html")])]
+    pub extensions: Vec<String>,
+    /// Follow symbolic links.
+    #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
+    pub follow_links: bool,
+    /// Skip the files ignored by `.gitignore` files, including those in the parent directories
+    /// of `path`.
+    #[clap(long)]
+    pub respect_gitignore: bool,
+    /// Process the pages without writing any file, to preview the results.
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Options applied to the pages matching a glob, such as
+    /// `{ "match": "blog/**", "options": { "inlineFonts": true } }`. Globs are matched against
+    /// the path of each page relative to `path`, and later overrides take precedence.
+    #[clap(skip)]
+    pub overrides: Vec<OptionsOverride>,
+    /// Options which overrides do not change, by their name in configuration files, such as
+    /// `preload`. The command line sets it to the options it is given, so that they take
+    /// precedence over the whole configuration file.
+    #[serde(skip)]
+    #[clap(skip)]
+    pub locked_options: Vec<String>,
+}
+
+impl default::Default for DirectoryOptions {
+    fn default() -> Self {
+        Self {
+            output_dir: None,
+            include: Default::default(),
+            exclude: Default::default(),
+            extensions: vec!["html".to_string()],
+            follow_links: true,
+            respect_gitignore: false,
+            dry_run: false,
+            overrides: Default::default(),
+            locked_options: Default::default(),
+        }
+    }
+}
+
+/// The parts of the document analyzed for critical CSS, shared by all stylesheets.
+struct CriticalScope {
+    containers: Vec<html::NodeDataRef<html::ElementData>>,
+    traversal: TraversalOptions,
+    safelist: Option<Safelist>,
+    /// The host of the declarative shadow tree being processed, if any.
+    shadow_host: Option<html::NodeDataRef<html::ElementData>>,
+}
+
 struct StylesheetInfo {
     pub el: NodeRef,
     pub reduce: bool,
+    pub source: StylesheetSource,
+    pub href: Option<String>,
+}
+
+impl StylesheetInfo {
+    /// Starts the report of this stylesheet, before it is processed.
+    fn report(&self) -> StylesheetReport {
+        StylesheetReport::new(
+            self.source,
+            self.href.clone(),
+            self.reduce,
+            self.el.text_contents().len(),
+        )
+    }
 }
 
 #[derive(Clone)]
@@ -250,17 +484,20 @@ pub struct Critters {
     options: CrittersOptions,
 }
 
+/// Called with the path of a modified page of a directory, its original and its processed HTML.
+#[cfg(feature = "directory")]
+pub type PageCallback<'a> = dyn Fn(&str, &str, &str) + Sync + 'a;
+
 #[cfg(feature = "use-napi")]
 #[napi]
 impl Critters {
     #[napi(constructor)]
-    pub fn new(options: Option<serde_json::Value>) -> anyhow::Result<Self> {
-        use anyhow::anyhow;
+    pub fn new(options: Option<serde_json::Value>) -> Result<Self, CrittersError> {
         // try to initialize the logger, ignore error if it has already been initialized
         env_logger::try_init().ok();
         let options: CrittersOptions = match options {
             Some(options) => serde_json::from_value(options)
-                .map_err(|e| anyhow!("Failed to parse options: {}", e))?,
+                .map_err(|e| CrittersError::InvalidOptions(e.to_string()))?,
             None => Default::default(),
         };
         Ok(Critters { options })
@@ -268,14 +505,35 @@ impl Critters {
 
     /// Process the given HTML, extracting and inlining critical CSS
     #[napi]
-    pub fn process(&self, html: String) -> anyhow::Result<String> {
+    pub fn process(&self, html: String) -> Result<String, CrittersError> {
         self.process_impl(&html)
     }
 
+    /// Process the given HTML, extracting and inlining critical CSS. Returns the processed HTML
+    /// along with a report of the processing, as `{ html, report }`.
+    #[napi(ts_return_type = "{ html: string; report: ProcessReport }")]
+    pub fn process_with_report(&self, html: String) -> Result<serde_json::Value, CrittersError> {
+        let (html, report) = self.process_with_report_impl(&html)?;
+        Ok(serde_json::json!({ "html": html, "report": report }))
+    }
+
     /// Process all HTML files in the configured directory
-    #[napi]
-    pub fn process_dir(&self) -> anyhow::Result<CrittersDirectoryStats> {
-        self.process_dir_impl(None)
+    #[napi(
+        ts_args_type = "options?: Partial<DirectoryOptions>",
+        ts_return_type = "CrittersDirectoryStats"
+    )]
+    pub fn process_dir(
+        &self,
+        options: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, CrittersError> {
+        let options: DirectoryOptions = match options {
+            Some(options) => serde_json::from_value(options)
+                .map_err(|e| CrittersError::InvalidOptions(e.to_string()))?,
+            None => Default::default(),
+        };
+        Ok(serde_json::json!(
+            self.process_dir_impl(&options, None, None)?
+        ))
     }
 }
 
@@ -287,16 +545,61 @@ impl Critters {
 
     /// Process the given HTML, extracting and inlining critical CSS
     #[cfg(not(feature = "use-napi"))]
-    pub fn process(&self, html: &str) -> anyhow::Result<String> {
+    pub fn process(&self, html: &str) -> Result<String, CrittersError> {
         self.process_impl(html)
     }
 
+    /// Process the given HTML, extracting and inlining critical CSS. Returns the processed HTML
+    /// along with a report of the processing.
+    #[cfg(not(feature = "use-napi"))]
+    pub fn process_with_report(
+        &self,
+        html: &str,
+    ) -> Result<(String, ProcessReport), CrittersError> {
+        self.process_with_report_impl(html)
+    }
+
     /// Process the given HTML, extracting and inlining critical CSS
-    fn process_impl(&self, html: &str) -> anyhow::Result<String> {
+    fn process_impl(&self, html: &str) -> Result<String, CrittersError> {
+        self.process_with_report_impl(html).map(|(html, _)| html)
+    }
+
+    fn process_with_report_impl(
+        &self,
+        html: &str,
+    ) -> Result<(String, ProcessReport), CrittersError> {
         // Parse the HTML into a DOM
         let parser = html::parse_html();
         let dom = parser.one(html);
 
+        let report = self.process_document(&dom)?;
+
+        // Serialize back to an HTML string
+        Ok((dom.to_string(), report))
+    }
+
+    /// Process an already parsed document in place, extracting and inlining critical CSS.
+    ///
+    /// This avoids serializing and re-parsing documents which are already held as a tree, such as
+    /// by a static site generator transforming pages with the [`html`] module. In strict mode, a
+    /// document which fails to process may be left partially processed.
+    pub fn process_document(&self, dom: &NodeRef) -> Result<ProcessReport, CrittersError> {
+        let report = self.process_document_impl(dom)?;
+        self.check_strict(report)
+    }
+
+    /// Fails with the warnings of the report in strict mode, if there are any.
+    fn check_strict(&self, report: ProcessReport) -> Result<ProcessReport, CrittersError> {
+        if self.options.strict && !report.warnings.is_empty() {
+            return Err(CrittersError::Strict(report.warnings));
+        }
+        Ok(report)
+    }
+
+    /// Process an already parsed document in place, regardless of strict mode.
+    fn process_document_impl(&self, dom: &NodeRef) -> Result<ProcessReport, CrittersError> {
+        let dom = dom.clone();
+        let mut report = ProcessReport::default();
         let mut styles = Vec::new();
 
         // Inline styles
@@ -306,65 +609,198 @@ impl Critters {
 
         // External stylesheets
         if self.options.external {
-            styles.append(&mut self.get_external_stylesheets(&dom));
+            styles.append(&mut self.get_external_stylesheets(&dom, &mut report));
         }
 
         // Additional stylesheets
         if !self.options.additional_stylesheets.is_empty() {
-            styles.append(&mut self.get_additional_stylesheets(&dom)?);
+            styles.append(&mut self.get_additional_stylesheets(&dom, &mut report)?);
         }
 
-        // Select the critters container once for all stylesheets
-        let critters_container = dom
-            .select_first("[data-critters-container]")
-            .unwrap_or_else(|_| dom.select_first("body").unwrap());
+        // Select the critters containers once for all stylesheets
+        let critters_containers = self.select_containers(&dom, &mut report)?;
+        let traversal = self.traversal_options(&critters_containers, &mut report);
+        let safelist = self.options.harvest_classes.then(|| {
+            Safelist::harvest(&style_calculation::analyzed_elements(
+                &critters_containers,
+                &traversal,
+            ))
+        });
+        let mut scope = CriticalScope {
+            traversal,
+            containers: critters_containers,
+            safelist,
+            shadow_host: None,
+        };
 
         // Extract and inline critical CSS
         debug!("Inlining {} stylesheets.", styles.len());
-        for style in styles.iter().filter(|s| s.reduce) {
-            let res = self.process_style_el(&style.el, dom.clone(), &critters_container);
-            // Log processing errors and skip associated stylesheets
-            if let Err(err) = res {
-                error!(
-                    "Error encountered when processing stylesheet, skipping. {}",
-                    err
+        let mut stylesheets = Vec::new();
+        for style in &styles {
+            let mut stylesheet = style.report();
+            if style.reduce {
+                let res = self.process_style_el(
+                    &style.el,
+                    dom.clone(),
+                    &scope,
+                    &mut report,
+                    &mut stylesheet,
                 );
+                // Log processing errors and skip associated stylesheets
+                if let Err(err) = res {
+                    error!(
+                        "Error encountered when processing stylesheet, skipping. {}",
+                        err
+                    );
+                    report.warnings.push(format!("Skipped stylesheet. {err}"));
+                    stylesheet.action = StylesheetAction::Skipped;
+                }
+            }
+            stylesheets.push((style.el.clone(), stylesheet));
+        }
+
+        // Reduce the stylesheets of declarative shadow roots against their own shadow tree
+        if self.options.reduce_inline_styles {
+            for (host, shadow_root) in dom.shadow_roots() {
+                scope.shadow_host = Some(host);
+                for style in shadow_root.select("style").unwrap() {
+                    let mut stylesheet = StylesheetReport::new(
+                        StylesheetSource::ShadowRoot,
+                        None,
+                        true,
+                        style.text_contents().len(),
+                    );
+                    let res = self.process_style_el(
+                        style.as_node(),
+                        dom.clone(),
+                        &scope,
+                        &mut report,
+                        &mut stylesheet,
+                    );
+                    if let Err(err) = res {
+                        error!(
+                            "Error encountered when processing shadow root stylesheet, skipping. {}",
+                            err
+                        );
+                        report
+                            .warnings
+                            .push(format!("Skipped shadow root stylesheet. {err}"));
+                        stylesheet.action = StylesheetAction::Skipped;
+                    }
+                    stylesheets.push((style.as_node().clone(), stylesheet));
+                }
             }
         }
 
+        // Remove custom properties and registrations which are not referenced by the critical CSS
+        if self.options.prune_custom_properties
+            || matches!(self.options.property_rules, AtRuleStrategy::Critical)
+        {
+            let reduced = stylesheets
+                .iter()
+                .filter(|(_, stylesheet)| stylesheet.action == StylesheetAction::Reduced)
+                .map(|(el, _)| el.clone())
+                .collect::<Vec<_>>();
+            self.prune_custom_properties(&reduced, &dom, &mut report);
+        }
+
+        for (el, mut stylesheet) in stylesheets {
+            stylesheet.bytes_after = el.text_contents().len();
+            report.stylesheets.push(stylesheet);
+        }
+
         // Merge stylesheets
         if self.options.merge_stylesheets {
-            self.merge_stylesheets(styles)
+            self.merge_stylesheets(styles, &dom)
         }
 
-        // Serialize back to an HTML string
-        let mut result = Vec::new();
-        dom.serialize(&mut result)?;
-        Ok(String::from_utf8(result)?)
+        Ok(report)
     }
 
     /// Process all HTML files in the configured directory
     #[cfg(feature = "cli")]
     pub fn process_dir(
         &self,
+        options: &DirectoryOptions,
+        multi_progress: Option<&indicatif::MultiProgress>,
+    ) -> Result<CrittersDirectoryStats, CrittersError> {
+        self.process_dir_impl(options, multi_progress, None)
+    }
+
+    /// Process all HTML files in the configured directory, calling `on_page` with the path of each
+    /// modified page relative to `path`, its HTML before processing and its processed HTML. The
+    /// HTML before processing is serialized from the parsed document, so that both only differ
+    /// by the changes made by Critters. `on_page` is called from several threads at once.
+    ///
+    /// Unlike [`Critters::process_dir`], pages failing in strict mode do not fail the run, so that
+    /// the statistics are available either way. Use [`Critters::check_strict_dir`] to fail with
+    /// their problems afterwards.
+    #[cfg(feature = "cli")]
+    pub fn process_dir_with(
+        &self,
+        options: &DirectoryOptions,
         multi_progress: Option<&indicatif::MultiProgress>,
-    ) -> anyhow::Result<CrittersDirectoryStats> {
-        self.process_dir_impl(multi_progress)
+        on_page: Option<&PageCallback>,
+    ) -> Result<CrittersDirectoryStats, CrittersError> {
+        self.process_dir_pages(options, multi_progress, on_page)
+    }
+
+    /// Fails with the problems of the statistics in strict mode, if there are any.
+    #[cfg(feature = "directory")]
+    pub fn check_strict_dir(&self, stats: &CrittersDirectoryStats) -> Result<(), CrittersError> {
+        let problems = stats.problems();
+        if self.options.strict && !problems.is_empty() {
+            return Err(CrittersError::Strict(problems));
+        }
+        Ok(())
     }
 
     /// Process all HTML files in the configured directory
     #[cfg(feature = "directory")]
     fn process_dir_impl(
         &self,
+        options: &DirectoryOptions,
+        multi_progress: Option<&indicatif::MultiProgress>,
+        on_page: Option<&PageCallback>,
+    ) -> Result<CrittersDirectoryStats, CrittersError> {
+        let stats = self.process_dir_pages(options, multi_progress, on_page)?;
+        self.check_strict_dir(&stats)?;
+        Ok(stats)
+    }
+
+    /// Process all HTML files in the configured directory, regardless of strict mode.
+    #[cfg(feature = "directory")]
+    fn process_dir_pages(
+        &self,
+        options: &DirectoryOptions,
         multi_progress: Option<&indicatif::MultiProgress>,
-    ) -> anyhow::Result<CrittersDirectoryStats> {
+        on_page: Option<&PageCallback>,
+    ) -> Result<CrittersDirectoryStats, CrittersError> {
         use indicatif::{ParallelProgressIterator, ProgressBar};
         use log::info;
         use rayon::prelude::*;
         use std::time::Instant;
         use utils::ProgressBarExt;
 
-        let files = utils::locate_html_files(&self.options.path)?;
+        let overrides =
+            config::Overrides::new(&self.options, &options.overrides, &options.locked_options)?;
+        let (mut files, discovery) = utils::locate_html_files(&self.options.path, options)?;
+        // Skip the output of previous runs, if it is within the input directory. An output
+        // directory equal to the input directory, or containing it, processes pages in place.
+        if let Some(output_dir) = &options.output_dir {
+            let absolute = |dir: &str| {
+                path::absolute(dir)
+                    .map(|dir| dir.clean())
+                    .map_err(|e| CrittersError::io(dir, e))
+            };
+            let output_dir = absolute(output_dir)?;
+            let input_dir = absolute(&self.options.path)?;
+            if output_dir != input_dir && output_dir.starts_with(&input_dir) {
+                files.retain(|file| {
+                    !path::absolute(file).is_ok_and(|file| file.clean().starts_with(&output_dir))
+                });
+            }
+        }
 
         let start = Instant::now();
         let progress_bar = ProgressBar::new(files.len() as u64)
@@ -376,42 +812,207 @@ impl Critters {
             progress_bar
         };
 
-        files
+        let page_reports = files
             .par_iter()
             .progress_with(progress_bar.clone())
-            .for_each(|path| {
+            .map(|path| {
                 let start = Instant::now();
+                let relative_path = path
+                    .strip_prefix(&self.options.path)
+                    .unwrap()
+                    .display()
+                    .to_string();
+                let time_ms = || start.elapsed().as_secs_f64() * 1000.0;
+                let output = match &options.output_dir {
+                    Some(output_dir) => path::Path::new(output_dir).join(&relative_path),
+                    None => path.clone(),
+                };
+                let write = |contents: &[u8]| {
+                    if options.dry_run {
+                        Ok(())
+                    } else {
+                        let permissions = fs::metadata(path).ok().map(|m| m.permissions());
+                        utils::write_atomic(&output, contents, permissions)
+                    }
+                };
+                // Pages which are not processed are copied as is to the output directory
+                let copy_untouched = |contents: &[u8]| {
+                    if &output == path {
+                        Ok(())
+                    } else {
+                        write(contents)
+                    }
+                };
+
+                let html = match fs::read(path).map(String::from_utf8) {
+                    Ok(Ok(html)) => html,
+                    Ok(Err(e)) => {
+                        warn!("Skipping {}, which is not valid UTF-8.", path.display());
+                        if let Err(e) = copy_untouched(e.as_bytes()) {
+                            error!("Failed to process file {} with error {e}", path.display());
+                            return PageReport::new(relative_path, time_ms(), Err(e));
+                        }
+                        return PageReport::skipped(
+                            relative_path,
+                            time_ms(),
+                            "The file is not valid UTF-8.".to_string(),
+                        );
+                    }
+                    Err(e) => {
+                        let e = CrittersError::io(path, e);
+                        error!("Failed to process file {} with error {e}", path.display());
+                        return PageReport::new(relative_path, time_ms(), Err(e));
+                    }
+                };
 
-                let html =
-                    fs::read_to_string(path.clone()).expect("Failed to load HTML file from disk.");
-                let processed = match self.process_impl(&html) {
-                    Ok(s) => s,
+                // Pages matching an override are processed with their own options
+                let critters = match overrides.options_for(path::Path::new(&relative_path)) {
+                    Ok(options) => options.map(|options| Critters { options }),
                     Err(e) => {
                         error!("Failed to process file {} with error {e}", path.display());
-                        return;
+                        copy_untouched(html.as_bytes()).ok();
+                        return PageReport::new(relative_path, time_ms(), Err(e));
+                    }
+                };
+                let critters = critters.as_ref().unwrap_or(self);
+
+                let dom = html::parse_html().one(html.as_str());
+                let original = on_page.map(|_| dom.to_string());
+                let result = critters.process_document_impl(&dom);
+                let strict_failure = critters.options.strict
+                    && result
+                        .as_ref()
+                        .is_ok_and(|report| !report.warnings.is_empty());
+                let written = match &result {
+                    // Pages without stylesheets are left untouched
+                    Ok(report) if !strict_failure && !report.stylesheets.is_empty() => {
+                        let processed = dom.to_string();
+                        if let (Some(on_page), Some(original)) = (on_page, &original) {
+                            on_page(&relative_path, original, &processed);
+                        }
+                        write(processed.as_bytes())
                     }
+                    _ => copy_untouched(html.as_bytes()),
                 };
-                fs::write(path.clone(), processed).expect("Failed to write HTML file to disk.");
+                let result = result.and_then(|report| written.map(|()| report));
+                if let Err(e) = &result {
+                    error!("Failed to process file {} with error {e}", path.display());
+                }
 
-                let duration = start.elapsed();
+                info!("Processed {} in {:.0} ms", relative_path, time_ms());
 
-                info!(
-                    "Processed {} in {} ms",
-                    path.strip_prefix(&self.options.path).unwrap().display(),
-                    duration.as_millis()
-                );
-            });
+                let mut page = PageReport::new(relative_path, time_ms(), result);
+                if strict_failure {
+                    page.fail_strict();
+                }
+                page
+            })
+            .collect();
 
         progress_bar.finish_and_clear();
         if let Some(multi) = multi_progress {
             multi.remove(&progress_bar);
         }
         Ok(CrittersDirectoryStats {
-            pages: files.len() as u32,
-            time_sec: start.elapsed().as_secs_f64(),
+            discovery,
+            ..CrittersDirectoryStats::from_pages(page_reports, start.elapsed().as_secs_f64())
         })
     }
 
+    /// Selects the elements whose subtrees are analyzed for critical CSS, falling back to the
+    /// body if no element matches the configured container selector.
+    fn select_containers(
+        &self,
+        dom: &NodeRef,
+        report: &mut ProcessReport,
+    ) -> Result<Vec<html::NodeDataRef<html::ElementData>>, CrittersError> {
+        let containers = match dom.select(&self.options.container_selector) {
+            Ok(containers) => containers.collect::<Vec<_>>(),
+            Err(_) => {
+                let warning = format!(
+                    "Invalid container selector \"{}\", falling back to the document body.",
+                    self.options.container_selector
+                );
+                warn!("{warning}");
+                report.warnings.push(warning);
+                Vec::new()
+            }
+        };
+
+        if containers.is_empty() {
+            let body = dom
+                .select_first("body")
+                .map_err(|_| CrittersError::MissingElement("body"))?;
+            Ok(vec![body])
+        } else {
+            Ok(containers)
+        }
+    }
+
+    /// Builds the options controlling which elements of the containers are analyzed, including
+    /// where the analysis is cut off by the fold.
+    fn traversal_options(
+        &self,
+        containers: &[html::NodeDataRef<html::ElementData>],
+        report: &mut ProcessReport,
+    ) -> TraversalOptions {
+        let ignore = self.options.ignore_selector.as_ref().and_then(|selector| {
+            match Selectors::compile(selector) {
+                Ok(selectors) => Some(selectors),
+                Err(_) => {
+                    let warning = format!("Invalid ignore selector \"{selector}\", ignoring.");
+                    warn!("{warning}");
+                    report.warnings.push(warning);
+                    None
+                }
+            }
+        });
+
+        let mut traversal = TraversalOptions {
+            ignore,
+            skip_hidden: self.options.skip_hidden,
+            fold: None,
+            target: self
+                .options
+                .url_fragment
+                .as_ref()
+                .map(|fragment| fragment.trim_start_matches('#').to_string())
+                .filter(|fragment| !fragment.is_empty()),
+        };
+
+        let fold = FoldOptions {
+            max_elements: self.options.fold_max_elements,
+            max_sections: self.options.fold_max_sections,
+            max_height: self.options.fold_max_height,
+        };
+        if let Some(cutoff) = style_calculation::find_fold(containers, &fold, &traversal) {
+            let element = format!(
+                "<{}{}>",
+                cutoff.element.name.local,
+                cutoff
+                    .element
+                    .attributes
+                    .borrow()
+                    .get("id")
+                    .map(|id| format!(" id=\"{id}\""))
+                    .unwrap_or_default()
+            );
+            info!(
+                "Reached the fold at {element} ({:?}) after {} elements, estimated {}px high.",
+                cutoff.reason, cutoff.elements, cutoff.height
+            );
+            report.fold = Some(FoldReport {
+                element,
+                reason: cutoff.reason,
+                elements: cutoff.elements,
+                estimated_height: cutoff.height,
+            });
+            traversal.fold = Some(cutoff.element);
+        }
+
+        traversal
+    }
+
     /// Gets inline styles from the document.
     fn get_inline_stylesheets(&self, dom: &NodeRef) -> Vec<StylesheetInfo> {
         dom.select("style")
@@ -419,12 +1020,18 @@ impl Critters {
             .map(|n| StylesheetInfo {
                 el: n.as_node().clone(),
                 reduce: true,
+                source: StylesheetSource::Inline,
+                href: None,
             })
             .collect()
     }
 
     /// Resolve links to external stylesheets, inlining them and replacing the link with a preload strategy.
-    fn get_external_stylesheets(&self, dom: &NodeRef) -> Vec<StylesheetInfo> {
+    fn get_external_stylesheets(
+        &self,
+        dom: &NodeRef,
+        report: &mut ProcessReport,
+    ) -> Vec<StylesheetInfo> {
         let external_sheets: Vec<_> = dom.select("link[rel=\"stylesheet\"]").unwrap().collect();
 
         external_sheets
@@ -432,7 +1039,13 @@ impl Critters {
             .filter_map(|link| {
                 self.inline_external_stylesheet(link.as_node(), dom)
                     .unwrap_or_else(|e| {
-                        error!("Failed to inline external stylesheet. {e}");
+                        // Asset errors are logged as the stylesheet is resolved
+                        if !matches!(e, CrittersError::Asset { .. }) {
+                            error!("Failed to inline external stylesheet. {e}");
+                        }
+                        report
+                            .warnings
+                            .push(format!("Failed to inline external stylesheet. {e}"));
                         None
                     })
             })
@@ -440,15 +1053,31 @@ impl Critters {
     }
 
     /// Resolve styles for the provided additional stylesheets, if any, and append them to the head.
-    fn get_additional_stylesheets(&self, dom: &NodeRef) -> anyhow::Result<Vec<StylesheetInfo>> {
+    fn get_additional_stylesheets(
+        &self,
+        dom: &NodeRef,
+        report: &mut ProcessReport,
+    ) -> Result<Vec<StylesheetInfo>, CrittersError> {
         self.options
             .additional_stylesheets
             .iter()
             .sorted()
             .dedup()
-            .filter_map(|href| self.get_css_asset(href))
-            .map(|css| self.inject_style(&css, dom))
-            .map_ok(|el| StylesheetInfo { el, reduce: true })
+            .filter_map(|href| match self.get_css_asset(href) {
+                Ok(css) => css.map(|css| (href, css)),
+                Err(e) => {
+                    report.warnings.push(e.to_string());
+                    None
+                }
+            })
+            .map(|(href, css)| {
+                self.inject_style(&css, dom).map(|el| StylesheetInfo {
+                    el,
+                    reduce: true,
+                    source: StylesheetSource::Additional,
+                    href: Some(href.clone()),
+                })
+            })
             .collect()
     }
 
@@ -457,25 +1086,25 @@ impl Critters {
         &self,
         sheet: &str,
         dom: NodeRef,
-        critters_container: &html::NodeDataRef<html::ElementData>,
-    ) -> anyhow::Result<String> {
+        scope: &CriticalScope,
+        report: &mut ProcessReport,
+        stylesheet: &mut StylesheetReport,
+    ) -> Result<String, CrittersError> {
         let mut failed_selectors = Vec::new();
         let mut rules_to_remove = HashSet::new();
-        let mut critical_keyframe_names: HashSet<String> = HashSet::new();
-        let mut critical_fonts = String::new();
+        let mut critical_keyframe_names = AnimationNames::default();
+        let mut critical_at_rules = AtRuleUsage::default();
+        let mut critical_fonts = HashSet::new();
+        let mut critical_images = IndexSet::new();
 
         let mut ast = StyleSheet::parse(sheet, Default::default())
-            .map_err(|_| anyhow::Error::msg("Failed to parse stylesheet."))?;
+            .map_err(|e| CrittersError::parse(stylesheet.href.as_deref(), e))?;
+        let control_comments = ControlComments::parse(sheet, &ast.rules);
 
         // Precompute list of used selectors
-        let all_selectors = ast
-            .rules
-            .0
-            .iter()
-            .filter_map(|rule| match rule {
-                CssRule::Style(style_rule) => Some(style_rule.selectors.clone()),
-                _ => None,
-            })
+        let all_selectors = css::reducible_style_rules(&ast.rules)
+            .into_iter()
+            .map(|style_rule| &style_rule.selectors)
             .filter_map(|selectors| {
                 match Selectors::compile(
                     &selectors
@@ -492,84 +1121,133 @@ impl Critters {
             .flat_map(|selectors| selectors.0)
             .collect::<HashSet<_>>();
 
-        let used_selectors = style_calculation::calculate_styles_for_tree(
-            &critters_container,
-            all_selectors.clone(),
-        )
+        let used_selectors = match &scope.shadow_host {
+            Some(host) => style_calculation::calculate_styles_for_shadow_tree(
+                host,
+                all_selectors.clone(),
+                &scope.traversal,
+            ),
+            None => style_calculation::calculate_styles_for_trees(
+                &scope.containers,
+                all_selectors.clone(),
+                &scope.traversal,
+            ),
+        }
         .iter()
         .map(|sel| sel.to_string())
         .collect::<HashSet<_>>();
 
         // TODO: use a visitor to handle nested rules
         // First pass, mark rules not present in the document for removal
-        for rule in &mut ast.rules.0 {
-            if let CssRule::Style(style_rule) = rule {
-                let global_pseudo_regex = regex!(r"^::?(before|after)$");
-
-                // Filter selectors based on their usage in the document
-                let filtered_selectors = style_rule
-                    .selectors
-                    .0
-                    .iter()
-                    .filter(|sel| {
-                        let selector = sel.to_css_string(Default::default()).unwrap();
-                        // easy selectors
-                        if selector == ":root"
-                            || selector == "html"
-                            || selector == "body"
-                            || global_pseudo_regex.is_match(&selector)
-                        {
-                            return true;
-                        }
+        let mut pending = vec![&mut ast.rules];
+        while let Some(rules) = pending.pop() {
+            for rule in &mut rules.0 {
+                if let CssRule::Style(style_rule) = rule {
+                    let global_pseudo_regex = regex!(r"^::?(before|after)$");
+
+                    // Control comments take precedence over usage in the document
+                    let forced = control_comments.get(style_rule);
+                    if forced == Some(false) {
+                        rules_to_remove.insert(style_rule.id());
+                        continue;
+                    }
 
-                        // allow rules
-                        if self
-                            .options
-                            .allow_rules
-                            .iter()
-                            .any(|m| m.matches(&selector))
-                        {
-                            return true;
-                        }
+                    // Filter selectors based on their usage in the document
+                    let filtered_selectors = style_rule
+                        .selectors
+                        .0
+                        .iter()
+                        .filter(|sel| {
+                            let selector = sel.to_css_string(Default::default()).unwrap();
+                            // easy selectors
+                            if forced == Some(true)
+                                || selector == ":root"
+                                || selector == "html"
+                                || selector == "body"
+                                || global_pseudo_regex.is_match(&selector)
+                            {
+                                return true;
+                            }
 
-                        // check DOM for elements matching selector
-                        // TODO: consider including failed selectors (mainly pseudo selectors)
-                        // by inverting this check to exclude unused selectors
-                        used_selectors.contains(&selector)
-                    })
-                    .cloned()
-                    .collect::<Vec<_>>();
-
-                if filtered_selectors.is_empty() {
-                    rules_to_remove.insert(style_rule.id());
-                    continue;
-                } else {
-                    style_rule.selectors = SelectorList::new(filtered_selectors.into());
-                }
+                            // allow rules
+                            if self
+                                .options
+                                .allow_rules
+                                .iter()
+                                .any(|m| m.matches(&selector))
+                            {
+                                return true;
+                            }
 
-                // Detect and collect keyframes and font usage
-                for decl in &style_rule.declarations.declarations {
-                    if matches!(
-                        decl.property_id(),
-                        PropertyId::Animation(_) | PropertyId::AnimationName(_)
-                    ) {
-                        let value = decl.value_to_css_string(Default::default()).unwrap();
-                        for v in value.split_whitespace() {
-                            if !v.trim().is_empty() {
-                                critical_keyframe_names.insert(v.trim().to_string());
+                            // classes and attributes added at runtime
+                            if scope
+                                .safelist
+                                .as_ref()
+                                .is_some_and(|safelist| safelist.matches(sel))
+                            {
+                                return true;
                             }
-                        }
+
+                            // check DOM for elements matching selector
+                            // TODO: consider including failed selectors (mainly pseudo selectors)
+                            // by inverting this check to exclude unused selectors
+                            used_selectors.contains(&selector)
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+                    if filtered_selectors.is_empty() {
+                        rules_to_remove.insert(style_rule.id());
+                        continue;
+                    } else {
+                        style_rule.selectors = SelectorList::new(filtered_selectors.into());
                     }
 
-                    if matches!(decl.property_id(), PropertyId::FontFamily) {
-                        critical_fonts.push_str(
-                            format!(
-                                " {}",
-                                &decl.value_to_css_string(Default::default()).unwrap()
-                            )
-                            .as_str(),
-                        );
+                    // Detect and collect keyframes, font and image usage
+                    css::walk_style_rules(&style_rule.rules, &mut |nested| {
+                        for (decl, _) in nested.declarations.iter() {
+                            critical_keyframe_names.collect(decl);
+                            critical_at_rules.collect(decl);
+                            css::collect_font_families(decl, &mut critical_fonts);
+                            if self.options.preload_images {
+                                css::collect_images(decl, &mut critical_images);
+                            }
+                        }
+                    });
+                    for (decl, _) in style_rule.declarations.iter() {
+                        critical_keyframe_names.collect(decl);
+                        critical_at_rules.collect(decl);
+                        css::collect_font_families(decl, &mut critical_fonts);
+                        if self.options.preload_images {
+                            css::collect_images(decl, &mut critical_images);
+                        }
                     }
+                } else if let CssRule::LayerBlock(layer) = rule {
+                    // Rules within cascade layers are reduced like top-level rules
+                    pending.push(&mut layer.rules);
+                } else if let Some(nested) = css::nested_rules(rule) {
+                    // Nested rules are not reduced, so any animations they reference are critical
+                    css::walk_style_rules(nested, &mut |style| {
+                        for (decl, _) in style.declarations.iter() {
+                            critical_keyframe_names.collect(decl);
+                            critical_at_rules.collect(decl);
+                        }
+                    });
+                }
+            }
+        }
+        critical_keyframe_names.resolve_variables(&ast.rules);
+        critical_at_rules.resolve_counter_styles(&ast.rules);
+
+        for image in &critical_images {
+            match self.inject_image_preload(image, stylesheet.href.as_deref(), &dom) {
+                Ok(Some(href)) => report.add_preload(PreloadKind::Image, &href),
+                Ok(None) => (),
+                Err(e) => {
+                    error!("Failed to inject image preload directive. {e}");
+                    report
+                        .warnings
+                        .push(format!("Failed to inject image preload directive. {e}"));
                 }
             }
         }
@@ -583,9 +1261,20 @@ impl Critters {
                     let kf_name = match &k.name {
                         KeyframesName::Ident(CustomIdent(id)) | KeyframesName::Custom(id) => id,
                     };
-                    critical_keyframe_names.contains(&kf_name.to_string())
+                    let critical = critical_keyframe_names.contains(kf_name);
+                    if critical {
+                        report.add_keyframes(kf_name);
+                    }
+                    critical
+                }
+                KeyframesStrategy::All => {
+                    match &k.name {
+                        KeyframesName::Ident(CustomIdent(id)) | KeyframesName::Custom(id) => {
+                            report.add_keyframes(id)
+                        }
+                    }
+                    true
                 }
-                KeyframesStrategy::All => true,
                 KeyframesStrategy::None => false,
             },
             CssRule::FontFace(f) => {
@@ -602,8 +1291,8 @@ impl Critters {
                                 .unwrap()
                                 .and_then(|m| m.get(2).map(|c| c.as_str().to_string()));
                         }
-                        FontFaceProperty::FontFamily(f) => {
-                            family = Some(f.to_css_string(Default::default()).unwrap())
+                        FontFaceProperty::FontFamily(FontFamily::FamilyName(name)) => {
+                            family = Some(name.to_string())
                         }
                         _ => (),
                     }
@@ -615,31 +1304,69 @@ impl Critters {
                     && !preloaded_fonts.contains(href.as_ref().unwrap())
                 {
                     let href = href.clone().unwrap();
-                    if let Err(e) = self.inject_font_preload(&href, &dom) {
-                        error!("Failed to inject font preload directive. {e}");
+                    match self.inject_font_preload(&href, &dom) {
+                        Ok(()) => report.add_preload(PreloadKind::Font, href.trim()),
+                        Err(e) => {
+                            error!("Failed to inject font preload directive. {e}");
+                            report
+                                .warnings
+                                .push(format!("Failed to inject font preload directive. {e}"));
+                        }
                     }
                     preloaded_fonts.insert(href);
                 }
 
-                self.options.inline_fonts
-                    && family.is_some()
+                let inline = self.options.inline_fonts
                     && href.as_ref().is_some()
-                    && critical_fonts.contains(&family.unwrap())
+                    && family
+                        .as_ref()
+                        .is_some_and(|family| critical_fonts.contains(family.as_str()));
+                if inline {
+                    report.add_font(family.as_ref().unwrap());
+                }
+                inline
+            }
+            // usage of registered properties is evaluated across the whole document later
+            CssRule::Property(_) => !matches!(self.options.property_rules, AtRuleStrategy::None),
+            CssRule::CounterStyle(c) => self
+                .options
+                .counter_style_rules
+                .retain(|| critical_at_rules.counter_styles.contains(c.name.0.as_ref())),
+            CssRule::FontPaletteValues(p) => self
+                .options
+                .font_palette_values_rules
+                .retain(|| critical_at_rules.font_palettes.contains(p.name.as_ref())),
+            CssRule::Page(_) => self.options.page_rules.retain(|| false),
+            CssRule::Unknown(u) if u.name.eq_ignore_ascii_case("font-feature-values") => {
+                self.options.font_feature_values_rules.retain(|| {
+                    css::font_feature_values_families(&u.prelude)
+                        .iter()
+                        .any(|family| critical_fonts.contains(family.as_str()))
+                })
+            }
+            CssRule::Unknown(u) if u.name.eq_ignore_ascii_case("view-transition") => {
+                self.options.view_transition_rules.retain(|| true)
             }
             _ => true,
         });
+        css::reduce_layers(&mut ast.rules, &rules_to_remove);
 
         debug!(
             "Removed {}/{} rules.",
             original_rules - ast.rules.0.len(),
             original_rules
         );
+        stylesheet.rules_kept = ast.rules.0.len();
+        stylesheet.rules_removed = original_rules - ast.rules.0.len();
+        stylesheet.failed_selectors = failed_selectors;
 
         // serialize stylesheet
-        let css = ast.to_css(PrinterOptions {
-            minify: self.options.compress,
-            ..Default::default()
-        })?;
+        let css = ast
+            .to_css(PrinterOptions {
+                minify: self.options.compress,
+                ..Default::default()
+            })
+            .map_err(|e| CrittersError::Print(e.to_string()))?;
 
         Ok(css.code)
     }
@@ -649,8 +1376,10 @@ impl Critters {
         &self,
         style: &NodeRef,
         dom: NodeRef,
-        critters_container: &html::NodeDataRef<html::ElementData>,
-    ) -> anyhow::Result<()> {
+        scope: &CriticalScope,
+        report: &mut ProcessReport,
+        stylesheet: &mut StylesheetReport,
+    ) -> Result<(), CrittersError> {
         let style_child = match style.children().nth(0) {
             Some(c) => c,
             // skip empty stylesheets
@@ -660,7 +1389,7 @@ impl Critters {
 
         let sheet = match style_data {
             NodeData::Text(t) => t.borrow().to_string(),
-            _ => return Err(anyhow::Error::msg("Invalid style tag")),
+            _ => return Err(CrittersError::InvalidStyle),
         };
 
         // skip empty stylesheets
@@ -668,7 +1397,7 @@ impl Critters {
             return Ok(());
         }
 
-        let css = self.process_style(&sheet, dom, critters_container)?;
+        let css = self.process_style(&sheet, dom, scope, report, stylesheet)?;
 
         // remove all existing text from style node
         style.children().for_each(|c| c.detach());
@@ -677,10 +1406,112 @@ impl Critters {
         Ok(())
     }
 
-    /// Given href, find the corresponding CSS asset
-    fn get_css_asset(&self, href: &str) -> Option<String> {
+    /// Remove custom properties and `@property` registrations that are not referenced by any
+    /// stylesheet or inline style in the document from the given reduced stylesheets.
+    fn prune_custom_properties(
+        &self,
+        reduced: &[NodeRef],
+        dom: &NodeRef,
+        report: &mut ProcessReport,
+    ) {
+        let prune_registrations = matches!(self.options.property_rules, AtRuleStrategy::Critical);
+        if !self.options.prune_custom_properties
+            && !reduced
+                .iter()
+                .any(|el| el.text_contents().contains("@property"))
+        {
+            return;
+        }
+
+        // stylesheets within shadow trees may reference custom properties of the document
+        let trees = std::iter::once(dom.clone())
+            .chain(dom.shadow_roots().into_iter().map(|(_, root)| root))
+            .collect::<Vec<_>>();
+        let sheets = trees
+            .iter()
+            .flat_map(|tree| tree.select("style").unwrap())
+            .map(|el| (el.as_node().clone(), el.text_contents()))
+            .collect::<Vec<_>>();
+
+        let mut usage = CustomPropertyUsage::default();
+        let mut asts = Vec::new();
+        for (el, sheet) in &sheets {
+            // Other stylesheets are left as is, and were already reported if they are invalid
+            if !reduced.contains(el) {
+                usage.add_unparsed_stylesheet(sheet);
+                continue;
+            }
+            match StyleSheet::parse(sheet, Default::default()) {
+                Ok(ast) => asts.push((el, ast)),
+                Err(e) => {
+                    let warning = format!(
+                        "Failed to parse stylesheet, skipping custom property pruning. {e}"
+                    );
+                    warn!("{warning}");
+                    report.warnings.push(warning);
+                    return;
+                }
+            }
+        }
+
+        for (_, ast) in &asts {
+            usage.add_stylesheet(&ast.rules);
+        }
+        for el in trees
+            .iter()
+            .flat_map(|tree| tree.select("[style]").unwrap())
+        {
+            let attrs = el.attributes.borrow();
+            let style = attrs.get("style").unwrap_or_default();
+            match StyleAttribute::parse(style, Default::default()) {
+                Ok(attr) => usage.add_inline_style(&attr.declarations),
+                // fall back to scanning for references when the attribute is invalid
+                Err(_) => regex!(r"var\(\s*(--[\w-]+)")
+                    .captures_iter(style)
+                    .for_each(|c| usage.add_root(&c[1])),
+            };
+        }
+
+        let used = usage.used();
+        // Registrations are retained for declared properties, unless those are being removed
+        let registered = if self.options.prune_custom_properties {
+            used.clone()
+        } else {
+            used.iter().chain(usage.declared()).cloned().collect()
+        };
+
+        for (el, mut ast) in asts {
+            let removed = css::remove_unused_custom_properties(
+                &mut ast.rules,
+                self.options.prune_custom_properties.then_some(&used),
+                prune_registrations.then_some(&registered),
+            );
+            if removed == 0 {
+                continue;
+            }
+            debug!("Removed {removed} unused custom properties and registrations.");
+
+            match ast.to_css(PrinterOptions {
+                minify: self.options.compress,
+                ..Default::default()
+            }) {
+                Ok(css) => {
+                    el.children().for_each(|c| c.detach());
+                    el.append(NodeRef::new_text(css.code));
+                }
+                Err(e) => {
+                    error!("Failed to serialize stylesheet. {e}");
+                    report
+                        .warnings
+                        .push(format!("Failed to serialize stylesheet. {e}"));
+                }
+            }
+        }
+    }
+
+    /// Given href, find the corresponding CSS asset. Returns `None` for remote stylesheets.
+    fn get_css_asset(&self, href: &str) -> Result<Option<String>, CrittersError> {
         let output_path = &self.options.path;
-        let output_path_absolute = path::absolute(&self.options.path).unwrap();
         let public_path = &self.options.public_path;
 
         // CHECK - the output path
@@ -697,38 +1528,56 @@ impl Critters {
 
         // Ignore remote stylesheets
         if regex!(r"^https?:\/\/").is_match(normalized_path) || href.starts_with("//") {
-            return None;
+            return Ok(None);
         }
 
+        let asset_error = |path: Option<path::PathBuf>, reason: String| CrittersError::Asset {
+            href: href.to_string(),
+            path,
+            reason,
+        };
+
+        let output_path_absolute = match path::absolute(output_path) {
+            Ok(path) => path,
+            Err(e) => {
+                let reason = format!("Failed to resolve output path \"{output_path}\". {e}");
+                warn!("{reason}");
+                return Err(asset_error(None, reason));
+            }
+        };
+
         let filename = match path::absolute(path::Path::new(output_path).join(normalized_path)) {
             Ok(path) => path.clean(),
             Err(e) => {
-                warn!(
+                let reason = format!(
                     "Failed to resolve path with output path {} and href {}. {e}",
                     output_path, normalized_path
                 );
-                return None;
+                warn!("{reason}");
+                return Err(asset_error(None, reason));
             }
         };
 
         // Check if the resolved path is valid
         if !filename.starts_with(&output_path_absolute) {
-            warn!(
+            let reason = format!(
                 "Matched stylesheet with path \"{}\", which is not within the configured output path \"{}\".",
                 filename.display(),
                 output_path_absolute.display()
             );
-            return None;
+            warn!("{reason}");
+            return Err(asset_error(Some(filename), reason));
         }
 
-        match fs::read_to_string(filename.clone()) {
-            Ok(sheet) => Some(sheet),
+        match fs::read_to_string(&filename) {
+            Ok(sheet) => Ok(Some(sheet)),
             Err(e) => {
-                error!(
+                let reason = format!(
                     "Loading stylesheet at path \"{}\" failed. {e}",
                     filename.display()
                 );
-                None
+                error!("{reason}");
+                Err(asset_error(Some(filename), reason))
             }
         }
     }
@@ -738,7 +1587,7 @@ impl Critters {
         &self,
         link: &NodeRef,
         dom: &NodeRef,
-    ) -> anyhow::Result<Option<StylesheetInfo>> {
+    ) -> Result<Option<StylesheetInfo>, CrittersError> {
         let link_el = link.as_element().unwrap();
         let link_attrs = link_el.attributes.borrow();
         let href = match link_attrs.get("href") {
@@ -747,7 +1596,7 @@ impl Critters {
         };
         drop(link_attrs);
 
-        let sheet = match self.get_css_asset(&href) {
+        let sheet = match self.get_css_asset(&href)? {
             Some(v) => v,
             None => return Ok(None),
         };
@@ -764,6 +1613,8 @@ impl Critters {
             return Ok(Some(StylesheetInfo {
                 el: style,
                 reduce: false,
+                source: StylesheetSource::External,
+                href: Some(href),
             }));
         }
 
@@ -779,12 +1630,14 @@ impl Critters {
             return Ok(Some(StylesheetInfo {
                 el: style,
                 reduce: true,
+                source: StylesheetSource::External,
+                href: Some(href),
             }));
         }
 
         let body = dom
             .select_first("body")
-            .map_err(|_| anyhow::Error::msg("Failed to locate document body"))?;
+            .map_err(|_| CrittersError::MissingElement("body"))?;
 
         let update_link_to_preload = || {
             let mut link_attrs = link_el.attributes.borrow_mut();
@@ -864,14 +1717,16 @@ impl Critters {
         Ok(Some(StylesheetInfo {
             el: style,
             reduce: true,
+            source: StylesheetSource::External,
+            href: Some(href),
         }))
     }
 
     /// Inject the given CSS stylesheet as a new <style> tag in the DOM
-    fn inject_style(&self, sheet: &str, dom: &NodeRef) -> anyhow::Result<NodeRef> {
+    fn inject_style(&self, sheet: &str, dom: &NodeRef) -> Result<NodeRef, CrittersError> {
         let head = dom
             .select_first("head")
-            .map_err(|_| anyhow::Error::msg("Failed to locate <head> element in DOM."))?;
+            .map_err(|_| CrittersError::MissingElement("head"))?;
         let style_node = NodeRef::new_html_element("style", vec![]);
 
         style_node.append(NodeRef::new_text(sheet));
@@ -881,10 +1736,10 @@ impl Critters {
     }
 
     /// Injects a preload directive into the head for the given font URL.
-    fn inject_font_preload(&self, font: &str, dom: &NodeRef) -> anyhow::Result<()> {
+    fn inject_font_preload(&self, font: &str, dom: &NodeRef) -> Result<(), CrittersError> {
         let head = dom
             .select_first("head")
-            .map_err(|_| anyhow::Error::msg("Failed to locate <head> element in DOM."))?;
+            .map_err(|_| CrittersError::MissingElement("head"))?;
 
         head.as_node().append(NodeRef::new_html_element(
             "link",
@@ -899,23 +1754,99 @@ impl Critters {
         Ok(())
     }
 
-    fn merge_stylesheets(&self, styles: Vec<StylesheetInfo>) {
-        let mut styles_iter = styles.into_iter().map(|s| s.el).rev();
-        let first = match styles_iter.next() {
-            Some(f) => match f.first_child() {
-                Some(c) => c,
-                None => return,
-            },
+    /// Injects a preload directive into the head for the given image, unless it is already preloaded.
+    /// Relative URLs are resolved against the `href` of the stylesheet referencing the image, if it
+    /// is not inline. Returns the URL of the image if a directive was injected.
+    fn inject_image_preload(
+        &self,
+        image: &CriticalImage,
+        stylesheet_href: Option<&str>,
+        dom: &NodeRef,
+    ) -> Result<Option<String>, CrittersError> {
+        let resolve = |url: &str| match stylesheet_href {
+            Some(stylesheet_href) => utils::resolve_url(stylesheet_href, url.trim()),
+            None => url.trim().to_string(),
+        };
+        let (href, srcset) = match image {
+            CriticalImage::Url(url) => (resolve(url), None),
+            CriticalImage::ImageSet(candidates) => (
+                resolve(&candidates[0].0),
+                Some(
+                    candidates
+                        .iter()
+                        .map(|(url, density)| format!("{} {density}", resolve(url)))
+                        .join(", "),
+                ),
+            ),
+        };
+        let href = href.as_str();
+
+        // inline images do not need to be preloaded
+        if href.is_empty() || href.starts_with("data:") {
+            return Ok(None);
+        }
+
+        let head = dom
+            .select_first("head")
+            .map_err(|_| CrittersError::MissingElement("head"))?;
+
+        let already_preloaded = head
+            .as_node()
+            .select("link[rel=preload][as=image]")
+            .unwrap()
+            .any(|link| link.attributes.borrow().get("href") == Some(href));
+        if already_preloaded {
+            return Ok(None);
+        }
+
+        let mut attrs = vec![("rel", "preload"), ("as", "image"), ("href", href)];
+        if let Some(srcset) = &srcset {
+            attrs.push(("imagesrcset", srcset));
+        }
+        if self.options.preload_images_high_priority {
+            attrs.push(("fetchpriority", "high"));
+        }
+        head.as_node()
+            .append(NodeRef::new_html_element("link", attrs));
+
+        Ok(Some(href.to_string()))
+    }
+
+    /// Merge the given stylesheets into the last of them. Stylesheets are concatenated in
+    /// document order, so that the cascade (including the order of `@layer` declarations) is
+    /// preserved.
+    fn merge_stylesheets(&self, styles: Vec<StylesheetInfo>, dom: &NodeRef) {
+        let mut styles = styles.into_iter().map(|s| s.el).collect::<Vec<_>>();
+        let document_order = dom
+            .inclusive_descendants()
+            .enumerate()
+            .filter(|(_, node)| styles.contains(node))
+            .map(|(i, node)| (node, i))
+            .collect::<Vec<_>>();
+        styles.sort_by_key(|el| {
+            document_order
+                .iter()
+                .find(|(node, _)| node == el)
+                .map(|(_, i)| *i)
+                .unwrap_or(usize::MAX)
+        });
+
+        let last = match styles.pop() {
+            Some(l) => match l.first_child() {
+                Some(c) => c,
+                None => return,
+            },
             None => return,
         };
 
-        let mut sheet = first.text_contents();
-        for style in styles_iter {
+        let mut sheet = String::new();
+        for style in styles {
             sheet += &style.text_contents();
             style.detach();
         }
+        sheet += &last.text_contents();
 
-        first.into_text_ref().unwrap().replace(sheet);
+        last.into_text_ref().unwrap().replace(sheet);
     }
 }
 
@@ -970,6 +1901,7 @@ mod tests {
 
         for (path, contents) in files {
             let file_path = tmp_dir.path().join(path);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
             let mut tmp_file = File::create(file_path).unwrap();
             writeln!(tmp_file, "{}", contents).unwrap();
         }
@@ -991,6 +1923,299 @@ mod tests {
         assert!(!stylesheet.contains(".non-critical"));
     }
 
+    #[test]
+    fn process_document() {
+        let critters = Critters::new(Default::default());
+
+        let dom = html::parse_html().one(BASIC_HTML);
+        let report = critters.process_document(&dom).unwrap();
+        assert_eq!(report.stylesheets_reduced(), 1);
+        assert!(report.warnings.is_empty());
+
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+        assert!(stylesheet.contains(".critical"));
+        assert!(!stylesheet.contains(".non-critical"));
+
+        assert_eq!(dom.to_string(), critters.process(BASIC_HTML).unwrap());
+    }
+
+    #[test]
+    fn process_document_without_body() {
+        use markup5ever::{local_name, namespace_url, ns, QualName};
+
+        let critters = Critters::new(Default::default());
+
+        let dom = NodeRef::new_document();
+        let style = NodeRef::new_element(QualName::new(None, ns!(html), local_name!("style")), []);
+        style.append(NodeRef::new_text(".critical { color: red; }"));
+        dom.append(style);
+
+        assert!(matches!(
+            critters.process_document(&dom),
+            Err(CrittersError::MissingElement("body"))
+        ));
+    }
+
+    #[test]
+    fn process_with_report() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        let write_file = |name: &str, contents: &str| {
+            let mut file = File::create(tmp_dir.path().join(name)).unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+        };
+        write_file("small.css", ".small { color: green; }");
+        write_file(
+            "main.css",
+            r#"
+            @font-face { font-family: "Critical"; src: url(/critical.woff2); }
+            @font-face { font-family: "Unused"; src: url(/unused.woff2); }
+            @keyframes spin { to { transform: rotate(1turn); } }
+            @keyframes unused { to { opacity: 0; } }
+            .critical { font-family: "Critical"; animation: spin 1s; }
+            .non-critical { color: blue; }
+            .hero { background-image: url(/hero.png); }
+            "#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            external: true,
+            inline_threshold: 100,
+            preload_fonts: true,
+            inline_fonts: true,
+            preload_images: true,
+            ..Default::default()
+        });
+
+        let html = construct_html(
+            r#"<style>.critical:hover:unknown { color: red; } .other { color: red; }</style>
+            <link rel="stylesheet" href="small.css">
+            <link rel="stylesheet" href="main.css">"#,
+            r#"<div class="critical hero">Hello World</div>"#,
+        );
+        let (processed, report) = critters.process_with_report(&html).unwrap();
+        assert_eq!(processed, critters.process(&html).unwrap());
+
+        let [inline, small, main] = report.stylesheets.as_slice() else {
+            panic!("Expected three stylesheets, got {:?}", report.stylesheets);
+        };
+        assert_eq!(inline.source, StylesheetSource::Inline);
+        assert_eq!(inline.action, StylesheetAction::Reduced);
+        assert_eq!((inline.rules_kept, inline.rules_removed), (0, 2));
+        assert_eq!(inline.failed_selectors.len(), 1);
+        assert_eq!(inline.bytes_after, 0);
+
+        assert_eq!(small.source, StylesheetSource::External);
+        assert_eq!(small.href.as_deref(), Some("small.css"));
+        assert_eq!(small.action, StylesheetAction::Inlined);
+        assert_eq!(small.bytes_before, small.bytes_after);
+
+        assert_eq!(main.href.as_deref(), Some("main.css"));
+        assert_eq!(main.action, StylesheetAction::Reduced);
+        assert_eq!((main.rules_kept, main.rules_removed), (4, 3));
+        assert!(main.bytes_after < main.bytes_before);
+
+        assert_eq!(report.stylesheets_reduced(), 2);
+        assert_eq!(report.keyframes, vec!["spin"]);
+        assert_eq!(report.fonts, vec!["Critical"]);
+        assert!(report
+            .preloads
+            .iter()
+            .any(|p| p.kind == PreloadKind::Image && p.href == "/hero.png"));
+        assert!(report.preloads.iter().any(|p| p.kind == PreloadKind::Font));
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn directory_stats() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        File::create(tmp_dir.path().join("shared.css"))
+            .unwrap()
+            .write_all(BASIC_CSS.as_bytes())
+            .unwrap();
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+
+        let page = |path: &str, time_ms: f64, html: &str| {
+            PageReport::new(
+                path.to_string(),
+                time_ms,
+                critters.process_with_report(html).map(|(_, report)| report),
+            )
+        };
+        let shared = r#"<link rel="stylesheet" href="/shared.css">"#;
+
+        let stats = CrittersDirectoryStats::from_pages(
+            vec![
+                page(
+                    "b.html",
+                    5.0,
+                    &construct_html(shared, r#"<div class="critical"></div>"#),
+                ),
+                page(
+                    "a.html",
+                    10.0,
+                    &construct_html(shared, r#"<div class="critical"></div>"#),
+                ),
+                page("c.html", 1.0, BASIC_HTML),
+                page("empty.html", 1.0, &construct_html("", "")),
+                PageReport::new(
+                    "failed.html".to_string(),
+                    1.0,
+                    Err(CrittersError::MissingElement("head")),
+                ),
+            ],
+            1.5,
+        );
+
+        assert_eq!(stats.pages, 5);
+        assert_eq!(
+            (
+                stats.pages_succeeded,
+                stats.pages_failed,
+                stats.pages_skipped
+            ),
+            (3, 1, 1)
+        );
+        assert_eq!(
+            stats.critical_bytes,
+            stats
+                .biggest_pages
+                .iter()
+                .map(|p| p.critical_bytes)
+                .sum::<usize>()
+        );
+        let paths = |pages: &[PageSummary]| pages.iter().map(|p| p.path.clone()).collect_vec();
+        assert_eq!(paths(&stats.slowest_pages), ["a.html", "b.html", "c.html"]);
+        assert_eq!(stats.stylesheets.len(), 1);
+        assert_eq!(stats.stylesheets[0].href, "/shared.css");
+        assert_eq!(stats.stylesheets[0].pages, 2);
+        assert_eq!(
+            stats
+                .page_reports
+                .iter()
+                .map(|p| p.path.as_str())
+                .collect_vec(),
+            ["a.html", "b.html", "c.html", "empty.html", "failed.html"]
+        );
+        assert_eq!(
+            stats.page_reports[4].error.as_deref(),
+            Some("Failed to locate <head> element in document.")
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        File::create(tmp_dir.path().join("broken.css"))
+            .unwrap()
+            .write_all(b".a { color: red; }\n  .b:: { color: red }")
+            .unwrap();
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+
+        let err = critters.get_css_asset("missing.css").unwrap_err();
+        assert_eq!(err.code(), "ASSET_ERROR");
+        assert!(matches!(
+            err,
+            CrittersError::Asset { href, path: Some(path), .. }
+                if href == "missing.css" && path.ends_with("missing.css")
+        ));
+        assert!(matches!(
+            critters.get_css_asset("../outside.css"),
+            Err(CrittersError::Asset { .. })
+        ));
+        assert!(matches!(
+            critters.get_css_asset("https://example.com/remote.css"),
+            Ok(None)
+        ));
+
+        let (_, report) = critters
+            .process_with_report(&construct_html(
+                r#"<link rel="stylesheet" href="broken.css"><link rel="stylesheet" href="missing.css">"#,
+                "",
+            ))
+            .unwrap();
+        assert_eq!(report.stylesheets.len(), 1);
+        assert_eq!(report.stylesheets[0].action, StylesheetAction::Skipped);
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.warnings[0].contains("Failed to load stylesheet \"missing.css\""));
+        assert!(report.warnings[1].contains("Failed to parse stylesheet broken.css:2:8: "));
+
+        let err = CrittersError::parse(
+            Some("broken.css"),
+            StyleSheet::parse(".a {}\n  .b:: {}", Default::default()).unwrap_err(),
+        );
+        assert_eq!(err.code(), "PARSE_ERROR");
+        assert!(matches!(
+            err,
+            CrittersError::Parse {
+                location: Some(SourceLocation { line: 2, column: 8 }),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn strict() {
+        let html = construct_html(
+            r#"<style>.critical { color: red; } .b:: {}</style>
+            <link rel="stylesheet" href="missing.css">"#,
+            r#"<div class="critical"></div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+        let (_, report) = critters.process_with_report(&html).unwrap();
+        assert_eq!(report.warnings.len(), 2);
+
+        let critters = Critters::new(CrittersOptions {
+            strict: true,
+            ..Default::default()
+        });
+        let err = critters.process(&html).unwrap_err();
+        assert_eq!(err.code(), "STRICT");
+        let CrittersError::Strict(problems) = &err else {
+            panic!("Expected a strict mode error, got {err:?}");
+        };
+        assert_eq!(problems, &report.warnings);
+        assert!(err
+            .to_string()
+            .starts_with("Processing failed in strict mode with 2 problems:\n  - "));
+        assert!(critters.process(BASIC_HTML).is_ok());
+
+        let mut failed = PageReport::new("failed.html".to_string(), 1.0, Ok(report));
+        failed.fail_strict();
+        let stats = CrittersDirectoryStats::from_pages(
+            vec![
+                failed,
+                PageReport::new(
+                    "error.html".to_string(),
+                    1.0,
+                    Err(CrittersError::MissingElement("head")),
+                ),
+                PageReport::skipped(
+                    "binary.html".to_string(),
+                    1.0,
+                    "The file is not valid UTF-8.".to_string(),
+                ),
+            ],
+            1.0,
+        );
+        assert_eq!(stats.pages_failed, 2);
+        assert_eq!(stats.critical_bytes, 0);
+        let problems = stats.problems();
+        assert_eq!(problems.len(), 3);
+        assert_eq!(
+            problems[0],
+            "error.html: Failed to locate <head> element in document."
+        );
+        assert!(problems[1..].iter().all(|p| p.starts_with("failed.html: ")));
+    }
+
     #[test]
     fn complex() {
         let critters = Critters::new(Default::default());
@@ -1057,6 +2282,152 @@ mod tests {
         assert_eq!(preload_attrs.get("href"), Some("trickster-COLRv1.otf"));
     }
 
+    #[test]
+    fn image_preload() {
+        let html = construct_html(
+            r#"<style>
+                .hero { background: url("hero.jpg") no-repeat center; }
+                .hero-again { background-image: url(hero.jpg); }
+                .list { list-style-image: url(bullet.svg); content: url(marker.svg); }
+                .mask { mask-image: url("data:image/svg+xml;base64,PHN2Zz48L3N2Zz4="); }
+                .retina { background-image: image-set("logo.png" 1x, "logo@2x.png" 2x); }
+                .unused { background-image: url(unused.jpg); }
+            </style>"#,
+            r#"<div class="hero hero-again"></div>
+            <ul class="list"></ul>
+            <div class="mask"></div>
+            <div class="retina"></div>"#,
+        );
+        let critters = Critters::new(CrittersOptions {
+            preload_images: true,
+            preload_images_high_priority: true,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let preloads = dom
+            .select("head > link[rel=preload][as=image]")
+            .unwrap()
+            .map(|link| {
+                let attrs = link.attributes.borrow();
+                assert_eq!(attrs.get("fetchpriority"), Some("high"));
+                (
+                    attrs.get("href").unwrap().to_string(),
+                    attrs.get("imagesrcset").map(|s| s.to_string()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            preloads,
+            vec![
+                ("hero.jpg".to_string(), None),
+                ("bullet.svg".to_string(), None),
+                ("marker.svg".to_string(), None),
+                (
+                    "logo.png".to_string(),
+                    Some("logo.png 1x, logo@2x.png 2x".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn image_preload_external() {
+        let tmp_dir = create_test_folder(&[(
+            "css/main.css",
+            r#"
+            .hero { background: url(../img/hero.jpg) no-repeat; }
+            .absolute { background-image: url(/logo.png); }
+            .nested {
+                & .icon { background-image: image-set("icons/a.svg" 1x, "icons/a@2x.svg" 2x); }
+            }
+            "#,
+        )]);
+        let html = construct_html(
+            r#"<link rel="stylesheet" href="/css/main.css">"#,
+            r#"<div class="hero absolute"></div>
+            <div class="nested"><span class="icon"></span></div>"#,
+        );
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir,
+            preload_images: true,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let preloads = dom
+            .select("head > link[rel=preload][as=image]")
+            .unwrap()
+            .map(|link| {
+                let attrs = link.attributes.borrow();
+                (
+                    attrs.get("href").unwrap().to_string(),
+                    attrs.get("imagesrcset").map(|s| s.to_string()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            preloads,
+            vec![
+                ("/img/hero.jpg".to_string(), None),
+                ("/logo.png".to_string(), None),
+                (
+                    "/css/icons/a.svg".to_string(),
+                    Some("/css/icons/a.svg 1x, /css/icons/a@2x.svg 2x".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_url() {
+        let resolve = utils::resolve_url;
+        assert_eq!(resolve("/css/main.css", "../img/a.png"), "/img/a.png");
+        assert_eq!(
+            resolve("/css/main.css?v=1", "./a.png?v=2"),
+            "/css/a.png?v=2"
+        );
+        assert_eq!(resolve("css/main.css", "../../a.png"), "../a.png");
+        assert_eq!(resolve("/main.css", "../a.png"), "/a.png");
+        assert_eq!(
+            resolve("https://cdn.example.com/css/main.css", "../a.png"),
+            "https://cdn.example.com/a.png"
+        );
+        assert_eq!(resolve("main.css", "a.png"), "a.png");
+        for url in [
+            "/a.png",
+            "https://example.com/a.png",
+            "data:image/png;base64,",
+            "#a",
+        ] {
+            assert_eq!(resolve("/css/main.css", url), url);
+        }
+    }
+
+    #[test]
+    fn image_preload_disabled() {
+        let html = construct_html(
+            r#"<style>.hero { background: url("hero.jpg"); }</style>"#,
+            r#"<div class="hero"></div>"#,
+        );
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        dom.select_first("link[rel=preload][as=image]")
+            .expect_err("Unexpected image preload link.");
+    }
+
     #[test]
     fn external_stylesheet() {
         let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
@@ -1210,314 +2581,333 @@ mod tests {
         assert!(!stylesheet.contains(".non-critical"));
     }
 
-    fn setup_preload_test(strategy: PreloadStrategy, link_attrs: Vec<(&str, &str)>) -> NodeRef {
-        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
-
+    #[test]
+    fn merge_stylesheets_preserves_document_order() {
         let html = construct_html(
-            &format!(
-                r#"<link rel="stylesheet" href="external.css" {} />"#,
-                link_attrs
-                    .iter()
-                    .map(|(k, v)| format!(r#"{k}="{v}""#))
-                    .join(" ")
-            ),
-            r#"<div class="critical">Hello world</div>"#,
+            r#"<style>@layer base, components;</style>
+            <style>@layer components { .card { color: red; } }</style>
+            <style>@layer base { .card { color: blue; } }</style>"#,
+            r#"<div class="card">Hello</div>"#,
         );
 
-        let critters = Critters::new(CrittersOptions {
-            path: tmp_dir,
-            external: true,
-            preload: strategy,
-            ..Default::default()
-        });
+        let critters = Critters::new(Default::default());
 
-        let processed = critters
-            .process(&html)
-            .expect("Failed to inline critical css");
+        let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheets: Vec<_> = dom
+            .select("style")
+            .unwrap()
+            .map(|s| s.text_contents())
+            .collect();
 
-        parser.one(processed)
+        assert_eq!(stylesheets.len(), 1);
+        assert_eq!(
+            stylesheets[0],
+            "@layer base,components;@layer components{.card{color:red}}@layer base{.card{color:#00f}}"
+        );
     }
 
-    fn get_noscript_link(noscript_el: &NodeRef) -> NodeRef {
-        use markup5ever::{local_name, namespace_url, ns, QualName};
+    #[test]
+    fn layers_reduced() {
+        let html = construct_html(
+            r#"<style>
+                @layer reset, base, components;
+                @layer reset { h1 { margin: 0; } }
+                @layer base { .unused-base { color: red; } }
+                @layer components {
+                    .card { padding: 1px; }
+                    .unused { color: blue; }
+                    @layer nested { .unused-nested { color: green; } }
+                }
+                @layer { .unused-anonymous { color: orange; } }
+                @layer theme { .unused-theme { color: purple; } }
+                @layer layout { .card { margin: 0; } }
+                @layer theme { .unused-theme-again { color: pink; } }
+            </style>"#,
+            r#"<h1>Title</h1><div class="card">Hello</div>"#,
+        );
 
-        let noscript_text = noscript_el
-            .children()
-            .exactly_one()
-            .expect("Could not get noscript text content.");
-        let noscript_text_val = noscript_text.as_text().unwrap().borrow().clone();
+        let critters = Critters::new(Default::default());
 
-        let ctx_name = QualName::new(None, ns!(html), local_name!("link"));
-        let parser = html::parse_fragment(ctx_name, vec![]);
-        let noscript_doc = parser.one(noscript_text_val);
-        let noscript_child = noscript_doc
-            .first_child()
-            .expect("Could not get noscript link element.")
-            .first_child()
-            .expect("Could not get noscript link element.");
-        let noscript_child_el = noscript_child.as_element().unwrap();
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
 
         assert_eq!(
-            noscript_child_el.name.local,
-            markup5ever::LocalName::from("link")
+            stylesheet,
+            concat!(
+                "@layer reset,base,components;",
+                "@layer reset{h1{margin:0}}",
+                "@layer components{.card{padding:1px}@layer nested{}}",
+                "@layer theme{}",
+                "@layer layout{.card{margin:0}}"
+            )
         );
-
-        noscript_child
     }
 
     #[test]
-    fn preload_swap() {
-        let dom = setup_preload_test(PreloadStrategy::Swap, vec![]);
+    fn multiple_containers() {
+        let html = construct_html(
+            r#"<style>
+                .logo { color: red; }
+                .hero { color: blue; }
+                .footer { color: green; }
+            </style>"#,
+            r#"<header data-critters-container><div class="logo"></div></header>
+            <main data-critters-container><div class="hero"></div></main>
+            <footer><div class="footer"></div></footer>"#,
+        );
 
-        let preload_link = dom
-            .select_first("head > link[rel=preload]")
-            .expect("Failed to locate preload link.");
-        assert_eq!(
-            preload_link.attributes.borrow().get("href"),
-            Some("external.css")
-        );
-        assert_eq!(preload_link.attributes.borrow().get("as"), Some("style"));
+        let critters = Critters::new(Default::default());
 
-        let noscript_el = dom
-            .select_first("noscript")
-            .expect("Failed to locate noscript link");
-        let noscript_link = get_noscript_link(noscript_el.as_node());
-        let noscript_link_el = noscript_link.as_element().unwrap();
+        let processed = critters.process(&html).unwrap();
 
-        assert_eq!(
-            noscript_link_el.attributes.borrow().get("rel"),
-            Some("stylesheet")
-        );
-        assert_eq!(
-            noscript_link_el.attributes.borrow().get("href"),
-            Some("external.css")
-        );
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert_eq!(stylesheet, ".logo{color:red}.hero{color:#00f}");
     }
 
     #[test]
-    fn preload_swap_high() {
-        let dom = setup_preload_test(PreloadStrategy::SwapHigh, vec![]);
-
-        let preload_link = dom
-            .select_first("head > link[rel~=preload]")
-            .expect("Failed to locate preload link.");
-        assert_eq!(
-            preload_link.attributes.borrow().get("href"),
-            Some("external.css")
-        );
-        assert_eq!(
-            preload_link.attributes.borrow().get("rel"),
-            Some("alternate stylesheet preload")
-        );
-        assert_eq!(preload_link.attributes.borrow().get("as"), Some("style"));
-        assert_eq!(
-            preload_link.attributes.borrow().get("title"),
-            Some("styles")
-        );
-        assert_eq!(
-            preload_link.attributes.borrow().get("onload"),
-            Some("this.title='';this.rel='stylesheet'")
-        );
-
-        let noscript_el = dom
-            .select_first("noscript")
-            .expect("Failed to locate noscript link");
-        let noscript_link = get_noscript_link(noscript_el.as_node());
-        let noscript_link_el = noscript_link.as_element().unwrap();
-
-        assert_eq!(
-            noscript_link_el.attributes.borrow().get("rel"),
-            Some("stylesheet")
-        );
-        assert_eq!(
-            noscript_link_el.attributes.borrow().get("href"),
-            Some("external.css")
+    fn container_selector() {
+        let html = construct_html(
+            r#"<style>
+                .logo { color: red; }
+                .hero { color: blue; }
+                .more { color: green; }
+                main > section .title { color: pink; }
+            </style>"#,
+            r#"<header><div class="logo"></div></header>
+            <main>
+                <section><h1 class="title hero"></h1></section>
+                <section><div class="more"></div></section>
+            </main>"#,
         );
-    }
 
-    #[test]
-    fn preload_media() {
-        let dom = setup_preload_test(PreloadStrategy::Media, vec![("media", "test")]);
+        let critters = Critters::new(CrittersOptions {
+            container_selector: "header, main > section:first-of-type".to_string(),
+            ..Default::default()
+        });
 
-        let preload_link = dom
-            .select_first("head > link[media=print]")
-            .expect("Failed to locate preload link.");
-        assert_eq!(
-            preload_link.attributes.borrow().get("onload"),
-            Some("this.media='test'")
-        );
+        let processed = critters.process(&html).unwrap();
 
-        let noscript_el = dom
-            .select_first("noscript")
-            .expect("Failed to locate noscript link");
-        let noscript_link = get_noscript_link(noscript_el.as_node());
-        let noscript_link_el = noscript_link.as_element().unwrap();
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
 
         assert_eq!(
-            noscript_link_el.attributes.borrow().get("rel"),
-            Some("stylesheet")
-        );
-        assert_eq!(
-            noscript_link_el.attributes.borrow().get("href"),
-            Some("external.css")
+            stylesheet,
+            ".logo{color:red}.hero{color:#00f}main>section .title{color:pink}"
         );
     }
 
     #[test]
-    fn noscript_fallback_disabled_swap() {
-        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
-
+    fn container_selector_invalid() {
         let html = construct_html(
-            r#"<link rel="stylesheet" href="external.css" />"#,
-            r#"<div class="critical">Hello world</div>"#,
+            r#"<style>.hero { color: blue; }</style>"#,
+            r#"<div class="hero"></div>"#,
         );
 
         let critters = Critters::new(CrittersOptions {
-            path: tmp_dir,
-            external: true,
-            preload: PreloadStrategy::Swap,
-            noscript_fallback: false,
+            container_selector: "[[invalid".to_string(),
             ..Default::default()
         });
 
-        let processed = critters
-            .process(&html)
-            .expect("Failed to inline critical css");
+        let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Preload link should still exist
-        dom.select_first("head > link[rel=preload]")
-            .expect("Failed to locate preload link.");
-
-        // Noscript should NOT exist
-        dom.select_first("noscript")
-            .expect_err("Noscript element should not exist when noscript_fallback is disabled");
+        assert_eq!(stylesheet, ".hero{color:#00f}");
     }
 
-    #[test]
-    fn noscript_fallback_disabled_media() {
-        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
+    const IGNORED_ELEMENTS_HTML: &str = r#"<div class="hero"></div>
+        <div class="modal" data-critters-ignore><div class="modal-body"></div></div>
+        <nav class="menu"><a class="menu-link"></a></nav>
+        <div class="banner" hidden></div>
+        <dialog class="dialog"><p class="dialog-text"></p></dialog>
+        <dialog class="open-dialog" open></dialog>
+        <template><div class="templated"></div></template>
+        <div class="collapsed" style="color: red; DISPLAY: none !important"></div>"#;
+
+    const IGNORED_ELEMENTS_CSS: &str = r#"<style>
+        .hero { color: red; }
+        .modal, .modal-body { color: blue; }
+        .menu-link { color: green; }
+        .banner { color: orange; }
+        .dialog-text { color: purple; }
+        .open-dialog { color: pink; }
+        .templated { color: teal; }
+        .collapsed { color: navy; }
+    </style>"#;
 
-        let html = construct_html(
-            r#"<link rel="stylesheet" href="external.css" media="screen" />"#,
-            r#"<div class="critical">Hello world</div>"#,
-        );
+    #[test]
+    fn ignored_elements() {
+        let html = construct_html(IGNORED_ELEMENTS_CSS, IGNORED_ELEMENTS_HTML);
 
         let critters = Critters::new(CrittersOptions {
-            path: tmp_dir,
-            external: true,
-            preload: PreloadStrategy::Media,
-            noscript_fallback: false,
+            ignore_selector: Some("nav".to_string()),
             ..Default::default()
         });
 
-        let processed = critters
-            .process(&html)
-            .expect("Failed to inline critical css");
+        let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Media link should still exist
-        dom.select_first("head > link[media=print]")
-            .expect("Failed to locate media link.");
-
-        // Noscript should NOT exist
-        dom.select_first("noscript")
-            .expect_err("Noscript element should not exist when noscript_fallback is disabled");
+        assert_eq!(stylesheet, ".hero{color:red}.open-dialog{color:pink}");
     }
 
     #[test]
-    fn noscript_fallback_disabled_swap_high() {
-        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
-
-        let html = construct_html(
-            r#"<link rel="stylesheet" href="external.css" />"#,
-            r#"<div class="critical">Hello world</div>"#,
-        );
+    fn hidden_elements_analyzed() {
+        let html = construct_html(IGNORED_ELEMENTS_CSS, IGNORED_ELEMENTS_HTML);
 
         let critters = Critters::new(CrittersOptions {
-            path: tmp_dir,
-            external: true,
-            preload: PreloadStrategy::SwapHigh,
-            noscript_fallback: false,
+            skip_hidden: false,
             ..Default::default()
         });
 
-        let processed = critters
-            .process(&html)
-            .expect("Failed to inline critical css");
+        let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Preload link should still exist
-        dom.select_first("head > link[rel~=preload]")
-            .expect("Failed to locate preload link.");
-
-        // Noscript should NOT exist
-        dom.select_first("noscript")
-            .expect_err("Noscript element should not exist when noscript_fallback is disabled");
+        assert!(!stylesheet.contains(".modal"));
+        assert!(stylesheet.contains(".menu-link"));
+        assert!(stylesheet.contains(".banner"));
+        assert!(stylesheet.contains(".dialog-text"));
+        assert!(stylesheet.contains(".collapsed"));
     }
 
     #[test]
-    fn allow_rules_string() {
+    fn fold_cutoff() {
+        let html = construct_html(
+            r#"<style>
+                .hero { color: red; }
+                .intro { color: blue; }
+                .article { color: green; }
+                .comments { color: orange; }
+            </style>"#,
+            r#"<section class="hero"></section>
+            <section class="intro"></section>
+            <div data-critters-fold></div>
+            <article class="article"></article>
+            <section class="comments"></section>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+        let processed = critters.process(&html).unwrap();
+        let dom = html::parse_html().one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+        assert_eq!(stylesheet, ".hero{color:red}.intro{color:#00f}");
+
+        let (_, report) = critters.process_with_report(&html).unwrap();
+        let fold = report.fold.unwrap();
+        assert_eq!(fold.element, "<div>");
+        assert_eq!(fold.reason, style_calculation::FoldReason::Marker);
+        assert_eq!(fold.elements, 3);
+
         let critters = Critters::new(CrittersOptions {
-            allow_rules: vec![Matcher::String(".non-critical".to_string())],
+            fold_max_sections: Some(1),
             ..Default::default()
         });
-
-        let processed = critters.process(BASIC_HTML).unwrap();
-
-        let parser = html::parse_html();
-        let dom = parser.one(processed);
+        let processed = critters.process(&html).unwrap();
+        let dom = html::parse_html().one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
-
-        assert!(stylesheet.contains(".critical"));
-        assert!(stylesheet.contains(".non-critical"));
+        assert_eq!(stylesheet, ".hero{color:red}");
+
+        let (_, report) = critters.process_with_report(&html).unwrap();
+        let fold = report.fold.unwrap();
+        assert_eq!(fold.element, "<section>");
+        assert_eq!(fold.reason, style_calculation::FoldReason::MaxSections);
+        assert_eq!(fold.elements, 2);
+
+        let (_, report) = Critters::new(Default::default())
+            .process_with_report(BASIC_HTML)
+            .unwrap();
+        assert!(report.fold.is_none());
     }
 
     #[test]
-    fn allow_rules_regex() {
+    fn harvest_classes() {
+        let html = construct_html(
+            r#"<style>
+                .menu.is-open .menu-item { color: red; }
+                .toast-visible { color: blue; }
+                .active { color: green; }
+                .md\:flex { display: flex; }
+                [data-state="open"] { color: orange; }
+                [data-state="closed"] { color: purple; }
+                .menu [aria-expanded="true"] { color: pink; }
+                .never-used { color: teal; }
+                .unknown.is-open { color: navy; }
+            </style>"#,
+            r#"<nav class="menu" x-data="{ open: false }" x-bind:class="{ 'is-open': open }">
+                <a class="menu-item" :aria-expanded="open ? 'true' : 'false'"></a>
+            </nav>
+            <div data-state="closed"></div>
+            <span class="tab" class:active={selected}></span>
+            <script>document.body.classList.add("toast-visible");</script>
+            <script type="application/json">{"state": "open", "classes": "md:flex"}</script>"#,
+        );
+
         let critters = Critters::new(CrittersOptions {
-            allow_rules: vec![Matcher::Regex(Regex::new("^.non").unwrap())],
+            harvest_classes: true,
             ..Default::default()
         });
 
-        let processed = critters.process(BASIC_HTML).unwrap();
+        let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        assert!(stylesheet.contains(".critical"));
-        assert!(stylesheet.contains(".non-critical"));
+        assert_eq!(
+            stylesheet,
+            concat!(
+                ".menu.is-open .menu-item{color:red}",
+                ".toast-visible{color:#00f}",
+                ".active{color:green}",
+                ".md\\:flex{display:flex}",
+                "[data-state=open]{color:orange}",
+                "[data-state=closed]{color:purple}",
+                ".menu [aria-expanded=true]{color:pink}"
+            )
+        );
     }
 
     #[test]
-    fn keyframes_critical() {
+    fn harvest_classes_skips_code() {
         let html = construct_html(
             r#"<style>
-                .animated { animation: slideIn 1s ease-in; }
-                .not-animated { color: blue; }
-                @keyframes slideIn {
-                    from { transform: translateX(-100%); }
-                    to { transform: translateX(0); }
-                }
-                @keyframes slideOut {
-                    from { transform: translateX(0); }
-                    to { transform: translateX(100%); }
-                }
+                .toast { color: red; }
+                .astro-card { color: blue; }
+                .is-active { color: green; }
+                .function, .return, .const, .document { color: orange; }
+                .list { color: purple; }
+                .class { color: teal; }
             </style>"#,
-            r#"<div class="animated">Hello World</div>"#,
+            r#"<div class:list='["astro-card", { "is-active": active }]'></div>
+            <nav :class="{ class: false }"></nav>
+            <script>
+                function show() {
+                    const toast = document.createElement("div");
+                    toast.classList.add('toast');
+                    return toast;
+                }
+            </script>"#,
         );
 
         let critters = Critters::new(CrittersOptions {
-            keyframes: KeyframesStrategy::Critical,
+            harvest_classes: true,
             ..Default::default()
         });
 
@@ -1527,40 +2917,30 @@ mod tests {
         let dom = parser.one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Should include the animated class that's used
-        assert!(stylesheet.contains(".animated"));
-        // Should NOT include the unused class
-        assert!(!stylesheet.contains(".not-animated"));
-        // Should include the slideIn keyframe (used by .animated)
-        assert!(stylesheet.contains("slideIn") || stylesheet.contains("@keyframes"));
-        // Should NOT include the unused slideOut keyframe
-        assert!(!stylesheet.contains("slideOut"));
+        assert_eq!(
+            stylesheet,
+            ".toast{color:red}.astro-card{color:#00f}.is-active{color:green}"
+        );
     }
 
     #[test]
-    fn keyframes_all() {
+    fn harvest_classes_from_analyzed_content() {
         let html = construct_html(
             r#"<style>
-                .animated { animation: slideIn 1s ease-in; }
-                .not-animated { color: blue; }
-                @keyframes slideIn {
-                    from { transform: translateX(-100%); }
-                    to { transform: translateX(0); }
-                }
-                @keyframes slideOut {
-                    from { transform: translateX(0); }
-                    to { transform: translateX(100%); }
-                }
-                @keyframes fadeIn {
-                    from { opacity: 0; }
-                    to { opacity: 1; }
-                }
+                .header-open { color: red; }
+                .modal-open { color: blue; }
+                .footer-open { color: green; }
             </style>"#,
-            r#"<div class="animated">Hello World</div>"#,
+            r#"<header :class="{ 'header-open': open }"></header>
+            <div class="modal" data-critters-ignore>
+                <script>modal.classList.add("modal-open");</script>
+            </div>
+            <div data-critters-fold></div>
+            <footer x-bind:class="{ 'footer-open': open }"></footer>"#,
         );
 
         let critters = Critters::new(CrittersOptions {
-            keyframes: KeyframesStrategy::All,
+            harvest_classes: true,
             ..Default::default()
         });
 
@@ -1570,76 +2950,964 @@ mod tests {
         let dom = parser.one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Should include the animated class
-        assert!(stylesheet.contains(".animated"));
-        // Should NOT include the unused class
-        assert!(!stylesheet.contains(".not-animated"));
-        // Should include ALL keyframes, even unused ones
-        assert!(stylesheet.contains("@keyframes slideIn"));
-        assert!(stylesheet.contains("@keyframes slideOut"));
-        assert!(stylesheet.contains("@keyframes fadeIn"));
+        assert_eq!(stylesheet, ".header-open{color:red}");
     }
 
     #[test]
-    fn keyframes_none() {
+    fn declarative_shadow_dom() {
         let html = construct_html(
             r#"<style>
-                .animated { animation: slideIn 1s ease-in; }
-                .not-animated { color: blue; }
-                @keyframes slideIn {
-                    from { transform: translateX(-100%); }
-                    to { transform: translateX(0); }
-                }
-                @keyframes slideOut {
-                    from { transform: translateX(0); }
-                    to { transform: translateX(100%); }
-                }
+                .card::part(title) { color: red; }
+                .card::part(unused) { color: blue; }
+                .inner { color: green; }
+            </style>"#,
+            r#"<div class="card">
+                <template shadowrootmode="open">
+                    <style>
+                        :host { display: block; }
+                        :host(.featured) { border: 1px solid; }
+                        ::slotted(span) { font-weight: bold; }
+                        ::slotted(em) { font-style: normal; }
+                        .inner { padding: 4px; }
+                        .missing { padding: 8px; }
+                        .card { margin: 0; }
+                    </style>
+                    <h2 part="title" class="inner"><slot></slot></h2>
+                </template>
+                <span>Light</span>
+            </div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("head > style").unwrap().text_contents();
+        assert_eq!(stylesheet, ".card::part(title){color:red}");
+
+        let template = dom.select_first("template").unwrap();
+        let shadow_root = template.template_contents.clone().unwrap();
+        let shadow_stylesheet = shadow_root.select_first("style").unwrap().text_contents();
+        assert_eq!(
+            shadow_stylesheet,
+            ":host{display:block}::slotted(span){font-weight:700}.inner{padding:4px}"
+        );
+    }
+
+    #[test]
+    fn control_comments() {
+        let html = construct_html(
+            r#"<style>
+                /* critters:include */
+                .modal { display: none; }
+                /* critters:exclude */
+                .card { padding: 1px; }
+                .tooltip { /* critters:include */ content: "→ ✓"; } h1 { /* critters:exclude */ margin: 0; }
+                /* critters:include start */
+                .toast { color: red; }
+                .drawer { color: blue; }
+                /* critters:include end */
+                .unused { color: green; }
+                /* critters:exclude start */
+                p { color: orange; }
+                @layer base { span { color: purple; } }
+                /* critters:include */
+                .title { color: pink; }
+                /* critters:exclude end */
+                div { color: teal; }
+            </style>"#,
+            r#"<h1 class="title">Title</h1><div class="card"><p>Hello</p><span>World</span></div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert_eq!(
+            stylesheet,
+            concat!(
+                ".modal{display:none}",
+                ".tooltip{content:\"→ ✓\"}",
+                ".toast{color:red}",
+                ".drawer{color:#00f}",
+                "@layer base{}",
+                ".title{color:pink}",
+                "div{color:teal}"
+            )
+        );
+    }
+
+    #[test]
+    fn state_pseudo_classes() {
+        let css = r#"<style>
+            input:checked { color: red; }
+            input:disabled { color: gray; }
+            input:required { color: orange; }
+            input:placeholder-shown { color: silver; }
+            section:target { color: blue; }
+            p:lang(de) { quotes: "„" "“"; }
+            p:lang(fr, "*-CH") { color: green; }
+            p:dir(rtl) { text-align: right; }
+            a:hover { color: pink; }
+        </style>"#;
+        let html = construct_html(
+            css,
+            r#"
+            <input type="checkbox" disabled>
+            <input type="email" placeholder="Email" required>
+            <section id="pricing"></section>
+            <p lang="de-DE" dir="rtl">Hallo</p>
+            <a href="/">Home</a>
+            "#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            url_fragment: Some("#pricing".to_string()),
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert_eq!(
+            stylesheet,
+            concat!(
+                "input:disabled{color:gray}",
+                "input:required{color:orange}",
+                "input:placeholder-shown{color:silver}",
+                "section:target{color:#00f}",
+                "p:lang(de){quotes:\"„\" \"“\"}",
+                "p:dir(rtl){text-align:right}"
+            )
+        );
+    }
+
+    fn setup_preload_test(strategy: PreloadStrategy, link_attrs: Vec<(&str, &str)>) -> NodeRef {
+        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
+
+        let html = construct_html(
+            &format!(
+                r#"<link rel="stylesheet" href="external.css" {} />"#,
+                link_attrs
+                    .iter()
+                    .map(|(k, v)| format!(r#"{k}="{v}""#))
+                    .join(" ")
+            ),
+            r#"<div class="critical">Hello world</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir,
+            external: true,
+            preload: strategy,
+            ..Default::default()
+        });
+
+        let processed = critters
+            .process(&html)
+            .expect("Failed to inline critical css");
+
+        let parser = html::parse_html();
+
+        parser.one(processed)
+    }
+
+    fn get_noscript_link(noscript_el: &NodeRef) -> NodeRef {
+        use markup5ever::{local_name, namespace_url, ns, QualName};
+
+        let noscript_text = noscript_el
+            .children()
+            .exactly_one()
+            .expect("Could not get noscript text content.");
+        let noscript_text_val = noscript_text.as_text().unwrap().borrow().clone();
+
+        let ctx_name = QualName::new(None, ns!(html), local_name!("link"));
+        let parser = html::parse_fragment(ctx_name, vec![]);
+        let noscript_doc = parser.one(noscript_text_val);
+        let noscript_child = noscript_doc
+            .first_child()
+            .expect("Could not get noscript link element.")
+            .first_child()
+            .expect("Could not get noscript link element.");
+        let noscript_child_el = noscript_child.as_element().unwrap();
+
+        assert_eq!(
+            noscript_child_el.name.local,
+            markup5ever::LocalName::from("link")
+        );
+
+        noscript_child
+    }
+
+    #[test]
+    fn preload_swap() {
+        let dom = setup_preload_test(PreloadStrategy::Swap, vec![]);
+
+        let preload_link = dom
+            .select_first("head > link[rel=preload]")
+            .expect("Failed to locate preload link.");
+        assert_eq!(
+            preload_link.attributes.borrow().get("href"),
+            Some("external.css")
+        );
+        assert_eq!(preload_link.attributes.borrow().get("as"), Some("style"));
+
+        let noscript_el = dom
+            .select_first("noscript")
+            .expect("Failed to locate noscript link");
+        let noscript_link = get_noscript_link(noscript_el.as_node());
+        let noscript_link_el = noscript_link.as_element().unwrap();
+
+        assert_eq!(
+            noscript_link_el.attributes.borrow().get("rel"),
+            Some("stylesheet")
+        );
+        assert_eq!(
+            noscript_link_el.attributes.borrow().get("href"),
+            Some("external.css")
+        );
+    }
+
+    #[test]
+    fn preload_swap_high() {
+        let dom = setup_preload_test(PreloadStrategy::SwapHigh, vec![]);
+
+        let preload_link = dom
+            .select_first("head > link[rel~=preload]")
+            .expect("Failed to locate preload link.");
+        assert_eq!(
+            preload_link.attributes.borrow().get("href"),
+            Some("external.css")
+        );
+        assert_eq!(
+            preload_link.attributes.borrow().get("rel"),
+            Some("alternate stylesheet preload")
+        );
+        assert_eq!(preload_link.attributes.borrow().get("as"), Some("style"));
+        assert_eq!(
+            preload_link.attributes.borrow().get("title"),
+            Some("styles")
+        );
+        assert_eq!(
+            preload_link.attributes.borrow().get("onload"),
+            Some("this.title='';this.rel='stylesheet'")
+        );
+
+        let noscript_el = dom
+            .select_first("noscript")
+            .expect("Failed to locate noscript link");
+        let noscript_link = get_noscript_link(noscript_el.as_node());
+        let noscript_link_el = noscript_link.as_element().unwrap();
+
+        assert_eq!(
+            noscript_link_el.attributes.borrow().get("rel"),
+            Some("stylesheet")
+        );
+        assert_eq!(
+            noscript_link_el.attributes.borrow().get("href"),
+            Some("external.css")
+        );
+    }
+
+    #[test]
+    fn preload_media() {
+        let dom = setup_preload_test(PreloadStrategy::Media, vec![("media", "test")]);
+
+        let preload_link = dom
+            .select_first("head > link[media=print]")
+            .expect("Failed to locate preload link.");
+        assert_eq!(
+            preload_link.attributes.borrow().get("onload"),
+            Some("this.media='test'")
+        );
+
+        let noscript_el = dom
+            .select_first("noscript")
+            .expect("Failed to locate noscript link");
+        let noscript_link = get_noscript_link(noscript_el.as_node());
+        let noscript_link_el = noscript_link.as_element().unwrap();
+
+        assert_eq!(
+            noscript_link_el.attributes.borrow().get("rel"),
+            Some("stylesheet")
+        );
+        assert_eq!(
+            noscript_link_el.attributes.borrow().get("href"),
+            Some("external.css")
+        );
+    }
+
+    #[test]
+    fn noscript_fallback_disabled_swap() {
+        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
+
+        let html = construct_html(
+            r#"<link rel="stylesheet" href="external.css" />"#,
+            r#"<div class="critical">Hello world</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir,
+            external: true,
+            preload: PreloadStrategy::Swap,
+            noscript_fallback: false,
+            ..Default::default()
+        });
+
+        let processed = critters
+            .process(&html)
+            .expect("Failed to inline critical css");
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+
+        // Preload link should still exist
+        dom.select_first("head > link[rel=preload]")
+            .expect("Failed to locate preload link.");
+
+        // Noscript should NOT exist
+        dom.select_first("noscript")
+            .expect_err("Noscript element should not exist when noscript_fallback is disabled");
+    }
+
+    #[test]
+    fn noscript_fallback_disabled_media() {
+        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
+
+        let html = construct_html(
+            r#"<link rel="stylesheet" href="external.css" media="screen" />"#,
+            r#"<div class="critical">Hello world</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir,
+            external: true,
+            preload: PreloadStrategy::Media,
+            noscript_fallback: false,
+            ..Default::default()
+        });
+
+        let processed = critters
+            .process(&html)
+            .expect("Failed to inline critical css");
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+
+        // Media link should still exist
+        dom.select_first("head > link[media=print]")
+            .expect("Failed to locate media link.");
+
+        // Noscript should NOT exist
+        dom.select_first("noscript")
+            .expect_err("Noscript element should not exist when noscript_fallback is disabled");
+    }
+
+    #[test]
+    fn noscript_fallback_disabled_swap_high() {
+        let tmp_dir = create_test_folder(&[("external.css", BASIC_CSS)]);
+
+        let html = construct_html(
+            r#"<link rel="stylesheet" href="external.css" />"#,
+            r#"<div class="critical">Hello world</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir,
+            external: true,
+            preload: PreloadStrategy::SwapHigh,
+            noscript_fallback: false,
+            ..Default::default()
+        });
+
+        let processed = critters
+            .process(&html)
+            .expect("Failed to inline critical css");
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+
+        // Preload link should still exist
+        dom.select_first("head > link[rel~=preload]")
+            .expect("Failed to locate preload link.");
+
+        // Noscript should NOT exist
+        dom.select_first("noscript")
+            .expect_err("Noscript element should not exist when noscript_fallback is disabled");
+    }
+
+    #[test]
+    fn allow_rules_string() {
+        let critters = Critters::new(CrittersOptions {
+            allow_rules: vec![Matcher::String(".non-critical".to_string())],
+            ..Default::default()
+        });
+
+        let processed = critters.process(BASIC_HTML).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains(".critical"));
+        assert!(stylesheet.contains(".non-critical"));
+    }
+
+    #[test]
+    fn allow_rules_regex() {
+        let critters = Critters::new(CrittersOptions {
+            allow_rules: vec![Matcher::Regex(Regex::new("^.non").unwrap())],
+            ..Default::default()
+        });
+
+        let processed = critters.process(BASIC_HTML).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains(".critical"));
+        assert!(stylesheet.contains(".non-critical"));
+    }
+
+    #[test]
+    fn keyframes_critical() {
+        let html = construct_html(
+            r#"<style>
+                .animated { animation: slideIn 1s ease-in; }
+                .not-animated { color: blue; }
+                @keyframes slideIn {
+                    from { transform: translateX(-100%); }
+                    to { transform: translateX(0); }
+                }
+                @keyframes slideOut {
+                    from { transform: translateX(0); }
+                    to { transform: translateX(100%); }
+                }
+            </style>"#,
+            r#"<div class="animated">Hello World</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            keyframes: KeyframesStrategy::Critical,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // Should include the animated class that's used
+        assert!(stylesheet.contains(".animated"));
+        // Should NOT include the unused class
+        assert!(!stylesheet.contains(".not-animated"));
+        // Should include the slideIn keyframe (used by .animated)
+        assert!(stylesheet.contains("slideIn") || stylesheet.contains("@keyframes"));
+        // Should NOT include the unused slideOut keyframe
+        assert!(!stylesheet.contains("slideOut"));
+    }
+
+    #[test]
+    fn keyframes_all() {
+        let html = construct_html(
+            r#"<style>
+                .animated { animation: slideIn 1s ease-in; }
+                .not-animated { color: blue; }
+                @keyframes slideIn {
+                    from { transform: translateX(-100%); }
+                    to { transform: translateX(0); }
+                }
+                @keyframes slideOut {
+                    from { transform: translateX(0); }
+                    to { transform: translateX(100%); }
+                }
+                @keyframes fadeIn {
+                    from { opacity: 0; }
+                    to { opacity: 1; }
+                }
+            </style>"#,
+            r#"<div class="animated">Hello World</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            keyframes: KeyframesStrategy::All,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // Should include the animated class
+        assert!(stylesheet.contains(".animated"));
+        // Should NOT include the unused class
+        assert!(!stylesheet.contains(".not-animated"));
+        // Should include ALL keyframes, even unused ones
+        assert!(stylesheet.contains("@keyframes slideIn"));
+        assert!(stylesheet.contains("@keyframes slideOut"));
+        assert!(stylesheet.contains("@keyframes fadeIn"));
+    }
+
+    #[test]
+    fn keyframes_none() {
+        let html = construct_html(
+            r#"<style>
+                .animated { animation: slideIn 1s ease-in; }
+                .not-animated { color: blue; }
+                @keyframes slideIn {
+                    from { transform: translateX(-100%); }
+                    to { transform: translateX(0); }
+                }
+                @keyframes slideOut {
+                    from { transform: translateX(0); }
+                    to { transform: translateX(100%); }
+                }
+            </style>"#,
+            r#"<div class="animated">Hello World</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            keyframes: KeyframesStrategy::None,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // Should include the animated class (but the animation property remains)
+        assert!(stylesheet.contains(".animated"));
+        // Should NOT include the unused class
+        assert!(!stylesheet.contains(".not-animated"));
+        // Should NOT include any keyframes definitions
+        assert!(!stylesheet.contains("@keyframes"));
+        assert!(!stylesheet.contains("slideOut"));
+    }
+
+    #[test]
+    fn keyframes_critical_with_animation_property() {
+        let html = construct_html(
+            r#"<style>
+                .box { animation-name: bounce; }
+                .other { color: red; }
+                @keyframes bounce {
+                    0%, 100% { transform: translateY(0); }
+                    50% { transform: translateY(-20px); }
+                }
+                @keyframes spin {
+                    from { transform: rotate(0deg); }
+                    to { transform: rotate(360deg); }
+                }
+            </style>"#,
+            r#"<div class="box">Bouncing Box</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            keyframes: KeyframesStrategy::Critical,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // Should include the box class
+        assert!(stylesheet.contains(".box"));
+        // Should include the bounce keyframe (referenced by animation-name)
+        assert!(stylesheet.contains("@keyframes bounce"));
+        // Should NOT include unused spin keyframe
+        assert!(!stylesheet.contains("spin"));
+    }
+
+    #[test]
+    fn keyframes_critical_multiple_animations() {
+        let html = construct_html(
+            r#"<style>
+                .combo { animation: fadeIn 1s, slideIn 0.5s; }
+                @keyframes fadeIn {
+                    from { opacity: 0; }
+                    to { opacity: 1; }
+                }
+                @keyframes slideIn {
+                    from { transform: translateX(-100%); }
+                    to { transform: translateX(0); }
+                }
+                @keyframes slideOut {
+                    from { transform: translateX(0); }
+                    to { transform: translateX(100%); }
+                }
+            </style>"#,
+            r#"<div class="combo">Multiple animations</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            keyframes: KeyframesStrategy::Critical,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // Should include both used keyframes
+        assert!(stylesheet.contains("fadeIn"));
+        assert!(stylesheet.contains("slideIn"));
+        // Should NOT include unused keyframe
+        assert!(!stylesheet.contains("slideOut"));
+    }
+
+    #[test]
+    fn keyframes_critical_no_animations() {
+        let html = construct_html(
+            r#"<style>
+                .static { color: red; }
+                @keyframes unused {
+                    from { opacity: 0; }
+                    to { opacity: 1; }
+                }
+            </style>"#,
+            r#"<div class="static">No animations here</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            keyframes: KeyframesStrategy::Critical,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // Should include the static class
+        assert!(stylesheet.contains(".static"));
+        // Should NOT include unused keyframe
+        assert!(!stylesheet.contains("unused"));
+        assert!(!stylesheet.contains("@keyframes"));
+    }
+
+    #[test]
+    fn keyframes_critical_ignores_animation_keywords() {
+        let html = construct_html(
+            r#"<style>
+                .spinner { animation: spin 1s linear infinite, pulse 2s ease-in-out; }
+                @keyframes spin {
+                    to { transform: rotate(360deg); }
+                }
+                @keyframes pulse {
+                    50% { opacity: 0.5; }
+                }
+                @keyframes linear {
+                    to { opacity: 0; }
+                }
+            </style>"#,
+            r#"<div class="spinner">Loading</div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains("@keyframes spin"));
+        assert!(stylesheet.contains("@keyframes pulse"));
+        // `linear` is an easing keyword, not an animation name
+        assert!(!stylesheet.contains("@keyframes linear"));
+    }
+
+    #[test]
+    fn keyframes_critical_nested_media() {
+        let html = construct_html(
+            r#"<style>
+                .static { color: red; }
+                @media (prefers-reduced-motion: no-preference) {
+                    .static { animation: fadeIn 1s; }
+                }
+                @keyframes fadeIn {
+                    from { opacity: 0; }
+                }
+                @keyframes unused {
+                    from { opacity: 0; }
+                }
+            </style>"#,
+            r#"<div class="static">Hello</div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains("@keyframes fadeIn"));
+        assert!(!stylesheet.contains("@keyframes unused"));
+    }
+
+    #[test]
+    fn keyframes_critical_var_reference() {
+        let html = construct_html(
+            r#"<style>
+                :root { --enter: slideIn 1s ease-out; --slide-name: slideOut; }
+                .banner { animation: var(--enter); }
+                .toast { animation: var(--exit, var(--slide-name)) 1s; }
+                @keyframes slideIn {
+                    from { transform: translateX(-100%); }
+                }
+                @keyframes slideOut {
+                    to { transform: translateX(100%); }
+                }
+                @keyframes unused {
+                    from { opacity: 0; }
+                }
+            </style>"#,
+            r#"<div class="banner">Hello</div><div class="toast">World</div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains("@keyframes slideIn"));
+        assert!(stylesheet.contains("@keyframes slideOut"));
+        assert!(!stylesheet.contains("@keyframes unused"));
+    }
+
+    #[test]
+    fn keyframes_critical_var_ignores_animation_keywords() {
+        let html = construct_html(
+            r#"<style>
+                :root { --name: spin; }
+                .loader { animation: var(--name) 1s ease-in infinite alternate forwards; }
+                .fade { animation: var(--fade, fade) 1s steps(4, jump-end) paused; }
+                @keyframes spin {
+                    to { transform: rotate(360deg); }
+                }
+                @keyframes fade {
+                    to { opacity: 0; }
+                }
+                @keyframes ease-in {
+                    to { opacity: 0; }
+                }
+                @keyframes infinite {
+                    to { opacity: 0; }
+                }
+                @keyframes alternate {
+                    to { opacity: 0; }
+                }
+                @keyframes forwards {
+                    to { opacity: 0; }
+                }
+                @keyframes jump-end {
+                    to { opacity: 0; }
+                }
+                @keyframes paused {
+                    to { opacity: 0; }
+                }
+            </style>"#,
+            r#"<div class="loader">Loading</div><div class="fade">Fading</div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains("@keyframes spin"), "{stylesheet}");
+        assert!(stylesheet.contains("@keyframes fade"), "{stylesheet}");
+        // Keywords next to a `var()` are not animation names
+        for keyword in [
+            "ease-in",
+            "infinite",
+            "alternate",
+            "forwards",
+            "jump-end",
+            "paused",
+        ] {
+            assert!(
+                !stylesheet.contains(&format!("@keyframes {keyword}")),
+                "{stylesheet}"
+            );
+        }
+    }
+
+    #[test]
+    fn keyframes_critical_vendor_prefixed() {
+        let html = construct_html(
+            r#"<style>
+                .box { -webkit-animation: wobble 1s; }
+                @-webkit-keyframes wobble {
+                    50% { -webkit-transform: rotate(5deg); }
+                }
+                @-webkit-keyframes unused {
+                    from { opacity: 0; }
+                }
+            </style>"#,
+            r#"<div class="box">Hello</div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        assert!(stylesheet.contains("@-webkit-keyframes wobble"));
+        assert!(!stylesheet.contains("unused"));
+    }
+
+    #[test]
+    fn prune_custom_properties() {
+        let html = construct_html(
+            r#"<style>
+                :root {
+                    --color-brand: #f00;
+                    --color-accent: var(--color-brand);
+                    --color-unused: #0f0;
+                    --space-sm: 4px;
+                    --space-lg: 32px;
+                    --radius: 2px;
+                }
+                @property --angle {
+                    syntax: "<angle>";
+                    inherits: false;
+                    initial-value: 0deg;
+                }
+                @property --unused-angle {
+                    syntax: "<angle>";
+                    inherits: false;
+                    initial-value: 0deg;
+                }
+                .unused { --only-unused: 1px; border-radius: var(--radius); }
+                .card { color: var(--color-accent); transform: rotate(var(--angle)); }
+            </style>"#,
+            r#"<div class="card" style="padding: var(--space-sm)">Hello</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            prune_custom_properties: true,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let stylesheet = dom.select_first("style").unwrap().text_contents();
+
+        // transitively referenced
+        assert!(stylesheet.contains("--color-brand"), "{stylesheet}");
+        assert!(stylesheet.contains("--color-accent"), "{stylesheet}");
+        // referenced by an inline style attribute
+        assert!(stylesheet.contains("--space-sm"), "{stylesheet}");
+        assert!(stylesheet.contains("@property --angle"), "{stylesheet}");
+
+        assert!(!stylesheet.contains("--color-unused"), "{stylesheet}");
+        assert!(!stylesheet.contains("--space-lg"), "{stylesheet}");
+        assert!(!stylesheet.contains("--radius"), "{stylesheet}");
+        assert!(!stylesheet.contains("--unused-angle"), "{stylesheet}");
+    }
+
+    #[test]
+    fn prune_custom_properties_across_stylesheets() {
+        let html = construct_html(
+            r#"<style>
+                :root { --tokens-only: 1px; --gap: 8px; }
+            </style>
+            <style>
+                .grid { gap: var(--gap); }
+            </style>"#,
+            r#"<div class="grid">Hello</div>"#,
+        );
+
+        let critters = Critters::new(CrittersOptions {
+            prune_custom_properties: true,
+            merge_stylesheets: false,
+            ..Default::default()
+        });
+
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        let tokens = dom.select_first("style").unwrap().text_contents();
+
+        assert!(tokens.contains("--gap"), "{tokens}");
+        assert!(!tokens.contains("--tokens-only"), "{tokens}");
+    }
+
+    #[test]
+    fn prune_custom_properties_keeps_references_from_skipped_stylesheets() {
+        let html = construct_html(
+            r#"<style>
+                :root { --tokens-only: 1px; --gap: 8px; }
+            </style>
+            <style>
+                .grid:::invalid { gap: var(--gap); }
             </style>"#,
-            r#"<div class="animated">Hello World</div>"#,
+            r#"<div class="grid">Hello</div>"#,
         );
 
         let critters = Critters::new(CrittersOptions {
-            keyframes: KeyframesStrategy::None,
+            prune_custom_properties: true,
+            merge_stylesheets: false,
             ..Default::default()
         });
 
-        let processed = critters.process(&html).unwrap();
+        let (processed, report) = critters.process_with_report(&html).unwrap();
+        assert_eq!(report.stylesheets[1].action, StylesheetAction::Skipped);
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
-        let stylesheet = dom.select_first("style").unwrap().text_contents();
+        let tokens = dom.select_first("style").unwrap().text_contents();
 
-        // Should include the animated class (but the animation property remains)
-        assert!(stylesheet.contains(".animated"));
-        // Should NOT include the unused class
-        assert!(!stylesheet.contains(".not-animated"));
-        // Should NOT include any keyframes definitions
-        assert!(!stylesheet.contains("@keyframes"));
-        assert!(!stylesheet.contains("slideOut"));
+        assert!(tokens.contains("--gap"), "{tokens}");
+        assert!(!tokens.contains("--tokens-only"), "{tokens}");
     }
 
     #[test]
-    fn keyframes_critical_with_animation_property() {
+    fn prune_custom_properties_disabled() {
         let html = construct_html(
             r#"<style>
-                .box { animation-name: bounce; }
-                .other { color: red; }
-                @keyframes bounce {
-                    0%, 100% { transform: translateY(0); }
-                    50% { transform: translateY(-20px); }
-                }
-                @keyframes spin {
-                    from { transform: rotate(0deg); }
-                    to { transform: rotate(360deg); }
-                }
+                :root { --unused: 1px; }
             </style>"#,
-            r#"<div class="box">Bouncing Box</div>"#,
+            r#"<div>Hello</div>"#,
         );
 
-        let critters = Critters::new(CrittersOptions {
-            keyframes: KeyframesStrategy::Critical,
-            ..Default::default()
-        });
+        let critters = Critters::new(Default::default());
 
         let processed = critters.process(&html).unwrap();
 
@@ -1647,82 +3915,168 @@ mod tests {
         let dom = parser.one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Should include the box class
-        assert!(stylesheet.contains(".box"));
-        // Should include the bounce keyframe (referenced by animation-name)
-        assert!(stylesheet.contains("@keyframes bounce"));
-        // Should NOT include unused spin keyframe
-        assert!(!stylesheet.contains("spin"));
+        assert!(stylesheet.contains("--unused"));
     }
 
-    #[test]
-    fn keyframes_critical_multiple_animations() {
+    const AT_RULES_CSS: &str = r#"
+        @property --angle { syntax: "<angle>"; inherits: false; initial-value: 0deg; }
+        @property --unused-angle { syntax: "<angle>"; inherits: false; initial-value: 0deg; }
+        @counter-style thumbs { system: cyclic; symbols: "👍"; suffix: " "; fallback: stars; }
+        @counter-style stars { system: cyclic; symbols: "*"; }
+        @counter-style unused-style { system: cyclic; symbols: "-"; }
+        @font-feature-values Font One { @styleset { nice-style: 12; } }
+        @font-feature-values Font { @styleset { nice-style: 2; } }
+        @font-feature-values Unused Font { @styleset { nice-style: 4; } }
+        @font-palette-values --identifier { font-family: Bixa; override-colors: 0 red; }
+        @font-palette-values --unused-palette { font-family: Bixa; override-colors: 0 blue; }
+        @page { margin: 1cm; }
+        @view-transition { navigation: auto; }
+        .list { list-style: thumbs; font-family: Font One; font-palette: --identifier; }
+        .spinner { transform: rotate(var(--angle)); }
+    "#;
+
+    fn process_at_rules(options: CrittersOptions) -> String {
         let html = construct_html(
-            r#"<style>
-                .combo { animation: fadeIn 1s, slideIn 0.5s; }
-                @keyframes fadeIn {
-                    from { opacity: 0; }
-                    to { opacity: 1; }
-                }
-                @keyframes slideIn {
-                    from { transform: translateX(-100%); }
-                    to { transform: translateX(0); }
-                }
-                @keyframes slideOut {
-                    from { transform: translateX(0); }
-                    to { transform: translateX(100%); }
-                }
-            </style>"#,
-            r#"<div class="combo">Multiple animations</div>"#,
+            &format!("<style>{AT_RULES_CSS}</style>"),
+            r#"<ul class="list"><li>One</li></ul><div class="spinner"></div>"#,
         );
 
-        let critters = Critters::new(CrittersOptions {
-            keyframes: KeyframesStrategy::Critical,
+        let critters = Critters::new(options);
+        let processed = critters.process(&html).unwrap();
+
+        let parser = html::parse_html();
+        let dom = parser.one(processed);
+        dom.select_first("style").unwrap().text_contents()
+    }
+
+    #[test]
+    fn at_rules_critical() {
+        let stylesheet = process_at_rules(Default::default());
+
+        assert!(stylesheet.contains("@property --angle"), "{stylesheet}");
+        assert!(!stylesheet.contains("--unused-angle"), "{stylesheet}");
+
+        assert!(stylesheet.contains("@counter-style thumbs"), "{stylesheet}");
+        // referenced as the fallback of a critical counter style
+        assert!(stylesheet.contains("@counter-style stars"), "{stylesheet}");
+        assert!(!stylesheet.contains("unused-style"), "{stylesheet}");
+
+        assert!(
+            stylesheet.contains("@font-feature-values Font One"),
+            "{stylesheet}"
+        );
+        assert!(!stylesheet.contains("Unused Font"), "{stylesheet}");
+        // family names are matched exactly, rather than as part of other names
+        assert!(
+            !stylesheet.contains("@font-feature-values Font{"),
+            "{stylesheet}"
+        );
+
+        assert!(
+            stylesheet.contains("@font-palette-values --identifier"),
+            "{stylesheet}"
+        );
+        assert!(!stylesheet.contains("--unused-palette"), "{stylesheet}");
+
+        assert!(!stylesheet.contains("@page"), "{stylesheet}");
+        assert!(stylesheet.contains("@view-transition"), "{stylesheet}");
+    }
+
+    #[test]
+    fn at_rules_all() {
+        let stylesheet = process_at_rules(CrittersOptions {
+            property_rules: AtRuleStrategy::All,
+            counter_style_rules: AtRuleStrategy::All,
+            font_feature_values_rules: AtRuleStrategy::All,
+            font_palette_values_rules: AtRuleStrategy::All,
+            page_rules: AtRuleStrategy::All,
+            view_transition_rules: AtRuleStrategy::All,
+            ..Default::default()
+        });
+
+        assert!(stylesheet.contains("--unused-angle"), "{stylesheet}");
+        assert!(stylesheet.contains("unused-style"), "{stylesheet}");
+        assert!(stylesheet.contains("Unused Font"), "{stylesheet}");
+        assert!(stylesheet.contains("--unused-palette"), "{stylesheet}");
+        assert!(stylesheet.contains("@page"), "{stylesheet}");
+        assert!(stylesheet.contains("@view-transition"), "{stylesheet}");
+    }
+
+    #[test]
+    fn at_rules_none() {
+        let stylesheet = process_at_rules(CrittersOptions {
+            property_rules: AtRuleStrategy::None,
+            counter_style_rules: AtRuleStrategy::None,
+            font_feature_values_rules: AtRuleStrategy::None,
+            font_palette_values_rules: AtRuleStrategy::None,
+            page_rules: AtRuleStrategy::None,
+            view_transition_rules: AtRuleStrategy::None,
             ..Default::default()
         });
 
+        assert!(!stylesheet.contains("@property"), "{stylesheet}");
+        assert!(!stylesheet.contains("@counter-style"), "{stylesheet}");
+        assert!(!stylesheet.contains("@font-feature-values"), "{stylesheet}");
+        assert!(!stylesheet.contains("@font-palette-values"), "{stylesheet}");
+        assert!(!stylesheet.contains("@page"), "{stylesheet}");
+        assert!(!stylesheet.contains("@view-transition"), "{stylesheet}");
+    }
+
+    #[test]
+    fn font_feature_values_from_font_shorthand() {
+        let html = construct_html(
+            r#"<style>
+                @font-feature-values Foo { @styleset { nice-style: 1; } }
+                @font-feature-values Bar Baz { @styleset { nice-style: 2; } }
+                @font-feature-values Unused { @styleset { nice-style: 3; } }
+                .a { font: 16px Foo; }
+                .b { font: bold var(--size)/1.2 "Bar Baz", serif; }
+            </style>"#,
+            r#"<div class="a"></div><div class="b"></div>"#,
+        );
+
+        let critters = Critters::new(Default::default());
         let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Should include both used keyframes
-        assert!(stylesheet.contains("fadeIn"));
-        assert!(stylesheet.contains("slideIn"));
-        // Should NOT include unused keyframe
-        assert!(!stylesheet.contains("slideOut"));
+        assert!(
+            stylesheet.contains("@font-feature-values Foo"),
+            "{stylesheet}"
+        );
+        assert!(
+            stylesheet.contains("@font-feature-values Bar Baz"),
+            "{stylesheet}"
+        );
+        assert!(!stylesheet.contains("Unused"), "{stylesheet}");
     }
 
     #[test]
-    fn keyframes_critical_no_animations() {
+    fn counter_style_from_counter_function() {
         let html = construct_html(
             r#"<style>
-                .static { color: red; }
-                @keyframes unused {
-                    from { opacity: 0; }
-                    to { opacity: 1; }
-                }
+                @counter-style roman-ish { system: additive; additive-symbols: 5 V, 1 I; }
+                @counter-style unused-style { system: cyclic; symbols: "-"; }
+                h2 { counter-increment: section; }
+                h2 .num { content: counter(section, roman-ish) ". "; }
             </style>"#,
-            r#"<div class="static">No animations here</div>"#,
+            r#"<h2><span class="num"></span>Heading</h2>"#,
         );
 
-        let critters = Critters::new(CrittersOptions {
-            keyframes: KeyframesStrategy::Critical,
-            ..Default::default()
-        });
-
+        let critters = Critters::new(Default::default());
         let processed = critters.process(&html).unwrap();
 
         let parser = html::parse_html();
         let dom = parser.one(processed);
         let stylesheet = dom.select_first("style").unwrap().text_contents();
 
-        // Should include the static class
-        assert!(stylesheet.contains(".static"));
-        // Should NOT include unused keyframe
-        assert!(!stylesheet.contains("unused"));
-        assert!(!stylesheet.contains("@keyframes"));
+        assert!(
+            stylesheet.contains("@counter-style roman-ish"),
+            "{stylesheet}"
+        );
+        assert!(!stylesheet.contains("unused-style"), "{stylesheet}");
     }
 
     #[test]
@@ -1989,4 +4343,451 @@ mod tests {
             Some("large.css")
         );
     }
+
+    #[test]
+    #[cfg(feature = "directory")]
+    fn overrides() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        fs::create_dir(tmp_dir.path().join("blog")).unwrap();
+        File::create(tmp_dir.path().join("shared.css"))
+            .unwrap()
+            .write_all(BASIC_CSS.as_bytes())
+            .unwrap();
+        let html = construct_html(
+            r#"<link rel="stylesheet" href="/shared.css">"#,
+            r#"<div class="critical"></div>"#,
+        );
+        for page in ["index.html", "blog/post.html"] {
+            fs::write(tmp_dir.path().join(page), &html).unwrap();
+        }
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            external: false,
+            ..Default::default()
+        });
+        let options = |overrides: serde_json::Value| DirectoryOptions {
+            overrides: serde_json::from_value(overrides).unwrap(),
+            ..Default::default()
+        };
+
+        let stats = critters
+            .process_dir_impl(
+                &options(serde_json::json!([
+                    { "match": "*.html", "options": { "pruneSource": true } },
+                    { "match": "blog/**", "options": { "external": true } },
+                ])),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(stats.pages_skipped, 1);
+        assert_eq!(stats.stylesheets.len(), 1);
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("index.html")).unwrap(),
+            html
+        );
+        assert_ne!(
+            fs::read_to_string(tmp_dir.path().join("blog/post.html")).unwrap(),
+            html
+        );
+
+        let invalid = |overrides: serde_json::Value| {
+            critters
+                .process_dir_impl(&options(overrides), None, None)
+                .unwrap_err()
+        };
+        assert_eq!(
+            invalid(serde_json::json!([{ "match": "[", "options": {} }])).code(),
+            "INVALID_OPTIONS"
+        );
+        assert_eq!(
+            invalid(serde_json::json!([
+                { "match": "**", "options": { "inlineThreshold": "none" } }
+            ]))
+            .code(),
+            "INVALID_OPTIONS"
+        );
+        // Options of the directory do not apply to single pages
+        assert_eq!(
+            invalid(serde_json::json!([
+                { "match": "**", "options": { "outputDir": "out" } }
+            ]))
+            .code(),
+            "INVALID_OPTIONS"
+        );
+    }
+
+    #[test]
+    fn options_accept_command_line_variants() {
+        // Configuration files use the same values as the command line
+        let options: CrittersOptions = serde_json::from_value(serde_json::json!({
+            "preload": "swap-high",
+            "keyframes": "all",
+            "propertyRules": "none",
+        }))
+        .unwrap();
+        assert!(matches!(options.preload, PreloadStrategy::SwapHigh));
+        assert!(matches!(options.keyframes, KeyframesStrategy::All));
+        assert!(matches!(options.property_rules, AtRuleStrategy::None));
+
+        let options: CrittersOptions =
+            serde_json::from_value(serde_json::json!({ "preload": "SwapHigh" })).unwrap();
+        assert!(matches!(options.preload, PreloadStrategy::SwapHigh));
+    }
+
+    #[test]
+    #[cfg(feature = "directory")]
+    fn overrides_locked_options() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        fs::create_dir(tmp_dir.path().join("blog")).unwrap();
+        fs::write(tmp_dir.path().join("shared.css"), BASIC_CSS).unwrap();
+        let html = construct_html(
+            r#"<link rel="stylesheet" href="/shared.css">"#,
+            r#"<div class="critical"></div>"#,
+        );
+        fs::write(tmp_dir.path().join("blog/post.html"), &html).unwrap();
+
+        // Such as `--preload none` given on the command line, over a configuration file
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            preload: PreloadStrategy::None,
+            ..Default::default()
+        });
+        let options = DirectoryOptions {
+            overrides: serde_json::from_value(serde_json::json!([
+                { "match": "blog/**", "options": { "preload": "Media", "compress": false } }
+            ]))
+            .unwrap(),
+            locked_options: vec!["preload".to_string()],
+            ..Default::default()
+        };
+        critters.process_dir_impl(&options, None, None).unwrap();
+
+        let processed = fs::read_to_string(tmp_dir.path().join("blog/post.html")).unwrap();
+        assert!(!processed.contains("media="), "{processed}");
+        // Other options of the override still apply
+        assert!(processed.contains(".critical {"), "{processed}");
+    }
+
+    #[test]
+    #[cfg(feature = "directory")]
+    fn overrides_keep_regex_matchers() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        fs::write(tmp_dir.path().join("index.html"), BASIC_HTML).unwrap();
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            allow_rules: vec![Matcher::Regex(Regex::new("^.non").unwrap())],
+            ..Default::default()
+        });
+        let options = DirectoryOptions {
+            overrides: serde_json::from_value(serde_json::json!([
+                { "match": "**", "options": { "compress": false } }
+            ]))
+            .unwrap(),
+            ..Default::default()
+        };
+        critters.process_dir_impl(&options, None, None).unwrap();
+
+        let processed = fs::read_to_string(tmp_dir.path().join("index.html")).unwrap();
+        assert!(processed.contains(".non-critical"));
+    }
+
+    #[test]
+    #[cfg(feature = "directory")]
+    fn output_dir() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        let input_dir = tmp_dir.path().join("src");
+        fs::create_dir_all(input_dir.join("blog")).unwrap();
+        let html = construct_html(
+            &format!("<style>{BASIC_CSS}</style>"),
+            r#"<div class="critical"></div>"#,
+        );
+        let plain = construct_html("", "");
+        fs::write(input_dir.join("blog/post.html"), &html).unwrap();
+        fs::write(input_dir.join("plain.html"), &plain).unwrap();
+
+        let process = |path: &path::Path, output_dir: &path::Path| {
+            let options = DirectoryOptions {
+                output_dir: Some(output_dir.to_str().unwrap().to_string()),
+                ..Default::default()
+            };
+            Critters::new(CrittersOptions {
+                path: path.to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+            .process_dir_impl(&options, None, None)
+            .unwrap()
+        };
+        let read = |path: path::PathBuf| fs::read_to_string(path).unwrap();
+
+        // Missing directories are created, and pages which are not processed are copied as is
+        let output_dir = input_dir.join("out/dist");
+        let stats = process(&input_dir, &output_dir);
+        assert_eq!((stats.pages_succeeded, stats.pages_skipped), (1, 1));
+        assert!(!read(output_dir.join("blog/post.html")).contains(".non-critical"));
+        assert_eq!(read(output_dir.join("plain.html")), plain);
+        assert_eq!(read(input_dir.join("blog/post.html")), html);
+
+        // The output of previous runs within the input directory is not processed again
+        assert_eq!(process(&input_dir, &output_dir).pages, 2);
+
+        // The input directory may be within the output directory
+        let stats = process(&input_dir, tmp_dir.path());
+        assert_eq!(stats.pages, 4);
+        assert!(!read(tmp_dir.path().join("blog/post.html")).contains(".non-critical"));
+        assert_eq!(read(input_dir.join("blog/post.html")), html);
+
+        // An output directory equal to the input directory processes pages in place
+        let stats = process(&input_dir, &input_dir);
+        assert_eq!(stats.pages, 4);
+        assert!(!read(input_dir.join("blog/post.html")).contains(".non-critical"));
+    }
+
+    #[test]
+    #[cfg(all(feature = "directory", unix))]
+    fn unreadable_pages() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        fs::write(tmp_dir.path().join("binary.html"), b"<html>\xff</html>").unwrap();
+        fs::write(tmp_dir.path().join("index.html"), BASIC_HTML).unwrap();
+        std::os::unix::fs::symlink("missing.html", tmp_dir.path().join("broken.html")).unwrap();
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            strict: true,
+            ..Default::default()
+        });
+        let options = DirectoryOptions {
+            follow_links: false,
+            ..Default::default()
+        };
+        let result = critters.process_dir_impl(&options, None, None);
+
+        // Pages which are not valid UTF-8 are skipped, and left untouched
+        assert_eq!(
+            fs::read(tmp_dir.path().join("binary.html")).unwrap(),
+            b"<html>\xff</html>"
+        );
+        // Pages which cannot be read fail, without preventing others from being processed
+        let Err(CrittersError::Strict(problems)) = result else {
+            panic!("Expected a strict mode error, got {result:?}");
+        };
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("broken.html: Failed to access"));
+
+        // The statistics remain available for reports when failing in strict mode
+        let stats = critters.process_dir_pages(&options, None, None).unwrap();
+        assert!(matches!(
+            critters.check_strict_dir(&stats),
+            Err(CrittersError::Strict(p)) if p == problems
+        ));
+        let page = |path: &str| {
+            stats
+                .page_reports
+                .iter()
+                .find(|p| p.path == path)
+                .unwrap()
+                .clone()
+        };
+        let binary = page("binary.html");
+        assert_eq!(binary.status, PageStatus::Skipped);
+        assert_eq!(
+            binary.error.as_deref(),
+            Some("The file is not valid UTF-8.")
+        );
+        assert_eq!(page("index.html").status, PageStatus::Succeeded);
+        let broken = page("broken.html");
+        assert_eq!(broken.status, PageStatus::Failed);
+        assert!(broken.error.unwrap().starts_with("Failed to access"));
+    }
+
+    #[test]
+    #[cfg(all(feature = "directory", unix))]
+    fn write_atomic() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        let file = tmp_dir.path().join("index.html");
+        fs::write(&file, "before").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        let permissions = fs::metadata(&file).unwrap().permissions();
+
+        utils::write_atomic(&file, "after", Some(permissions.clone())).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "after");
+        let mode = |file: &path::Path| fs::metadata(file).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&file), 0o640);
+        // No temporary file is left behind
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+
+        // New files take the permissions of their source
+        let nested = tmp_dir.path().join("missing/nested/index.html");
+        utils::write_atomic(&nested, "nested", Some(permissions)).unwrap();
+        assert_eq!(fs::read_to_string(&nested).unwrap(), "nested");
+        assert_eq!(mode(&nested), 0o640);
+
+        // or those of a newly created file without a source
+        let created = tmp_dir.path().join("created.html");
+        fs::write(&created, "").unwrap();
+        let written = tmp_dir.path().join("written.html");
+        utils::write_atomic(&written, "written", None).unwrap();
+        assert_eq!(mode(&written), mode(&created));
+    }
+
+    #[test]
+    #[cfg(all(feature = "directory", unix))]
+    fn output_dir_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        let input_dir = tmp_dir.path().join("src");
+        fs::create_dir_all(input_dir.join("sub")).unwrap();
+        let page = input_dir.join("sub/a.html");
+        fs::write(&page, BASIC_HTML).unwrap();
+        fs::set_permissions(&page, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let critters = Critters::new(CrittersOptions {
+            path: input_dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let options = DirectoryOptions {
+            output_dir: Some(tmp_dir.path().join("out").to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        critters.process_dir_impl(&options, None, None).unwrap();
+
+        let output = tmp_dir.path().join("out/sub/a.html");
+        let mode = fs::metadata(output).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
+    #[test]
+    #[cfg(feature = "directory")]
+    fn discovery() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        for dir in ["blog", "node_modules/pkg", "drafts"] {
+            fs::create_dir_all(tmp_dir.path().join(dir)).unwrap();
+        }
+        for file in [
+            "index.html",
+            "about.htm",
+            "style.css",
+            "blog/post.html",
+            "blog/post.XHTML",
+            "node_modules/pkg/fixture.html",
+            "drafts/draft.html",
+        ] {
+            fs::write(tmp_dir.path().join(file), BASIC_HTML).unwrap();
+        }
+        fs::write(tmp_dir.path().join(".gitignore"), "drafts/\n").unwrap();
+
+        let path = tmp_dir.path().to_str().unwrap();
+        let locate = |options: DirectoryOptions| {
+            let (files, report) = utils::locate_html_files(path, &options).unwrap();
+            let files = files
+                .iter()
+                .map(|f| {
+                    f.strip_prefix(tmp_dir.path())
+                        .unwrap()
+                        .display()
+                        .to_string()
+                })
+                .sorted()
+                .collect_vec();
+            (files, report)
+        };
+
+        let (files, report) = locate(Default::default());
+        assert_eq!(
+            files,
+            [
+                "blog/post.html",
+                "drafts/draft.html",
+                "index.html",
+                "node_modules/pkg/fixture.html"
+            ]
+        );
+        assert_eq!(report.files, 4);
+
+        let (files, report) = locate(DirectoryOptions {
+            exclude: vec!["**/node_modules/**".to_string(), "*.html".to_string()],
+            extensions: vec!["html".to_string(), ".htm".to_string(), "xhtml".to_string()],
+            respect_gitignore: true,
+            ..Default::default()
+        });
+        assert_eq!(files, ["about.htm", "blog/post.XHTML", "blog/post.html"]);
+        assert_eq!(report.files, 5);
+        assert_eq!(
+            report.excluded.iter().map(|e| e.files).collect_vec(),
+            [1, 1]
+        );
+
+        let (files, report) = locate(DirectoryOptions {
+            include: vec!["blog/**".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(files, ["blog/post.html"]);
+        assert_eq!(report.not_included, 3);
+
+        let invalid = utils::locate_html_files(
+            path,
+            &DirectoryOptions {
+                include: vec!["[".to_string()],
+                ..Default::default()
+            },
+        );
+        assert_eq!(invalid.unwrap_err().code(), "INVALID_OPTIONS");
+    }
+
+    #[test]
+    #[cfg(feature = "directory")]
+    fn dry_run() {
+        let tmp_dir = TempDir::new("critters-test").unwrap();
+        let output_dir = tmp_dir.path().join("out");
+        let html = construct_html(
+            &format!("<style>{BASIC_CSS}</style>"),
+            r#"<div class="critical"></div>"#,
+        );
+        fs::write(tmp_dir.path().join("index.html"), &html).unwrap();
+        fs::write(tmp_dir.path().join("empty.html"), construct_html("", "")).unwrap();
+
+        let critters = Critters::new(CrittersOptions {
+            path: tmp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let options = DirectoryOptions {
+            output_dir: Some(output_dir.to_str().unwrap().to_string()),
+            dry_run: true,
+            ..Default::default()
+        };
+        let pages = std::sync::Mutex::new(Vec::new());
+        let stats = critters
+            .process_dir_impl(
+                &options,
+                None,
+                Some(&|path, original, processed| {
+                    pages.lock().unwrap().push((
+                        path.to_string(),
+                        original.to_string(),
+                        processed.to_string(),
+                    ));
+                }),
+            )
+            .unwrap();
+
+        assert_eq!((stats.pages_succeeded, stats.pages_skipped), (1, 1));
+        assert!(!output_dir.exists());
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("index.html")).unwrap(),
+            html
+        );
+
+        // Only modified pages are reported, along with their HTML before and after processing
+        let pages = pages.into_inner().unwrap();
+        assert_eq!(pages.len(), 1);
+        let (path, original, processed) = &pages[0];
+        assert_eq!(path, "index.html");
+        assert!(original.contains(".non-critical"));
+        assert!(!processed.contains(".non-critical"));
+    }
 }
@@ -1,17 +1,101 @@
-use clap::{command, Parser};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use indicatif::MultiProgress;
 
-use critters_rs::{Critters, CrittersOptions};
+use critters_rs::{
+    Critters, CrittersDirectoryStats, CrittersOptions, DirectoryOptions, PageCallback, PageStatus,
+};
 use indicatif_log_bridge::LogWrapper;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The configuration files loaded from the current directory when `--config` is not given.
+const CONFIG_FILES: [&str; 2] = ["critters.config.json", "critters.config.toml"];
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Options of the `dir` command, which runs when no command is given.
+    #[command(flatten)]
+    dir: DirArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Process all HTML files in a directory. This is the default command.
+    Dir(DirArgs),
+    /// Process a single HTML file, or HTML read from standard input.
+    Process(ProcessArgs),
+}
+
+#[derive(Args, Debug)]
+struct DirArgs {
+    /// Load options from the given JSON or TOML file, instead of `critters.config.json` or
+    /// `critters.config.toml` in the current directory. Options given on the command line take
+    /// precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Write the statistics of the run, including per-page reports, as JSON to the given file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Print a unified diff of each modified page, or only of its `<head>`, to standard output.
+    /// The summary is printed to standard error instead. Combine with `--dry-run` to preview
+    /// the changes without writing them.
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "full")]
+    diff: Option<DiffMode>,
+
+    /// Options of the directory.
+    #[command(flatten)]
+    directory: DirectoryOptions,
+
     /// Critters options.
     #[command(flatten)]
     options: CrittersOptions,
 }
 
+/// The part of the pages included in diffs.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DiffMode {
+    /// The whole document.
+    Full,
+    /// Only the `<head>` element, where critical CSS is inlined.
+    Head,
+}
+
+#[derive(Args, Debug)]
+struct ProcessArgs {
+    /// The HTML file to process, or `-` to read from standard input.
+    input: PathBuf,
+
+    /// Write the processed HTML to the given file instead of standard output.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Load options from the given JSON or TOML file, instead of `critters.config.json` or
+    /// `critters.config.toml` in the current directory. Options given on the command line take
+    /// precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Write the report of the processing as JSON to the given file.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Critters options. Stylesheets are resolved from the directory of the input file, unless
+    /// `--path` is given.
+    #[command(flatten)]
+    options: CrittersOptions,
+}
+
 fn main() -> anyhow::Result<()> {
     let logger = env_logger::Builder::from_env(
         env_logger::Env::default().default_filter_or("critters_rs=warn"),
@@ -20,14 +104,356 @@ fn main() -> anyhow::Result<()> {
     let multi = MultiProgress::new();
     LogWrapper::new(multi.clone(), logger).try_init().unwrap();
 
-    let args = Args::parse();
+    // The matches are kept to tell options given on the command line from defaults
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let command_matches = matches.subcommand().map_or(&matches, |(_, m)| m);
+
+    match cli.command {
+        Some(Command::Process(args)) => process(args, command_matches),
+        Some(Command::Dir(args)) => dir(args, command_matches, &multi),
+        None => dir(cli.dir, &matches, &multi),
+    }
+}
+
+/// Processes all HTML files in the directory given by `--path`.
+fn dir(mut args: DirArgs, matches: &ArgMatches, multi: &MultiProgress) -> anyhow::Result<()> {
+    let config = read_config(args.config.as_deref())?;
+    args.options = load_options(config.as_ref(), args.options, matches)?;
+    args.directory = load_options(config.as_ref(), args.directory, matches)?;
+    // Options given on the command line also take precedence over the overrides of the file
+    args.directory.locked_options = command_line_options(matches).collect();
+    if args.options.path.is_empty() {
+        args.options.path = ".".to_string();
+    }
 
+    let dry_run = args.directory.dry_run;
     let critters = Critters::new(args.options);
-    let stats = critters.process_dir(Some(&multi))?;
+    let diffs = Mutex::new(Vec::new());
+    let on_page = |path: &str, original: &str, processed: &str| {
+        if let Some(diff) = args
+            .diff
+            .and_then(|mode| unified_diff(mode, path, original, processed))
+        {
+            diffs.lock().unwrap().push((path.to_string(), diff));
+        }
+    };
+    // Pages failing in strict mode fail the run only once the report is written
+    let stats = critters.process_dir_with(
+        &args.directory,
+        Some(multi),
+        args.diff.map(|_| &on_page as &PageCallback),
+    )?;
 
-    println!(
-        "\x1b[0;32m✓ Processed {} pages in {:.2}s.\x1b[0m",
-        stats.pages, stats.time_sec
-    );
+    let mut diffs = diffs.into_inner().unwrap();
+    diffs.sort();
+    for (_, diff) in diffs {
+        print!("{diff}");
+    }
+
+    // Keep standard output a valid patch when printing diffs
+    let mut out: Box<dyn Write> = match args.diff {
+        Some(_) => Box::new(std::io::stderr()),
+        None => Box::new(std::io::stdout()),
+    };
+    if dry_run {
+        writeln!(
+            out,
+            "\x1b[0;32m✓ Processed {} pages in {:.2}s, without writing them.\x1b[0m",
+            stats.pages, stats.time_sec
+        )?;
+        print_pages(&mut out, &stats)?;
+    } else {
+        writeln!(
+            out,
+            "\x1b[0;32m✓ Processed {} pages in {:.2}s.\x1b[0m",
+            stats.pages, stats.time_sec
+        )?;
+    }
+    print_summary(&mut out, &stats)?;
+
+    if let Some(path) = args.report {
+        std::fs::write(&path, serde_json::to_string_pretty(&stats)?)?;
+        writeln!(out, "Report written to {}.", path.display())?;
+    }
+    critters.check_strict_dir(&stats)?;
     Ok(())
 }
+
+/// Processes a single HTML file, or standard input when the input is `-`.
+fn process(mut args: ProcessArgs, matches: &ArgMatches) -> anyhow::Result<()> {
+    let config = read_config(args.config.as_deref())?;
+    args.options = load_options(config.as_ref(), args.options, matches)?;
+    let stdin = args.input == Path::new("-");
+    if args.options.path.is_empty() {
+        args.options.path = match args.input.parent() {
+            Some(dir) if !stdin && !dir.as_os_str().is_empty() => dir.display().to_string(),
+            _ => ".".to_string(),
+        };
+    }
+
+    let html = if stdin {
+        let mut html = String::new();
+        std::io::stdin()
+            .read_to_string(&mut html)
+            .context("Failed to read standard input")?;
+        html
+    } else {
+        std::fs::read_to_string(&args.input)
+            .with_context(|| format!("Failed to read {}", args.input.display()))?
+    };
+
+    let critters = Critters::new(args.options);
+    let (processed, report) = critters.process_with_report(&html)?;
+
+    match args.output {
+        Some(path) => std::fs::write(&path, processed)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => std::io::stdout().write_all(processed.as_bytes())?,
+    }
+    if let Some(path) = args.report {
+        std::fs::write(&path, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The options of a configuration file, by their name in the file.
+struct Config {
+    path: PathBuf,
+    options: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Reads the given configuration file, or the one in the current directory, if any.
+fn read_config(config: Option<&Path>) -> anyhow::Result<Option<Config>> {
+    let path = match config {
+        Some(config) => config.to_path_buf(),
+        None => match CONFIG_FILES.iter().map(PathBuf::from).find(|f| f.is_file()) {
+            Some(config) => config,
+            None => return Ok(None),
+        },
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let options: serde_json::Map<String, serde_json::Value> =
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?
+        } else {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?
+        };
+
+    // Unknown options, such as misspelled ones, would otherwise be silently ignored
+    let known = [
+        serde_json::to_value(CrittersOptions::default())?,
+        serde_json::to_value(DirectoryOptions::default())?,
+    ];
+    if let Some(key) = options
+        .keys()
+        .find(|key| known.iter().all(|options| options.get(key).is_none()))
+    {
+        anyhow::bail!("Unknown option \"{key}\" in {}", path.display());
+    }
+
+    Ok(Some(Config { path, options }))
+}
+
+/// Loads the options from the configuration file, if any, and applies the options given on the
+/// command line over them. Options which do not belong to `T` are ignored.
+fn load_options<T: Serialize + DeserializeOwned>(
+    config: Option<&Config>,
+    options: T,
+    matches: &ArgMatches,
+) -> anyhow::Result<T> {
+    let Some(config) = config else {
+        return Ok(options);
+    };
+
+    let mut merged = config.options.clone();
+    let serde_json::Value::Object(cli) = serde_json::to_value(&options)? else {
+        unreachable!("options serialize to an object");
+    };
+    for key in command_line_options(matches) {
+        if let Some(value) = cli.get(&key) {
+            merged.insert(key, value.clone());
+        }
+    }
+
+    serde_json::from_value(merged.into())
+        .with_context(|| format!("Invalid options in {}", config.path.display()))
+}
+
+/// The names in configuration files of the options given on the command line.
+fn command_line_options(matches: &ArgMatches) -> impl Iterator<Item = String> + '_ {
+    matches
+        .ids()
+        .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+        .map(|id| to_camel_case(id.as_str()))
+}
+
+/// Converts the id of an argument, which is the name of its field, to the name of the option in
+/// configuration files.
+fn to_camel_case(id: &str) -> String {
+    let mut words = id.split('_');
+    let mut key = words.next().unwrap_or_default().to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            key.push(first.to_ascii_uppercase());
+            key.extend(chars);
+        }
+    }
+    key
+}
+
+/// The unified diff of a page, or of its `<head>` element, unless it is unchanged.
+fn unified_diff(mode: DiffMode, path: &str, original: &str, processed: &str) -> Option<String> {
+    let (original, processed) = match mode {
+        DiffMode::Full => (original, processed),
+        DiffMode::Head => (head(original), head(processed)),
+    };
+    if original == processed {
+        return None;
+    }
+
+    let diff = similar::TextDiff::from_lines(original, processed)
+        .unified_diff()
+        // The `<head>` is never at the end of the file
+        .missing_newline_hint(matches!(mode, DiffMode::Full))
+        .header(&format!("a/{path}"), &format!("b/{path}"))
+        .to_string();
+    Some(diff)
+}
+
+/// The `<head>` element of a serialized document, or the whole document if it has none.
+fn head(html: &str) -> &str {
+    // Skip elements whose name starts with `head`, such as `<header>`
+    let start = html
+        .match_indices("<head")
+        .map(|(i, _)| i)
+        .find(|i| {
+            html[i + "<head".len()..]
+                .starts_with(|c: char| c == '>' || c == '/' || c.is_ascii_whitespace())
+        })
+        .unwrap_or(0);
+    let end = html[start..]
+        .find("</head>")
+        .map_or(html.len(), |end| start + end + "</head>".len());
+    &html[start..end]
+}
+
+/// Prints the results of every page of the run.
+fn print_pages(out: &mut dyn Write, stats: &CrittersDirectoryStats) -> std::io::Result<()> {
+    print_table(
+        out,
+        "All pages",
+        "Critical CSS",
+        stats.page_reports.iter().map(|p| {
+            let result = match p.status {
+                PageStatus::Succeeded => format_bytes(p.critical_bytes),
+                PageStatus::Failed => "failed".to_string(),
+                PageStatus::Skipped => "skipped".to_string(),
+            };
+            (p.path.clone(), result)
+        }),
+    )
+}
+
+/// Prints a summary table of the statistics of the run.
+fn print_summary(out: &mut dyn Write, stats: &CrittersDirectoryStats) -> std::io::Result<()> {
+    print_table(
+        out,
+        "Pages",
+        "",
+        [
+            ("Succeeded".to_string(), stats.pages_succeeded.to_string()),
+            ("Failed".to_string(), stats.pages_failed.to_string()),
+            ("Skipped".to_string(), stats.pages_skipped.to_string()),
+            (
+                "Critical CSS".to_string(),
+                format_bytes(stats.critical_bytes),
+            ),
+        ],
+    )?;
+    print_table(
+        out,
+        "Biggest pages",
+        "Critical CSS",
+        stats
+            .biggest_pages
+            .iter()
+            .map(|p| (p.path.clone(), format_bytes(p.critical_bytes))),
+    )?;
+    print_table(
+        out,
+        "Slowest pages",
+        "Time",
+        stats
+            .slowest_pages
+            .iter()
+            .map(|p| (p.path.clone(), format!("{:.0} ms", p.time_ms))),
+    )?;
+    print_table(
+        out,
+        "Skipped files",
+        "Files",
+        (stats.discovery.not_included > 0)
+            .then(|| {
+                (
+                    "Not included".to_string(),
+                    stats.discovery.not_included.to_string(),
+                )
+            })
+            .into_iter()
+            .chain(
+                stats
+                    .discovery
+                    .excluded
+                    .iter()
+                    .filter(|e| e.files > 0)
+                    .map(|e| (format!("Excluded by {}", e.pattern), e.files.to_string())),
+            ),
+    )?;
+    print_table(
+        out,
+        "Stylesheets",
+        "Pages",
+        stats
+            .stylesheets
+            .iter()
+            .map(|s| (s.href.clone(), s.pages.to_string())),
+    )
+}
+
+/// Prints a two column table, unless it has no rows.
+fn print_table(
+    out: &mut dyn Write,
+    title: &str,
+    column: &str,
+    rows: impl IntoIterator<Item = (String, String)>,
+) -> std::io::Result<()> {
+    let mut rows = rows.into_iter().peekable();
+    if rows.peek().is_none() {
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(out, "  \x1b[1m{title:<48} {column:>12}\x1b[0m")?;
+    for (name, value) in rows {
+        writeln!(out, "  {name:<48} {value:>12}")?;
+    }
+    Ok(())
+}
+
+/// Formats a size in bytes for display.
+fn format_bytes(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{bytes} B")
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,408 @@
+use serde::Serialize;
+
+use crate::html::style_calculation::FoldReason;
+use crate::CrittersError;
+
+/// A report of the processing of a single document.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+#[cfg_attr(feature = "typegen", ts(export))]
+pub struct ProcessReport {
+    /// The stylesheets discovered in the document, in the order they were processed.
+    pub stylesheets: Vec<StylesheetReport>,
+    /// The names of the keyframes kept in the critical CSS.
+    pub keyframes: Vec<String>,
+    /// The families of the font faces kept in the critical CSS.
+    pub fonts: Vec<String>,
+    /// The preload directives injected into the document.
+    pub preloads: Vec<PreloadReport>,
+    /// Where the analysis of the document was cut off, if the fold was reached.
+    pub fold: Option<FoldReport>,
+    /// Problems which did not prevent the document from being processed, such as stylesheets
+    /// which failed to parse and were left untouched.
+    pub warnings: Vec<String>,
+}
+
+impl ProcessReport {
+    /// The number of stylesheets reduced to their critical CSS.
+    pub fn stylesheets_reduced(&self) -> usize {
+        self.stylesheets
+            .iter()
+            .filter(|sheet| matches!(sheet.action, StylesheetAction::Reduced))
+            .count()
+    }
+
+    pub(crate) fn add_keyframes(&mut self, name: &str) {
+        if !self.keyframes.iter().any(|k| k == name) {
+            self.keyframes.push(name.to_string());
+        }
+    }
+
+    pub(crate) fn add_font(&mut self, family: &str) {
+        if !self.fonts.iter().any(|f| f == family) {
+            self.fonts.push(family.to_string());
+        }
+    }
+
+    pub(crate) fn add_preload(&mut self, kind: PreloadKind, href: &str) {
+        self.preloads.push(PreloadReport {
+            kind,
+            href: href.to_string(),
+        });
+    }
+}
+
+/// The point at which the analysis of a document was cut off by the fold.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct FoldReport {
+    /// The first element past the fold, such as `<section id="comments">`.
+    pub element: String,
+    /// Why the analysis was cut off at this element.
+    pub reason: FoldReason,
+    /// The number of elements before the fold.
+    pub elements: usize,
+    /// The estimated height of the content before the fold, in pixels.
+    pub estimated_height: u32,
+}
+
+/// Where a stylesheet was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub enum StylesheetSource {
+    /// A `<style>` element of the document.
+    Inline,
+    /// A `<link rel="stylesheet">` to a stylesheet on disk.
+    External,
+    /// One of the configured additional stylesheets.
+    Additional,
+    /// A `<style>` element within a declarative shadow root.
+    ShadowRoot,
+}
+
+/// What was done with a stylesheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub enum StylesheetAction {
+    /// The stylesheet was reduced to its critical CSS.
+    Reduced,
+    /// The stylesheet was inlined whole, as it is below the inline threshold.
+    Inlined,
+    /// The stylesheet was left untouched after an error.
+    Skipped,
+}
+
+/// The processing of a single stylesheet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct StylesheetReport {
+    /// Where the stylesheet was found.
+    pub source: StylesheetSource,
+    /// The `href` of external and additional stylesheets.
+    pub href: Option<String>,
+    /// What was done with the stylesheet.
+    pub action: StylesheetAction,
+    /// The size of the stylesheet before processing, in bytes.
+    pub bytes_before: usize,
+    /// The size of the stylesheet inlined in the document, in bytes.
+    pub bytes_after: usize,
+    /// The number of top-level rules kept.
+    pub rules_kept: usize,
+    /// The number of top-level rules removed.
+    pub rules_removed: usize,
+    /// Selectors which could not be evaluated against the document, along with the reason.
+    pub failed_selectors: Vec<String>,
+}
+
+impl StylesheetReport {
+    pub(crate) fn new(
+        source: StylesheetSource,
+        href: Option<String>,
+        reduce: bool,
+        bytes: usize,
+    ) -> Self {
+        Self {
+            source,
+            href,
+            action: if reduce {
+                StylesheetAction::Reduced
+            } else {
+                StylesheetAction::Inlined
+            },
+            bytes_before: bytes,
+            bytes_after: bytes,
+            rules_kept: 0,
+            rules_removed: 0,
+            failed_selectors: Vec::new(),
+        }
+    }
+}
+
+/// The kind of resource preloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub enum PreloadKind {
+    Font,
+    Image,
+}
+
+/// A preload directive injected into the document.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct PreloadReport {
+    /// The kind of resource preloaded.
+    pub kind: PreloadKind,
+    /// The URL of the resource.
+    pub href: String,
+}
+
+/// The number of pages listed in the biggest and slowest pages of `CrittersDirectoryStats`.
+const TOP_PAGES: usize = 10;
+
+/// Statistics resulting from `Critters::process_dir`.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+#[cfg_attr(feature = "typegen", ts(export))]
+pub struct CrittersDirectoryStats {
+    /// Total duration of processing, in seconds
+    pub time_sec: f64,
+    /// Number of pages found
+    pub pages: u32,
+    /// Number of pages processed successfully
+    pub pages_succeeded: u32,
+    /// Number of pages which failed to process and were left untouched
+    pub pages_failed: u32,
+    /// Number of pages without stylesheets or which could not be decoded, which were left
+    /// untouched
+    pub pages_skipped: u32,
+    /// Total size of the CSS inlined across all pages, in bytes
+    pub critical_bytes: usize,
+    /// The pages with the most inlined CSS, largest first
+    pub biggest_pages: Vec<PageSummary>,
+    /// The pages which took the longest to process, slowest first
+    pub slowest_pages: Vec<PageSummary>,
+    /// The external and additional stylesheets, along with the number of pages referencing them,
+    /// most used first
+    pub stylesheets: Vec<StylesheetUsage>,
+    /// The results of each page, ordered by path
+    pub page_reports: Vec<PageReport>,
+    /// The HTML files found in the directory, including those skipped by the include and exclude
+    /// globs
+    pub discovery: DiscoveryReport,
+}
+
+impl CrittersDirectoryStats {
+    /// Aggregates the results of the individual pages, leaving `discovery` empty.
+    pub fn from_pages(mut page_reports: Vec<PageReport>, time_sec: f64) -> Self {
+        page_reports.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let count = |status| page_reports.iter().filter(|p| p.status == status).count() as u32;
+        let top_pages = |key: &dyn Fn(&PageReport) -> f64| {
+            let mut pages: Vec<_> = page_reports
+                .iter()
+                .filter(|p| p.status == PageStatus::Succeeded)
+                .collect();
+            pages.sort_by(|a, b| key(b).total_cmp(&key(a)));
+            pages.iter().take(TOP_PAGES).map(|p| p.summary()).collect()
+        };
+
+        let mut stylesheets: Vec<StylesheetUsage> = Vec::new();
+        for sheet in page_reports
+            .iter()
+            .filter_map(|p| p.report.as_ref())
+            .flat_map(|r| &r.stylesheets)
+        {
+            let Some(href) = &sheet.href else { continue };
+            match stylesheets.iter_mut().find(|s| &s.href == href) {
+                Some(usage) => usage.pages += 1,
+                None => stylesheets.push(StylesheetUsage {
+                    href: href.clone(),
+                    pages: 1,
+                }),
+            }
+        }
+        stylesheets.sort_by(|a, b| b.pages.cmp(&a.pages).then_with(|| a.href.cmp(&b.href)));
+
+        Self {
+            time_sec,
+            pages: page_reports.len() as u32,
+            pages_succeeded: count(PageStatus::Succeeded),
+            pages_failed: count(PageStatus::Failed),
+            pages_skipped: count(PageStatus::Skipped),
+            critical_bytes: page_reports.iter().map(|p| p.critical_bytes).sum(),
+            biggest_pages: top_pages(&|p| p.critical_bytes as f64),
+            slowest_pages: top_pages(&|p| p.time_ms),
+            stylesheets,
+            page_reports,
+            discovery: Default::default(),
+        }
+    }
+
+    /// The problems of the pages which failed, prefixed by their path. Pages failed in strict
+    /// mode list each of their warnings. Skipped pages are not problems, even if they could not
+    /// be decoded.
+    pub fn problems(&self) -> Vec<String> {
+        self.page_reports
+            .iter()
+            .filter(|p| p.status == PageStatus::Failed)
+            .flat_map(|p| match &p.report {
+                Some(report) => report
+                    .warnings
+                    .iter()
+                    .map(|w| format!("{}: {w}", p.path))
+                    .collect::<Vec<_>>(),
+                None => vec![format!(
+                    "{}: {}",
+                    p.path,
+                    p.error.as_deref().unwrap_or_default()
+                )],
+            })
+            .collect()
+    }
+}
+
+/// The outcome of processing a single page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub enum PageStatus {
+    /// The page was processed and written back to disk.
+    Succeeded,
+    /// The page could not be processed, and was left untouched.
+    Failed,
+    /// The page has no stylesheets to process or could not be decoded, and was left untouched.
+    Skipped,
+}
+
+/// The processing of a single page of a directory.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct PageReport {
+    /// The path of the page, relative to the processed directory.
+    pub path: String,
+    /// The outcome of processing the page.
+    pub status: PageStatus,
+    /// The duration of processing, in milliseconds.
+    pub time_ms: f64,
+    /// The size of the CSS inlined in the page, in bytes.
+    pub critical_bytes: usize,
+    /// The error which caused the page to fail, or the reason it could not be decoded.
+    pub error: Option<String>,
+    /// The report of the processing, unless the page failed with an error.
+    pub report: Option<ProcessReport>,
+}
+
+impl PageReport {
+    /// Creates the report of a page from the result of processing it. Pages without stylesheets
+    /// are reported as skipped.
+    pub fn new(path: String, time_ms: f64, result: Result<ProcessReport, CrittersError>) -> Self {
+        match result {
+            Ok(report) => Self {
+                path,
+                status: if report.stylesheets.is_empty() {
+                    PageStatus::Skipped
+                } else {
+                    PageStatus::Succeeded
+                },
+                time_ms,
+                critical_bytes: report.stylesheets.iter().map(|s| s.bytes_after).sum(),
+                error: None,
+                report: Some(report),
+            },
+            Err(err) => Self {
+                path,
+                status: PageStatus::Failed,
+                time_ms,
+                critical_bytes: 0,
+                error: Some(err.to_string()),
+                report: None,
+            },
+        }
+    }
+
+    /// Creates the report of a page which was skipped for the given reason.
+    pub fn skipped(path: String, time_ms: f64, reason: String) -> Self {
+        Self {
+            path,
+            status: PageStatus::Skipped,
+            time_ms,
+            critical_bytes: 0,
+            error: Some(reason),
+            report: None,
+        }
+    }
+
+    /// Marks a page with warnings as failed, as it was left untouched in strict mode.
+    pub fn fail_strict(&mut self) {
+        let warnings = self.report.as_ref().map_or(0, |r| r.warnings.len());
+        self.status = PageStatus::Failed;
+        self.critical_bytes = 0;
+        self.error = Some(format!("{warnings} problems in strict mode"));
+    }
+
+    fn summary(&self) -> PageSummary {
+        PageSummary {
+            path: self.path.clone(),
+            critical_bytes: self.critical_bytes,
+            time_ms: self.time_ms,
+        }
+    }
+}
+
+/// A page listed in the biggest or slowest pages.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct PageSummary {
+    /// The path of the page, relative to the processed directory.
+    pub path: String,
+    /// The size of the CSS inlined in the page, in bytes.
+    pub critical_bytes: usize,
+    /// The duration of processing, in milliseconds.
+    pub time_ms: f64,
+}
+
+/// The number of pages referencing a stylesheet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct StylesheetUsage {
+    /// The `href` of the stylesheet.
+    pub href: String,
+    /// The number of pages referencing the stylesheet.
+    pub pages: u32,
+}
+
+/// The HTML files found when processing a directory.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct DiscoveryReport {
+    /// The number of files with one of the configured extensions, including those skipped by the
+    /// include and exclude globs, but not those ignored by `.gitignore` files.
+    pub files: u32,
+    /// The number of files skipped as they do not match any of the include globs.
+    pub not_included: u32,
+    /// The number of files skipped by each exclude glob, in the configured order.
+    pub excluded: Vec<ExcludedFiles>,
+}
+
+/// The number of files skipped by an exclude glob.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "typegen", derive(ts_rs::TS))]
+pub struct ExcludedFiles {
+    /// The exclude glob.
+    pub pattern: String,
+    /// The number of files skipped by the glob, excluding those skipped by earlier globs.
+    pub files: u32,
+}
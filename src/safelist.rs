@@ -0,0 +1,224 @@
+use lightningcss::selector::{Component, Selector};
+use std::collections::HashSet;
+
+use crate::html::{ElementData, NodeDataRef};
+use crate::utils::regex;
+
+/// Prefixes of attributes used by frameworks to bind classes and attributes at runtime, such as
+/// Alpine's `x-bind:class`, Vue's `:class` or Svelte's `class:active`.
+const BINDING_PREFIXES: &[&str] = &["x-bind:", "v-bind:", ":"];
+const DIRECTIVE_PREFIXES: &[&str] = &["x-", "v-", "@", "bind:", "hx-"];
+
+/// Reserved words and literals of JavaScript, which are not harvested from framework attributes
+/// unless quoted.
+const RESERVED_WORDS: &[&str] = &[
+    "async",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "import",
+    "in",
+    "instanceof",
+    "let",
+    "new",
+    "null",
+    "of",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "undefined",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Classes and attribute values which may be added to the document at runtime, harvested from
+/// inline scripts, JSON islands and framework attributes.
+///
+/// Selectors which only depend on these tokens and on classes and attributes present in the
+/// document are considered to match, since the elements they target may only exist after
+/// hydration.
+#[derive(Debug, Default)]
+pub struct Safelist {
+    /// Class-like tokens found in scripts and framework attributes.
+    tokens: HashSet<String>,
+    /// Classes present in the document.
+    classes: HashSet<String>,
+    /// Attribute names present in the document or bound by frameworks.
+    attributes: HashSet<String>,
+    /// Attribute names and values present in the document.
+    attribute_values: HashSet<(String, String)>,
+}
+
+impl Safelist {
+    /// Harvests tokens from the given elements, those analyzed for critical CSS. Scripts and
+    /// markup outside of the containers, in ignored subtrees or past the fold are not harvested.
+    pub fn harvest(elements: &[NodeDataRef<ElementData>]) -> Self {
+        let mut safelist = Safelist::default();
+
+        for element in elements {
+            let attributes = element.attributes.borrow();
+
+            // inline scripts, including JSON islands, of which only strings are harvested so that
+            // keywords and API names are left out
+            if element.name.local == html5ever::local_name!("script") && !attributes.contains("src")
+            {
+                safelist.add_string_tokens(&element.text_contents());
+            }
+
+            for (name, attribute) in &attributes.map {
+                let name = name.local.as_ref();
+                let value = &attribute.value;
+
+                if name == "class" {
+                    safelist
+                        .classes
+                        .extend(value.split_ascii_whitespace().map(String::from));
+                }
+                safelist.attributes.insert(name.to_string());
+                safelist
+                    .attribute_values
+                    .insert((name.to_string(), value.to_string()));
+
+                if let Some(bound) = BINDING_PREFIXES
+                    .iter()
+                    .find_map(|prefix| name.strip_prefix(prefix))
+                {
+                    safelist.attributes.insert(bound.to_string());
+                    safelist.add_expression_tokens(value);
+                } else if name == "class:list" {
+                    // Astro's directive lists the classes in its value
+                    safelist.add_expression_tokens(value);
+                } else if let Some(class) = name.strip_prefix("class:") {
+                    safelist.tokens.insert(class.to_string());
+                    safelist.add_expression_tokens(value);
+                } else if DIRECTIVE_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+                    // props of Astro islands
+                    || (name == "props" && element.name.local.as_ref() == "astro-island")
+                {
+                    safelist.add_expression_tokens(value);
+                }
+            }
+        }
+
+        safelist
+    }
+
+    /// Adds the class-like tokens of the string literals in the given script.
+    fn add_string_tokens(&mut self, source: &str) {
+        for literal in string_literals(source) {
+            self.add_tokens(literal, false);
+        }
+    }
+
+    /// Adds the class-like tokens of the given JavaScript expression, such as the value of a
+    /// framework attribute: those of its string literals, and its other identifiers except for
+    /// reserved words, which may be the unquoted keys of class objects.
+    fn add_expression_tokens(&mut self, source: &str) {
+        self.add_string_tokens(source);
+        self.add_tokens(&string_literal_regex().replace_all(source, " "), true);
+    }
+
+    /// Adds the class-like tokens of the given text, skipping reserved words if requested.
+    /// Tokens containing colons, such as Tailwind variants, are added both whole and split into
+    /// their parts.
+    fn add_tokens(&mut self, source: &str, skip_reserved: bool) {
+        let token_regex = regex!(r"-?[A-Za-z_][\w-]*(?:[:/][\w-]+)*");
+        for token in token_regex.find_iter(source) {
+            let token = token.as_str();
+            if skip_reserved && RESERVED_WORDS.contains(&token) {
+                continue;
+            }
+            if token.contains(':') {
+                self.tokens
+                    .extend(token.split(':').filter(|t| !t.is_empty()).map(String::from));
+            }
+            self.tokens.insert(token.to_string());
+        }
+    }
+
+    /// Returns whether the selector targets elements which may exist after hydration: it must
+    /// reference at least one harvested token, and every class and attribute it references must
+    /// be harvested or present in the document.
+    pub fn matches(&self, selector: &Selector) -> bool {
+        let mut harvested = false;
+
+        for component in selector.iter_raw_match_order() {
+            match component {
+                Component::Class(class) => {
+                    if self.tokens.contains(class.0.as_ref()) {
+                        harvested = true;
+                    } else if !self.classes.contains(class.0.as_ref()) {
+                        return false;
+                    }
+                }
+                Component::AttributeInNoNamespaceExists { local_name, .. }
+                    if !self.attributes.contains(local_name.0.as_ref()) =>
+                {
+                    return false;
+                }
+                Component::AttributeInNoNamespace {
+                    local_name, value, ..
+                } => {
+                    let (name, value) = (local_name.0.as_ref(), value.0.as_ref());
+                    if !self.attributes.contains(name) {
+                        return false;
+                    }
+                    if self.tokens.contains(value) {
+                        harvested = true;
+                    } else if !self
+                        .attribute_values
+                        .contains(&(name.to_string(), value.to_string()))
+                    {
+                        return false;
+                    }
+                }
+                Component::AttributeOther(attribute)
+                    if !self.attributes.contains(attribute.local_name.0.as_ref()) =>
+                {
+                    return false;
+                }
+                _ => (),
+            }
+        }
+
+        harvested
+    }
+}
+
+fn string_literal_regex() -> &'static regex::Regex {
+    regex!(r#""(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*'|`(?:[^`\\]|\\.)*`"#)
+}
+
+/// Returns the contents of the string and template literals in the given JavaScript source.
+fn string_literals(source: &str) -> impl Iterator<Item = &str> {
+    string_literal_regex()
+        .find_iter(source)
+        .map(|literal| &literal.as_str()[1..literal.len() - 1])
+}
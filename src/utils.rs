@@ -1,26 +1,133 @@
-use crate::html::NodeRef;
+use crate::html::iter::NodeIterator;
+use crate::html::{ElementData, NodeDataRef, NodeRef};
 use lightningcss::{rules::style::StyleRule, traits::Parse};
 
-/// Locate all the HTML files within a given directory.
+/// Locate the HTML files within the given directory, along with the number of files skipped
+/// by the include and exclude globs.
 #[cfg(feature = "directory")]
-pub fn locate_html_files(path: &str) -> anyhow::Result<Vec<std::path::PathBuf>> {
-    use walkdir::WalkDir;
+pub fn locate_html_files(
+    path: &str,
+    options: &crate::DirectoryOptions,
+) -> Result<(Vec<std::path::PathBuf>, crate::DiscoveryReport), crate::CrittersError> {
+    use crate::{DiscoveryReport, ExcludedFiles};
 
+    // Report a missing or unreadable directory instead of finding no files in it
+    std::fs::read_dir(path).map_err(|e| crate::CrittersError::io(path, e))?;
+
+    let include = options
+        .include
+        .iter()
+        .map(|pattern| compile_glob(pattern, "include"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let exclude = options
+        .exclude
+        .iter()
+        .map(|pattern| compile_glob(pattern, "exclude"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut report = DiscoveryReport {
+        excluded: options
+            .exclude
+            .iter()
+            .map(|pattern| ExcludedFiles {
+                pattern: pattern.clone(),
+                files: 0,
+            })
+            .collect(),
+        ..Default::default()
+    };
     let mut paths = Vec::new();
 
-    for entry in WalkDir::new(path)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let f_name = entry.file_name().to_string_lossy();
+    let walker = ignore::WalkBuilder::new(path)
+        .standard_filters(false)
+        .follow_links(options.follow_links)
+        .git_ignore(options.respect_gitignore)
+        .git_exclude(options.respect_gitignore)
+        .parents(options.respect_gitignore)
+        .require_git(false)
+        .build();
+    for entry in walker.filter_map(|e| e.ok()) {
+        let is_html = entry.file_type().is_some_and(|t| !t.is_dir())
+            && entry.path().extension().is_some_and(|ext| {
+                options
+                    .extensions
+                    .iter()
+                    .any(|e| ext.eq_ignore_ascii_case(e.trim_start_matches('.')))
+            });
+        if !is_html {
+            continue;
+        }
+        report.files += 1;
 
-        if f_name.ends_with(".html") {
-            paths.push(entry.into_path())
+        let relative_path = entry.path().strip_prefix(path).unwrap_or(entry.path());
+        if !include.is_empty() && !include.iter().any(|glob| glob.is_match(relative_path)) {
+            report.not_included += 1;
+            continue;
+        }
+        // Files matching several exclude globs are attributed to the first one
+        if let Some(i) = exclude.iter().position(|glob| glob.is_match(relative_path)) {
+            report.excluded[i].files += 1;
+            continue;
         }
+
+        paths.push(entry.into_path())
     }
 
-    Ok(paths)
+    Ok((paths, report))
+}
+
+/// Compiles a glob matched against paths relative to the processed directory, in which `*` does
+/// not match path separators.
+#[cfg(feature = "directory")]
+pub fn compile_glob(
+    pattern: &str,
+    option: &str,
+) -> Result<globset::GlobMatcher, crate::CrittersError> {
+    globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| {
+            crate::CrittersError::InvalidOptions(format!(
+                "Invalid glob \"{pattern}\" in {option}. {e}"
+            ))
+        })
+}
+
+/// Writes the given contents to a file atomically, by writing them to a temporary file in the
+/// same directory and renaming it over the original. The file is given the permissions of the
+/// source it was produced from, if any, or else those of a newly created file. Missing parent
+/// directories are created.
+#[cfg(feature = "directory")]
+pub fn write_atomic(
+    path: &std::path::Path,
+    contents: impl AsRef<[u8]>,
+    permissions: Option<std::fs::Permissions>,
+) -> Result<(), crate::CrittersError> {
+    use std::io::Write;
+
+    let io_error = |e| crate::CrittersError::io(path, e);
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    std::fs::create_dir_all(dir).map_err(io_error)?;
+
+    let mut builder = tempfile::Builder::new();
+    // Temporary files are only readable by their owner by default. Request the mode of a new
+    // file instead, which is then restricted by the umask.
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    let mut file = builder.tempfile_in(dir).map_err(io_error)?;
+    file.write_all(contents.as_ref()).map_err(io_error)?;
+    if let Some(permissions) = permissions {
+        file.as_file()
+            .set_permissions(permissions)
+            .map_err(io_error)?;
+    }
+    file.persist(path).map_err(|e| io_error(e.error))?;
+
+    Ok(())
 }
 
 pub trait StyleRuleExt {
@@ -42,8 +149,26 @@ impl StyleRuleExt for StyleRule<'_> {
 pub trait NodeRefExt {
     /// Creates a new HTML element with the given name and attributes.
     fn new_html_element(name: &str, attributes: Vec<(&str, &str)>) -> NodeRef;
+
+    /// Finds the declarative shadow roots within this tree, including nested ones, along with
+    /// their hosts.
+    fn shadow_roots(&self) -> Vec<(NodeDataRef<ElementData>, NodeRef)>;
 }
 impl NodeRefExt for NodeRef {
+    fn shadow_roots(&self) -> Vec<(NodeDataRef<ElementData>, NodeRef)> {
+        let mut shadow_roots = Vec::new();
+        let mut pending = vec![self.clone()];
+        while let Some(tree) = pending.pop() {
+            for host in tree.descendants().elements() {
+                if let Some(shadow_root) = host.as_node().shadow_root() {
+                    pending.push(shadow_root.clone());
+                    shadow_roots.push((host, shadow_root));
+                }
+            }
+        }
+        shadow_roots
+    }
+
     fn new_html_element(name: &str, attributes: Vec<(&str, &str)>) -> NodeRef {
         use crate::html::{Attribute, ExpandedName};
         use markup5ever::{namespace_url, ns, LocalName, QualName};
@@ -83,6 +208,50 @@ pub fn is_valid_media_query(s: &str) -> bool {
     lightningcss::media_query::MediaQuery::parse_string(s).is_ok()
 }
 
+/// Resolves a URL referenced by a stylesheet against the `href` of the stylesheet, as the browser
+/// would, so that it can be referenced from the document instead. URLs which are not relative
+/// paths, such as absolute paths, fragments and data URLs, are returned unchanged.
+pub fn resolve_url(href: &str, url: &str) -> String {
+    if url.is_empty()
+        || url.starts_with(['/', '#'])
+        || regex!(r"^[a-zA-Z][a-zA-Z0-9+.-]*:").is_match(url)
+    {
+        return url.to_string();
+    }
+
+    // The origin of absolute URLs is kept as is
+    let href = href.split(['?', '#']).next().unwrap_or_default();
+    let origin = regex!(r"^([a-zA-Z][a-zA-Z0-9+.-]*:)?//[^/]*")
+        .find(href)
+        .map_or("", |m| m.as_str());
+    let href = &href[origin.len()..];
+    let dir = &href[..href.rfind('/').map_or(0, |i| i + 1)];
+
+    let (path, suffix) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    let joined = format!("{dir}{path}");
+    let (root, joined) = match joined.strip_prefix('/') {
+        Some(joined) => ("/", joined),
+        None => ("", joined.as_str()),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in joined.split('/') {
+        match segment {
+            "." => (),
+            ".." => match segments.last() {
+                Some(&last) if last != ".." => {
+                    segments.pop();
+                }
+                // Relative paths may point above the directory of the document
+                _ if root.is_empty() => segments.push(".."),
+                _ => (),
+            },
+            segment => segments.push(segment),
+        }
+    }
+
+    format!("{origin}{root}{}{suffix}", segments.join("/"))
+}
+
 /// Macro to create a cached Regular expression literal.
 macro_rules! regex {
     ($re:expr) => {